@@ -1,9 +1,8 @@
 // Import necessary modules
 use topiary_config::Configuration;
-use topiary_core::{formatter, Language, Operation, TopiaryQuery};
+use topiary_core::{formatter, FormatOptions, Language, Operation, TopiaryQuery};
 
-#[tokio::main]
-async fn main() {
+fn main() {
     // Define input JSON string
     let mut input =
         "{\"name\":\"John Doe\",\"age\":43,\n\"phones\":[\"+44 1234567\",\"+44 2345678\"]}"
@@ -28,6 +27,13 @@ async fn main() {
         query: TopiaryQuery::new(&grammar, query).unwrap(),
         grammar,
         indent: None,
+        tab_width: 8,
+        expand_tabs: false,
+        final_newline: None,
+        normalise_indentation: false,
+        file_trailer: None,
+        post_process: None,
+        comment_kinds: None,
     };
 
     // Format the input JSON using the language configuration
@@ -35,10 +41,7 @@ async fn main() {
         &mut input,
         &mut output,
         &language,
-        Operation::Format {
-            skip_idempotence: false,
-            tolerate_parsing_errors: false,
-        },
+        Operation::Format(FormatOptions::default()),
     )
     .unwrap();
 
@@ -2,12 +2,17 @@
 
 use clap::{ArgAction, ArgGroup, Args, CommandFactory, Parser, Subcommand};
 use clap_complete::{generate, shells::Shell};
-use std::{io::stdout, path::PathBuf};
+use std::{
+    fs,
+    io::{self, stdin, stdout},
+    path::{Path, PathBuf},
+};
 
 use log::LevelFilter;
 
 use crate::{
-    error::{CLIResult, TopiaryError},
+    diagnostics::DiagnosticsFormat,
+    error::{CLIError, CLIResult, TopiaryError},
     visualisation,
 };
 
@@ -29,16 +34,21 @@ pub struct Cli {
 // NOTE Global arguments must be optional, even when defaults are specified
 #[derive(Args, Debug)]
 pub struct GlobalArgs {
-    /// Configuration file
+    /// Configuration file (repeatable)
     #[arg(
         short = 'C',
         long,
+        action = ArgAction::Append,
         display_order = 100,
         env = "TOPIARY_CONFIG_FILE",
         global = true,
         hide_env_values = true
     )]
-    pub configuration: Option<PathBuf>,
+    pub configuration: Vec<PathBuf>,
+
+    /// Format of the reported errors and warnings
+    #[arg(long, value_enum, default_value_t, global = true, display_order = 101)]
+    pub diagnostics: DiagnosticsFormat,
 
     /// Logging verbosity (increased per occurrence)
     #[arg(
@@ -94,7 +104,7 @@ pub struct ExactlyOneInput {
     group = ArgGroup::new("source")
         .multiple(false)
         .required(true)
-        .args(&["language", "files"])
+        .args(&["language", "files", "files_from"])
 )]
 pub struct AtLeastOneInput {
     #[command(flatten)]
@@ -105,6 +115,13 @@ pub struct AtLeastOneInput {
     /// Language detection and query selection is automatic, mapped from file extensions defined in
     /// the Topiary configuration.
     pub files: Vec<PathBuf>,
+
+    /// File listing the input files, one per line ("-" to read the list from stdin)
+    ///
+    /// Listed files whose language cannot be detected are skipped, so that the list can be, e.g.,
+    /// the output of `git diff --name-only`.
+    #[arg(long, value_name = "FILE")]
+    pub files_from: Option<PathBuf>,
 }
 
 // NOTE When changing the subcommands, please update verify-documented-usage.sh respectively.
@@ -121,6 +138,22 @@ pub enum Commands {
         #[arg(short, long)]
         skip_idempotence: bool,
 
+        /// Fail if any warning is raised while formatting
+        #[arg(long)]
+        deny_warnings: bool,
+
+        /// Ignore captures on anonymous nodes, such as punctuation
+        #[arg(long)]
+        named_only: bool,
+
+        /// Also format the files of languages configured not to be formatted
+        #[arg(long)]
+        force: bool,
+
+        /// Print, on stderr, how many inputs of each language were unchanged, changed or failed
+        #[arg(long)]
+        summary: bool,
+
         #[command(flatten)]
         inputs: AtLeastOneInput,
     },
@@ -140,6 +173,10 @@ pub enum Commands {
     #[command(alias = "cfg", display_order = 3)]
     Config,
 
+    /// List the query file found for each language
+    #[command(display_order = 4)]
+    Queries,
+
     /// Generate shell completion script
     #[command(display_order = 100)]
     Completion {
@@ -148,14 +185,130 @@ pub enum Commands {
     },
 }
 
-/// Given a vector of paths, recursively expand those that identify as directories, in place
+/// The name of the file listing, in the manner of `.gitignore`, the paths to skip when traversing
+/// the directory that contains it
+const IGNORE_FILE: &str = ".topiaryignore";
+
+/// A pattern read from an ignore file, which applies to the paths beneath the directory that
+/// contains the ignore file
+#[derive(Clone, Debug)]
+struct IgnorePattern {
+    base: PathBuf,
+    pattern: glob::Pattern,
+    // Patterns containing a slash are matched against the path relative to the base, others
+    // against the file name
+    anchored: bool,
+    // Patterns ending with a slash only match directories
+    dir_only: bool,
+}
+
+impl IgnorePattern {
+    fn matches(&self, path: &Path) -> bool {
+        if self.dir_only && !path.is_dir() {
+            return false;
+        }
+
+        if self.anchored {
+            path.strip_prefix(&self.base)
+                .is_ok_and(|relative| self.pattern.matches_path(relative))
+        } else {
+            path.file_name()
+                .is_some_and(|name| self.pattern.matches(&name.to_string_lossy()))
+        }
+    }
+}
+
+/// Reads the ignore patterns of a directory, if it contains an ignore file. Blank lines and lines
+/// starting with `#` are skipped. Negated patterns are not supported.
+fn read_ignore_file(dir: &Path) -> CLIResult<Vec<IgnorePattern>> {
+    let path = dir.join(IGNORE_FILE);
+    if !path.is_file() {
+        return Ok(vec![]);
+    }
+
+    let mut patterns = vec![];
+
+    for line in fs::read_to_string(&path)?.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('!') {
+            log::warn!(
+                "Negated patterns are not supported, in {}: {line}",
+                path.display()
+            );
+            continue;
+        }
+
+        let dir_only = line.ends_with('/');
+        let line = line.trim_end_matches('/');
+        let anchored = line.contains('/');
+        let line = line.trim_start_matches('/');
+
+        let pattern = glob::Pattern::new(line).map_err(|e| {
+            TopiaryError::Bin(
+                format!("Invalid pattern in {}: {line}", path.display()),
+                Some(CLIError::Generic(Box::new(e))),
+            )
+        })?;
+
+        patterns.push(IgnorePattern {
+            base: dir.to_path_buf(),
+            pattern,
+            anchored,
+            dir_only,
+        });
+    }
+
+    Ok(patterns)
+}
+
+/// Reads a list of paths, one per line, from a file or, given "-", from stdin. Blank lines are
+/// skipped.
+fn read_file_list(list: &Path) -> CLIResult<Vec<PathBuf>> {
+    let contents = if list == Path::new("-") {
+        io::read_to_string(stdin())?
+    } else {
+        fs::read_to_string(list)?
+    };
+
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Given a vector of paths, recursively expand those that identify as directories, in place.
+/// Within directories, the paths matched by the ignore files of the traversed directories are
+/// skipped, as are the ignore files themselves.
 fn traverse_fs(files: &mut Vec<PathBuf>) -> CLIResult<()> {
+    traverse_fs_ignoring(files, &[])
+}
+
+fn traverse_fs_ignoring(files: &mut Vec<PathBuf>, ignored: &[IgnorePattern]) -> CLIResult<()> {
     let mut expanded = vec![];
 
     for file in &mut *files {
         if file.is_dir() {
-            let mut subfiles = file.read_dir()?.flatten().map(|f| f.path()).collect();
-            traverse_fs(&mut subfiles)?;
+            let patterns: Vec<IgnorePattern> = ignored
+                .iter()
+                .cloned()
+                .chain(read_ignore_file(file)?)
+                .collect();
+            let mut subfiles = file
+                .read_dir()?
+                .flatten()
+                .map(|f| f.path())
+                .filter(|path| {
+                    path.file_name() != Some(IGNORE_FILE.as_ref())
+                        && !patterns.iter().any(|pattern| pattern.matches(path))
+                })
+                .collect();
+
+            traverse_fs_ignoring(&mut subfiles, &patterns)?;
             expanded.append(&mut subfiles);
         } else {
             expanded.push(file.to_path_buf());
@@ -189,9 +342,15 @@ pub fn get_args() -> CLIResult<Cli> {
 
     match &mut args.command {
         Commands::Format {
-            inputs: AtLeastOneInput { files, .. },
+            inputs: AtLeastOneInput {
+                files, files_from, ..
+            },
             ..
         } => {
+            if let Some(list) = files_from {
+                files.extend(read_file_list(list)?);
+            }
+
             // If we're given a list of FILES... then we assume them to all be on disk, even if "-"
             // is passed as an argument (i.e., interpret this as a valid filename, rather than as
             // stdin). We deduplicate this list to avoid formatting the same file multiple times
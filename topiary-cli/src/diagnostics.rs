@@ -0,0 +1,148 @@
+use std::{error::Error, io::Write, ops::Range};
+
+use clap::ValueEnum;
+use serde::Serialize;
+use topiary_core::FormatterError;
+
+use crate::error::{CLIError, TopiaryError};
+
+/// How errors and warnings are reported
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum DiagnosticsFormat {
+    // Human-readable messages, through the logger
+    #[default]
+    Human,
+
+    // One JSON object per diagnostic and per line, on standard error
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A position in the input, where lines and columns start at 1
+#[derive(Debug, Serialize)]
+pub struct Position {
+    line: u32,
+    column: u32,
+}
+
+/// A machine-readable account of an error or of a warning
+#[derive(Debug, Serialize)]
+pub struct Diagnostic {
+    /// The input the diagnostic relates to, if known
+    input: Option<String>,
+    severity: Severity,
+    kind: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cause: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start: Option<Position>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end: Option<Position>,
+    /// The byte offsets in the input the diagnostic relates to, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    span: Option<Range<usize>>,
+}
+
+impl Diagnostic {
+    /// The diagnostics describing warnings, one each.
+    pub fn from_warnings(input: Option<String>, warnings: &[String]) -> Vec<Self> {
+        warnings
+            .iter()
+            .map(|warning| Self {
+                input: input.clone(),
+                severity: Severity::Warning,
+                kind: "warning",
+                message: warning.clone(),
+                cause: None,
+                start: None,
+                end: None,
+                span: None,
+            })
+            .collect()
+    }
+
+    /// The diagnostics describing an error. Denied warnings give one diagnostic each.
+    pub fn from_error(input: Option<String>, error: &TopiaryError) -> Vec<Self> {
+        if let TopiaryError::Lib(FormatterError::Warnings(warnings)) = error {
+            return Self::from_warnings(input, warnings);
+        }
+
+        let (start, end) = match error {
+            TopiaryError::Lib(FormatterError::Parsing {
+                start_line,
+                start_column,
+                end_line,
+                end_column,
+                ..
+            }) => (
+                Some(Position {
+                    line: *start_line,
+                    column: *start_column,
+                }),
+                Some(Position {
+                    line: *end_line,
+                    column: *end_column,
+                }),
+            ),
+            _ => (None, None),
+        };
+        let span = match error {
+            TopiaryError::Lib(error) => error.span(),
+            _ => None,
+        };
+
+        vec![Self {
+            input,
+            severity: Severity::Error,
+            kind: kind(error),
+            message: error.to_string(),
+            cause: error.source().map(|source| source.to_string()),
+            start,
+            end,
+            span,
+        }]
+    }
+}
+
+/// A short, stable name for the kind of an error
+fn kind(error: &TopiaryError) -> &'static str {
+    match error {
+        TopiaryError::Lib(error) => match error {
+            FormatterError::GrammarVersion { .. } => "grammar_version",
+            FormatterError::Idempotence => "idempotence",
+            FormatterError::IdempotenceParsing(_) => "idempotence_parsing",
+            FormatterError::Internal(_, _) => "internal",
+            FormatterError::InvalidRange { .. } => "invalid_range",
+            FormatterError::Parsing { .. } => "parsing",
+            FormatterError::PatternDoesNotMatch(_) => "pattern_does_not_match",
+            FormatterError::Query { .. } => "query",
+            FormatterError::TokenMismatch { .. } => "token_mismatch",
+            FormatterError::TooDeep { .. } => "too_deep",
+            FormatterError::Io(_) => "io",
+            FormatterError::Warnings(_) => "warning",
+        },
+        TopiaryError::Bin(_, Some(CLIError::IOError(_))) => "io",
+        TopiaryError::Bin(_, Some(CLIError::UnsupportedLanguage(_)))
+        | TopiaryError::Bin(_, Some(CLIError::LanguageDetection(_, _))) => "language_detection",
+        TopiaryError::Bin(_, _) => "cli",
+        TopiaryError::Config(_) => "configuration",
+    }
+}
+
+/// Writes the diagnostics to standard error, as JSON Lines
+pub fn emit(diagnostics: &[Diagnostic]) {
+    let mut stderr = std::io::stderr().lock();
+    for diagnostic in diagnostics {
+        // Diagnostics only contain strings and numbers, so they always serialise
+        if let Ok(json) = serde_json::to_string(diagnostic) {
+            let _ = writeln!(stderr, "{json}");
+        }
+    }
+}
@@ -25,6 +25,9 @@ pub enum CLIError {
 
     /// Could not detect the input language from the `(filename, Option<extension>)`
     LanguageDetection(PathBuf, Option<String>),
+
+    /// The wrapped error has already been reported as diagnostics
+    Reported(Box<TopiaryError>),
 }
 
 /// # Safety
@@ -59,6 +62,7 @@ impl error::Error for TopiaryError {
             TopiaryError::Bin(_, Some(CLIError::Multiple)) => None,
             TopiaryError::Bin(_, Some(CLIError::UnsupportedLanguage(_))) => None,
             TopiaryError::Bin(_, Some(CLIError::LanguageDetection(_, _))) => None,
+            TopiaryError::Bin(_, Some(CLIError::Reported(error))) => Some(error.as_ref()),
             TopiaryError::Bin(_, None) => None,
             TopiaryError::Config(error) => error.source(),
         }
@@ -67,36 +71,44 @@ impl error::Error for TopiaryError {
 
 impl From<TopiaryError> for ExitCode {
     fn from(e: TopiaryError) -> Self {
-        let exit_code = match e {
-            // Multiple errors: Exit 9
-            TopiaryError::Bin(_, Some(CLIError::Multiple)) => 9,
+        ExitCode::from(exit_code(&e))
+    }
+}
+
+fn exit_code(e: &TopiaryError) -> u8 {
+    match e {
+        // Errors which have already been reported exit as they would have otherwise
+        TopiaryError::Bin(_, Some(CLIError::Reported(error))) => exit_code(error),
+
+        // Denied warnings: Exit 10
+        TopiaryError::Lib(FormatterError::Warnings(_)) => 10,
 
-            // Idempotency parsing errors: Exit 8
-            TopiaryError::Lib(FormatterError::IdempotenceParsing(_)) => 8,
+        // Multiple errors: Exit 9
+        TopiaryError::Bin(_, Some(CLIError::Multiple)) => 9,
 
-            // Idempotency errors: Exit 7
-            TopiaryError::Lib(FormatterError::Idempotence) => 7,
+        // Idempotency parsing errors: Exit 8
+        TopiaryError::Lib(FormatterError::IdempotenceParsing(_)) => 8,
 
-            // Exit 6 no longer exists and is now reserved for compatibility reasons
+        // Idempotency errors: Exit 7
+        TopiaryError::Lib(FormatterError::Idempotence) => 7,
 
-            // Parsing errors: Exit 5
-            TopiaryError::Lib(FormatterError::Parsing { .. }) => 5,
+        // Exit 6 no longer exists and is now reserved for compatibility reasons
 
-            // Query errors: Exit 4
-            TopiaryError::Lib(FormatterError::Query(_, _)) => 4,
+        // Parsing errors: Exit 5
+        TopiaryError::Lib(FormatterError::Parsing { .. }) => 5,
 
-            // I/O errors: Exit 3
-            TopiaryError::Lib(FormatterError::Io(_))
-            | TopiaryError::Bin(_, Some(CLIError::IOError(_))) => 3,
+        // Query errors: Exit 4
+        TopiaryError::Lib(FormatterError::Query { .. }) => 4,
 
-            // Bad arguments: Exit 2
-            // (Handled by clap: https://github.com/clap-rs/clap/issues/3426)
+        // I/O errors: Exit 3
+        TopiaryError::Lib(FormatterError::Io(_))
+        | TopiaryError::Bin(_, Some(CLIError::IOError(_))) => 3,
 
-            // Anything else: Exit 1
-            _ => 1,
-        };
+        // Bad arguments: Exit 2
+        // (Handled by clap: https://github.com/clap-rs/clap/issues/3426)
 
-        ExitCode::from(exit_code)
+        // Anything else: Exit 1
+        _ => 1,
     }
 }
 
@@ -8,7 +8,7 @@ use std::{
 
 use tempfile::NamedTempFile;
 use topiary_config::Configuration;
-use topiary_core::{Language, TopiaryQuery};
+use topiary_core::{KindSpacing, Language, TopiaryQuery};
 
 use crate::{
     cli::{AtLeastOneInput, ExactlyOneInput, FromStdin},
@@ -118,19 +118,7 @@ pub struct InputFile<'cfg> {
 impl<'cfg> InputFile<'cfg> {
     /// Convert our `InputFile` into language definition values that Topiary can consume
     pub async fn to_language(&self) -> CLIResult<Language> {
-        let grammar = self.language().grammar()?;
-        let contents = match &self.query {
-            QuerySource::Path(query) => tokio::fs::read_to_string(query).await?,
-            QuerySource::BuiltIn(contents) => contents.to_owned(),
-        };
-        let query = TopiaryQuery::new(&grammar, &contents)?;
-
-        Ok(Language {
-            name: self.language.name.clone(),
-            query,
-            grammar,
-            indent: self.language().config.indent.clone(),
-        })
+        to_language(self.language, &self.query).await
     }
 
     /// Expose input source
@@ -147,6 +135,79 @@ impl<'cfg> InputFile<'cfg> {
     pub fn query(&self) -> &QuerySource {
         &self.query
     }
+
+    /// Whether the input is a file excluded by the ignore patterns of its language
+    pub fn is_ignored(&self) -> CLIResult<bool> {
+        match &self.source {
+            InputSource::Disk(path, _) => Ok(self.language.is_ignored(path)?),
+            InputSource::Stdin => Ok(false),
+        }
+    }
+
+    /// Whether the input may be formatted, which is not the case of the files of the languages
+    /// whose configuration sets `format` to false
+    pub fn is_formatted(&self) -> bool {
+        match &self.source {
+            InputSource::Disk(_, _) => self.language.config.format,
+            InputSource::Stdin => true,
+        }
+    }
+}
+
+/// Convert a language of the configuration, with the given query, into language definition values
+/// that Topiary can consume
+pub async fn to_language(
+    language: &topiary_config::language::Language,
+    query: &QuerySource,
+) -> CLIResult<Language> {
+    let grammar = language.grammar()?;
+    let contents = match query {
+        QuerySource::Path(query) => read_query(query).await?,
+        QuerySource::BuiltIn(contents) => contents.to_owned(),
+    };
+    let spacing: Vec<(&str, KindSpacing)> = language
+        .config
+        .spacing
+        .iter()
+        .flatten()
+        .map(|(kind, spacing)| {
+            (
+                kind.as_str(),
+                KindSpacing {
+                    before: spacing.before.clone(),
+                    after: spacing.after.clone(),
+                },
+            )
+        })
+        .collect();
+    let query = TopiaryQuery::with_spacing(
+        &grammar,
+        &contents,
+        spacing.iter().map(|(kind, spacing)| (*kind, spacing)),
+    )?;
+
+    Ok(Language {
+        name: language.name.clone(),
+        query,
+        grammar,
+        indent: language.config.indent.clone(),
+        tab_width: language.config.tab_width.unwrap_or(8),
+        expand_tabs: language.config.expand_tabs.unwrap_or_default(),
+        final_newline: language.config.final_newline.clone(),
+        normalise_indentation: language.config.normalise_indentation.unwrap_or_default(),
+        file_trailer: language.config.file_trailer.clone(),
+        post_process: language.config.post_process.clone(),
+        comment_kinds: language.config.comment_kinds.clone(),
+    })
+}
+
+/// The query of a language of the configuration: the one it contains, if any, otherwise the file
+/// found in the default locations
+pub fn configured_query(language: &topiary_config::language::Language) -> CLIResult<QuerySource> {
+    Ok(match &language.config.query {
+        Some(contents) => contents.as_str().into(),
+        None => language.find_query_file()?.into(),
+    })
 }
 
 impl<'cfg> Read for InputFile<'cfg> {
@@ -178,11 +239,13 @@ impl<'cfg, 'i> Inputs<'cfg> {
             InputFrom::Stdin(language_name, query) => {
                 vec![(|| {
                     let language = config.get_language(&language_name)?;
-                    let query_source: QuerySource = match query {
+                    let query_source: QuerySource = match (query, &language.config.query) {
                         // The user specified a query file
-                        Some(p) => p,
+                        (Some(p), _) => p,
+                        // The configuration contains the query itself
+                        (None, Some(contents)) => contents.as_str().into(),
                         // The user did not specify a file, try the default locations
-                        None => match language.find_query_file() {
+                        (None, None) => match language.find_query_file() {
                             Ok(p) => p.into(),
                             // For some reason, Topiary could not find any
                             // matching file in a default location. As a final attempt, use try to the the
@@ -207,7 +270,7 @@ impl<'cfg, 'i> Inputs<'cfg> {
                 .into_iter()
                 .map(|path| {
                     let language = config.detect(&path)?;
-                    let query = language.find_query_file()?.into();
+                    let query = configured_query(language)?;
 
                     Ok(InputFile {
                         source: InputSource::Disk(path, None),
@@ -318,6 +381,22 @@ impl<'cfg> TryFrom<&InputFile<'cfg>> for OutputFile {
     }
 }
 
+/// Read a query file, without the byte order mark some editors put at the start of UTF-8 files
+async fn read_query(path: &Path) -> CLIResult<String> {
+    let bytes = tokio::fs::read(path).await?;
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(&bytes);
+
+    String::from_utf8(bytes.to_vec()).map_err(|e| {
+        TopiaryError::Bin(
+            format!("Query file {} is not valid UTF-8", path.to_string_lossy()),
+            Some(CLIError::IOError(std::io::Error::new(
+                ErrorKind::InvalidData,
+                e,
+            ))),
+        )
+    })
+}
+
 fn to_query<T>(name: T) -> CLIResult<QuerySource>
 where
     T: AsRef<str> + fmt::Display,
@@ -8,9 +8,13 @@ use std::{
 };
 
 use tokio::sync::Mutex;
+use topiary_config::Configuration;
 use topiary_core::Language;
 
-use crate::{error::CLIResult, io::InputFile};
+use crate::{
+    error::CLIResult,
+    io::{configured_query, to_language, InputFile, QuerySource},
+};
 
 /// Thread-safe language definition cache
 pub struct LanguageDefinitionCache(Mutex<HashMap<u64, Arc<Language>>>);
@@ -22,12 +26,42 @@ impl LanguageDefinitionCache {
 
     /// Fetch the language definition from the cache, populating if necessary, with thread-safety
     pub async fn fetch<'i>(&self, input: &'i InputFile<'i>) -> CLIResult<Arc<Language>> {
-        // There's no need to store the input's identifying information (language name and query)
+        self.fetch_language(input.language(), input.query()).await
+    }
+
+    /// Fetch the definitions of the languages that the query of a language embeds, with
+    /// `#embedded_language!`, from the cache, populating if necessary. Those that are not in the
+    /// configuration are left out, and formatting warns about them.
+    pub async fn fetch_embedded(
+        &self,
+        config: &Configuration,
+        language: &Language,
+    ) -> CLIResult<Vec<Arc<Language>>> {
+        let mut embedded = Vec::new();
+        for name in language.query.embedded_languages() {
+            let Ok(language) = config.get_language(&name) else {
+                continue;
+            };
+            let query = configured_query(language)?;
+            embedded.push(self.fetch_language(language, &query).await?);
+        }
+
+        Ok(embedded)
+    }
+
+    /// Fetch the definition of a language of the configuration, with the given query, from the
+    /// cache, populating if necessary, with thread-safety
+    async fn fetch_language(
+        &self,
+        language: &topiary_config::language::Language,
+        query: &QuerySource,
+    ) -> CLIResult<Arc<Language>> {
+        // There's no need to store the identifying information (language name and query)
         // in the key, so we use its hash directly. This side-steps any awkward lifetime issues.
         let key = {
             let mut hash = DefaultHasher::new();
-            input.language().name.hash(&mut hash);
-            input.query().hash(&mut hash);
+            language.name.hash(&mut hash);
+            query.hash(&mut hash);
 
             hash.finish()
         };
@@ -43,8 +77,8 @@ impl LanguageDefinitionCache {
                     "Cache {:p}: Hit at {:#016x} ({}, {})",
                     self,
                     key,
-                    input.language().name,
-                    input.query()
+                    language.name,
+                    query
                 );
 
                 lang_def.get().to_owned()
@@ -56,11 +90,11 @@ impl LanguageDefinitionCache {
                     "Cache {:p}: Insert at {:#016x} ({}, {})",
                     self,
                     key,
-                    input.language().name,
-                    input.query()
+                    language.name,
+                    query
                 );
 
-                let lang_def = Arc::new(input.to_language().await?);
+                let lang_def = Arc::new(to_language(language, query).await?);
                 slot.insert(lang_def).to_owned()
             }
         })
@@ -1,87 +1,160 @@
 mod cli;
+mod diagnostics;
 mod error;
 mod io;
 mod language;
+mod summary;
 mod visualisation;
 
 use std::{
     error::Error,
     io::{BufReader, BufWriter},
+    mem,
+    ops::Deref,
     process::ExitCode,
 };
 
-use topiary_core::{formatter, Operation};
+use itertools::Itertools;
+use topiary_core::{formatter, formatter_reporting_changes, FormatOptions, Operation};
 
 use crate::{
-    cli::Commands,
+    cli::{Cli, Commands},
+    diagnostics::{Diagnostic, DiagnosticsFormat},
     error::{CLIError, CLIResult, TopiaryError},
     io::{Inputs, OutputFile},
     language::LanguageDefinitionCache,
+    summary::{Outcome, Summary},
 };
 
 #[tokio::main]
 async fn main() -> ExitCode {
-    if let Err(e) = run().await {
-        print_error(&e);
+    let args = match cli::get_args() {
+        Ok(args) => args,
+        Err(e) => {
+            print_error(&e);
+            return e.into();
+        }
+    };
+    let diagnostics = args.global.diagnostics;
+
+    if let Err(e) = run(args).await {
+        report_error(&e, diagnostics);
         return e.into();
     }
 
     ExitCode::SUCCESS
 }
 
-async fn run() -> CLIResult<()> {
-    let args = cli::get_args()?;
-
-    let config = topiary_config::Configuration::fetch(&args.global.configuration)?;
+async fn run(args: Cli) -> CLIResult<()> {
+    let config = topiary_config::Configuration::fetch_layered(&args.global.configuration)?;
+    let diagnostics = args.global.diagnostics;
 
     // Delegate by subcommand
     match args.command {
         Commands::Format {
             tolerate_parsing_errors,
             skip_idempotence,
-            inputs,
+            deny_warnings,
+            named_only,
+            force,
+            summary,
+            mut inputs,
         } => {
+            // Files from a list may be of any kind, e.g. all those changed by a commit, so those
+            // of unsupported languages are skipped rather than failing
+            let mut unsupported = Vec::new();
+            if inputs.files_from.is_some() {
+                (inputs.files, unsupported) = mem::take(&mut inputs.files)
+                    .into_iter()
+                    .partition(|file| config.detect(file).is_ok());
+            }
+
             let inputs = Inputs::new(&config, &inputs);
             let cache = LanguageDefinitionCache::new();
 
-            let (_, mut results) = async_scoped::TokioScope::scope_and_block(|scope| {
+            let mut options = FormatOptions::default();
+            options.skip_idempotence = skip_idempotence;
+            options.tolerate_parsing_errors = tolerate_parsing_errors;
+            options.deny_warnings = deny_warnings;
+            options.named_only = named_only;
+
+            let (_, results) = async_scoped::TokioScope::scope_and_block(|scope| {
                 for input in inputs {
                     scope.spawn(async {
-                        let result: CLIResult<()> = match input {
-                            Ok(input) => {
-                                let language = cache.fetch(&input).await?;
-                                let output = OutputFile::try_from(&input)?;
-
-                                log::info!(
-                                    "Formatting {}, as {} using {}, to {}",
-                                    input.source(),
-                                    input.language().name,
-                                    input.query(),
-                                    output
-                                );
-
-                                let mut buf_input = BufReader::new(input);
-                                let mut buf_output = BufWriter::new(output);
-
-                                formatter(
-                                    &mut buf_input,
-                                    &mut buf_output,
-                                    &language,
-                                    Operation::Format {
-                                        skip_idempotence,
-                                        tolerate_parsing_errors,
-                                    },
-                                )?;
-
-                                buf_output.into_inner()?.persist()?;
-
-                                Ok(())
-                            }
+                        let source = input.as_ref().ok().map(|input| input.source().to_string());
+                        let language = input
+                            .as_ref()
+                            .ok()
+                            .map(|input| input.language().name.clone());
+                        let result: CLIResult<Outcome> = async {
+                            match input {
+                                Ok(input) => {
+                                    if input.is_ignored()? {
+                                        log::info!(
+                                            "Skipping {}, as it is ignored by the configuration",
+                                            input.source()
+                                        );
+                                        return Ok(Outcome::Skipped);
+                                    }
+
+                                    if !force && !input.is_formatted() {
+                                        log::info!(
+                                            "Skipping {}, as {} is configured not to be formatted",
+                                            input.source(),
+                                            input.language().name
+                                        );
+                                        return Ok(Outcome::Skipped);
+                                    }
+
+                                    let language = cache.fetch(&input).await?;
+                                    let embedded = cache.fetch_embedded(&config, &language).await?;
+                                    let embedded: Vec<_> =
+                                        embedded.iter().map(Deref::deref).collect();
+                                    let output = OutputFile::try_from(&input)?;
+
+                                    log::info!(
+                                        "Formatting {}, as {} using {}, to {}",
+                                        input.source(),
+                                        input.language().name,
+                                        input.query(),
+                                        output
+                                    );
 
-                            // This happens when the input resolver cannot establish an input
-                            // source, language or query file.
-                            Err(error) => Err(error),
-                        };
+                                    let mut buf_input = BufReader::new(input);
+                                    let mut buf_output = BufWriter::new(output);
+
+                                    let report = formatter_reporting_changes(
+                                        &mut buf_input,
+                                        &mut buf_output,
+                                        &language,
+                                        &embedded,
+                                        Operation::Format(options),
+                                    )?;
+
+                                    buf_output.into_inner()?.persist()?;
+
+                                    // Warnings that were not denied are only logged, unless
+                                    // diagnostics are reported as JSON
+                                    if diagnostics == DiagnosticsFormat::Json {
+                                        diagnostics::emit(&Diagnostic::from_warnings(
+                                            source.clone(),
+                                            &report.warnings,
+                                        ));
+                                    }
+
+                                    Ok(if report.changed {
+                                        Outcome::Changed
+                                    } else {
+                                        Outcome::Unchanged
+                                    })
+                                }
+
+                                // This happens when the input resolver cannot establish an input
+                                // source, language or query file.
+                                Err(error) => Err(error),
+                            }
+                        }
+                        .await;
 
                         if let Err(error) = &result {
                             // By this point, we've lost any reference to the original
@@ -89,24 +162,71 @@ async fn run() -> CLIResult<()> {
                             log::warn!("Skipping: {error}");
                         }
 
-                        result
+                        (source, language, result)
                     });
                 }
             });
 
-            if results.len() == 1 {
+            let single_input = results.len() == 1;
+            let mut failures: Vec<(Option<String>, TopiaryError)> = Vec::new();
+            let mut outcomes = Summary::default();
+            for result in results {
+                match result {
+                    Ok((source, language, outcome)) => {
+                        outcomes.record(language.as_deref(), &outcome);
+                        if let Err(error) = outcome {
+                            failures.push((source, error));
+                        }
+                    }
+                    Err(error) => {
+                        let error: TopiaryError = error.into();
+                        outcomes.record(None, &Err::<Outcome, _>(&error));
+                        failures.push((None, error));
+                    }
+                }
+            }
+
+            if !unsupported.is_empty() {
+                eprintln!(
+                    "Skipped {} file(s) of unsupported languages: {}",
+                    unsupported.len(),
+                    unsupported.iter().map(|file| file.display()).join(", ")
+                );
+            }
+
+            if summary {
+                eprintln!("{outcomes}");
+            }
+
+            if failures.is_empty() {
+                return Ok(());
+            }
+
+            if diagnostics == DiagnosticsFormat::Json {
+                // Report each failure along with the input it relates to
+                for (source, failure) in &failures {
+                    diagnostics::emit(&Diagnostic::from_error(source.clone(), failure));
+                }
+            }
+
+            let error = if single_input {
                 // If we just had one input, then handle errors as normal
-                results.remove(0)??
-            } else if results
-                .iter()
-                .any(|result| matches!(result, Err(_) | Ok(Err(_))))
-            {
+                failures.remove(0).1
+            } else {
                 // For multiple inputs, bail out if any failed with a "multiple errors" failure
-                return Err(TopiaryError::Bin(
+                TopiaryError::Bin(
                     "Processing of some inputs failed; see warning logs for details".into(),
                     Some(CLIError::Multiple),
-                ));
-            }
+                )
+            };
+
+            return Err(match diagnostics {
+                DiagnosticsFormat::Human => error,
+                DiagnosticsFormat::Json => TopiaryError::Bin(
+                    "Diagnostics were reported".into(),
+                    Some(CLIError::Reported(Box::new(error))),
+                ),
+            });
         }
 
         Commands::Visualise { format, input } => {
@@ -143,6 +263,20 @@ async fn run() -> CLIResult<()> {
             print!("{:#?}", config);
         }
 
+        Commands::Queries => {
+            for (language, query_file) in config.query_files() {
+                match query_file {
+                    _ if language.config.query.is_some() => {
+                        println!("{}: given in the configuration", language.name)
+                    }
+                    Ok((path, location)) => {
+                        println!("{}: {} (from {location})", language.name, path.display())
+                    }
+                    Err(_) => println!("{}: not found", language.name),
+                }
+            }
+        }
+
         Commands::Completion { shell } => {
             // The CLI parser fails if no shell is provided/detected, so it's safe to unwrap here
             cli::completion(shell.unwrap());
@@ -152,6 +286,14 @@ async fn run() -> CLIResult<()> {
     Ok(())
 }
 
+fn report_error(e: &TopiaryError, format: DiagnosticsFormat) {
+    match (format, e) {
+        (_, TopiaryError::Bin(_, Some(CLIError::Reported(_)))) => {}
+        (DiagnosticsFormat::Human, _) => print_error(e),
+        (DiagnosticsFormat::Json, _) => diagnostics::emit(&Diagnostic::from_error(None, e)),
+    }
+}
+
 fn print_error(e: &dyn Error) {
     log::error!("{e}");
     if let Some(source) = e.source() {
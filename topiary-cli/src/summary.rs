@@ -0,0 +1,79 @@
+use std::{collections::BTreeMap, fmt, ops::AddAssign};
+
+/// What became of an input that was processed without error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    // Not formatted, as it is ignored or its language is not to be formatted
+    Skipped,
+
+    // Formatted, and already so
+    Unchanged,
+
+    // Formatted, and rewritten
+    Changed,
+}
+
+/// The number of inputs with each outcome
+#[derive(Clone, Copy, Debug, Default)]
+struct Counts {
+    unchanged: usize,
+    changed: usize,
+    failed: usize,
+}
+
+impl AddAssign for Counts {
+    fn add_assign(&mut self, other: Self) {
+        self.unchanged += other.unchanged;
+        self.changed += other.changed;
+        self.failed += other.failed;
+    }
+}
+
+impl fmt::Display for Counts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} unchanged, {} changed, {} failed",
+            self.unchanged, self.changed, self.failed
+        )
+    }
+}
+
+/// How many inputs of each language were unchanged, changed or failed, for the `--summary` of
+/// the `format` subcommand. Skipped inputs are not counted.
+#[derive(Debug, Default)]
+pub struct Summary(BTreeMap<String, Counts>);
+
+impl Summary {
+    /// Counts the outcome of an input, whose language is unknown if it could not be detected
+    pub fn record<E>(&mut self, language: Option<&str>, outcome: &Result<Outcome, E>) {
+        if let Ok(Outcome::Skipped) = outcome {
+            return;
+        }
+
+        let counts = self
+            .0
+            .entry(language.unwrap_or("unknown").to_string())
+            .or_default();
+
+        match outcome {
+            Ok(Outcome::Unchanged) => counts.unchanged += 1,
+            Ok(Outcome::Changed) => counts.changed += 1,
+            Ok(Outcome::Skipped) => {}
+            Err(_) => counts.failed += 1,
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut total = Counts::default();
+
+        for (language, counts) in &self.0 {
+            writeln!(f, "{language}: {counts}")?;
+            total += *counts;
+        }
+
+        write!(f, "total: {total}")
+    }
+}
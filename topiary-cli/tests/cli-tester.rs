@@ -3,7 +3,7 @@ use std::{fmt, fs, fs::File, io::Write, path::PathBuf};
 use assert_cmd::Command;
 use predicates::{
     prelude::PredicateBooleanExt,
-    str::{ends_with, starts_with},
+    str::{contains, ends_with, starts_with},
 };
 use tempfile::TempDir;
 
@@ -78,6 +78,27 @@ fn test_fmt_stdin_query() {
         .stdout(JSON_EXPECTED);
 }
 
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_stdin_query_with_bom() {
+    let query = fs::read_to_string("../topiary-queries/queries/json.scm").unwrap();
+    let query = State::new(&format!("\u{feff}{query}"), "scm");
+
+    let mut topiary = Command::cargo_bin("topiary").unwrap();
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--language")
+        .arg("json")
+        .arg("--query")
+        .arg(query.path())
+        .write_stdin(JSON_INPUT)
+        .assert()
+        .success()
+        .stdout(JSON_EXPECTED);
+}
+
 #[test]
 #[cfg(all(feature = "json", feature = "toml"))]
 fn test_fmt_files() {
@@ -115,6 +136,182 @@ fn test_fmt_dir() {
     assert_eq!(json.read(), JSON_EXPECTED);
 }
 
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_dir_summary() {
+    let json = State::new(JSON_INPUT, "json");
+    let dir = json.path().parent().unwrap();
+    fs::write(dir.join("formatted.json"), JSON_EXPECTED).unwrap();
+    fs::write(dir.join("invalid.json"), "{ \"test\": ").unwrap();
+
+    let mut topiary = Command::cargo_bin("topiary").unwrap();
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--summary")
+        .arg(dir)
+        .assert()
+        .failure()
+        .stderr(contains(
+            "json: 1 unchanged, 1 changed, 1 failed\ntotal: 1 unchanged, 1 changed, 1 failed\n",
+        ));
+
+    assert_eq!(json.read(), JSON_EXPECTED);
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_dir_ignore() {
+    let json = State::new(JSON_INPUT, "json");
+    let dir = json.path().parent().unwrap();
+
+    // A generated file in the same directory is excluded by the configuration
+    let generated = dir.join("state.generated.json");
+    fs::write(&generated, JSON_INPUT).unwrap();
+
+    let config = State::new(
+        r#"{ languages = { json = { ignore = ["*.generated.json"] } } }"#,
+        "ncl",
+    );
+
+    let mut topiary = Command::cargo_bin("topiary").unwrap();
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--configuration")
+        .arg(config.path())
+        .arg(dir)
+        .assert()
+        .success();
+
+    assert_eq!(json.read(), JSON_EXPECTED);
+    assert_eq!(fs::read_to_string(generated).unwrap(), JSON_INPUT);
+}
+
+#[test]
+#[cfg(all(feature = "json", feature = "toml"))]
+fn test_fmt_dir_detect_only() {
+    let json = State::new(JSON_INPUT, "json");
+    let dir = json.path().parent().unwrap();
+    let toml = dir.join("state.toml");
+    fs::write(&toml, TOML_INPUT).unwrap();
+
+    let config = State::new(r#"{ languages = { json = { format = false } } }"#, "ncl");
+
+    let mut topiary = Command::cargo_bin("topiary").unwrap();
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--configuration")
+        .arg(config.path())
+        .arg(dir)
+        .assert()
+        .success();
+
+    // JSON is detected, but left untouched
+    assert_eq!(json.read(), JSON_INPUT);
+    assert_eq!(fs::read_to_string(toml).unwrap(), TOML_EXPECTED);
+
+    let mut topiary = Command::cargo_bin("topiary").unwrap();
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--configuration")
+        .arg(config.path())
+        .arg("--force")
+        .arg(dir)
+        .assert()
+        .success();
+
+    assert_eq!(json.read(), JSON_EXPECTED);
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_stdin_configured_query() {
+    let config = State::new(
+        r#"{ languages = { json = { query = m%"(pair ":" @append_space)"% } } }"#,
+        "ncl",
+    );
+    let no_queries = TempDir::new().unwrap();
+
+    let mut topiary = Command::cargo_bin("topiary").unwrap();
+
+    // The query given in the configuration is used, rather than json.scm
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", no_queries.path())
+        .arg("fmt")
+        .arg("--configuration")
+        .arg(config.path())
+        .arg("--language")
+        .arg("json")
+        .write_stdin(r#"{"test":123}"#)
+        .assert()
+        .success()
+        .stdout("{\"test\": 123}\n");
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_dir_topiaryignore() {
+    let json = State::new(JSON_INPUT, "json");
+    let dir = json.path().parent().unwrap();
+
+    // A file in an ignored subdirectory is left untouched, as is the ignore file itself
+    let vendored = dir.join("vendor").join("state.json");
+    fs::create_dir(vendored.parent().unwrap()).unwrap();
+    fs::write(&vendored, JSON_INPUT).unwrap();
+    fs::write(dir.join(".topiaryignore"), "# Third-party files\nvendor/\n").unwrap();
+
+    let mut topiary = Command::cargo_bin("topiary").unwrap();
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg(dir)
+        .assert()
+        .success();
+
+    assert_eq!(json.read(), JSON_EXPECTED);
+    assert_eq!(fs::read_to_string(vendored).unwrap(), JSON_INPUT);
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_files_from() {
+    let json = State::new(JSON_INPUT, "json");
+    let dir = json.path().parent().unwrap();
+    let unsupported = dir.join("notes.xyz");
+    fs::write(&unsupported, JSON_INPUT).unwrap();
+
+    let mut topiary = Command::cargo_bin("topiary").unwrap();
+
+    // The list is read from stdin, as from `git diff --name-only`
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--files-from")
+        .arg("-")
+        .write_stdin(format!(
+            "{}\n{}\n",
+            json.path().display(),
+            unsupported.display()
+        ))
+        .assert()
+        .success()
+        .stderr(contains(format!(
+            "Skipped 1 file(s) of unsupported languages: {}",
+            unsupported.display()
+        )));
+
+    assert_eq!(json.read(), JSON_EXPECTED);
+    assert_eq!(fs::read_to_string(unsupported).unwrap(), JSON_INPUT);
+}
+
 #[test]
 #[cfg(feature = "json")]
 fn test_fmt_invalid() {
@@ -140,6 +337,116 @@ fn test_fmt_invalid() {
         .failure();
 }
 
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_deny_warnings() {
+    let mut topiary = Command::cargo_bin("topiary").unwrap();
+
+    // A clean input passes
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--language")
+        .arg("json")
+        .arg("--deny-warnings")
+        .write_stdin(JSON_INPUT)
+        .assert()
+        .success()
+        .stdout(JSON_EXPECTED);
+
+    // Closing an unopened scope raises a warning, which is denied
+    let query = State::new(
+        &format!(
+            "{}\n(object \"}}\" @append_end_scope (#scope_id! \"x\"))",
+            fs::read_to_string("../topiary-queries/queries/json.scm").unwrap()
+        ),
+        "scm",
+    );
+
+    let mut topiary = Command::cargo_bin("topiary").unwrap();
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--language")
+        .arg("json")
+        .arg("--query")
+        .arg(query.path())
+        .arg("--deny-warnings")
+        .write_stdin(JSON_INPUT)
+        .assert()
+        .failure()
+        .code(10);
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_json_diagnostics() {
+    let query = State::new("(object) @not_a_capture", "scm");
+
+    let mut topiary = Command::cargo_bin("topiary").unwrap();
+
+    let output = topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--diagnostics")
+        .arg("json")
+        .arg("--language")
+        .arg("json")
+        .arg("--query")
+        .arg(query.path())
+        .write_stdin(JSON_INPUT)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(4));
+
+    // Standard error holds a single diagnostic, as a line of JSON
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let diagnostic: serde_json::Value = serde_json::from_str(stderr.trim_end()).unwrap();
+    assert_eq!(diagnostic["input"], "standard input");
+    assert_eq!(diagnostic["severity"], "error");
+    assert_eq!(diagnostic["kind"], "query");
+    assert!(diagnostic["message"]
+        .as_str()
+        .unwrap()
+        .contains("@not_a_capture"));
+    // The error is located at the object the capture matched
+    assert_eq!(diagnostic["span"]["start"], 0);
+    assert_eq!(diagnostic["span"]["end"], JSON_INPUT.len());
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_json_diagnostics_warnings() {
+    let query = State::new(r#"(object "}" @append_end_scope (#scope_id! "x"))"#, "scm");
+
+    let mut topiary = Command::cargo_bin("topiary").unwrap();
+
+    let output = topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--diagnostics")
+        .arg("json")
+        .arg("--language")
+        .arg("json")
+        .arg("--query")
+        .arg(query.path())
+        .write_stdin(JSON_INPUT)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // Warnings that are not denied are reported too
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let diagnostic: serde_json::Value = serde_json::from_str(stderr.trim_end()).unwrap();
+    assert_eq!(diagnostic["input"], "standard input");
+    assert_eq!(diagnostic["severity"], "warning");
+    assert!(diagnostic["message"]
+        .as_str()
+        .unwrap()
+        .contains("Closing unopened scope"));
+}
+
 #[test]
 #[cfg(feature = "json")]
 fn test_vis() {
@@ -205,6 +512,43 @@ fn test_cfg() {
         .stdout(IsToml);
 }
 
+#[test]
+fn test_queries() {
+    let mut topiary = Command::cargo_bin("topiary").unwrap();
+    let json_query = PathBuf::from("../topiary-queries/queries").join("json.scm");
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("queries")
+        .assert()
+        .success()
+        .stdout(contains(format!(
+            "json: {} (from the TOPIARY_LANGUAGE_DIR environment variable)\n",
+            json_query.display()
+        )));
+}
+
+#[test]
+fn test_queries_in_working_directory() {
+    let mut topiary = Command::cargo_bin("topiary").unwrap();
+    let working_dir = TempDir::new().unwrap();
+    let queries = working_dir.path().join("topiary-queries").join("queries");
+    fs::create_dir_all(&queries).unwrap();
+    fs::write(queries.join("json.scm"), "").unwrap();
+    let json_query = PathBuf::from("./topiary-queries/queries").join("json.scm");
+
+    topiary
+        .current_dir(working_dir.path())
+        .env_remove("TOPIARY_LANGUAGE_DIR")
+        .arg("queries")
+        .assert()
+        .success()
+        .stdout(contains(format!(
+            "json: {} (from the working directory)\n",
+            json_query.display()
+        )));
+}
+
 struct IsToml;
 
 impl predicates::Predicate<str> for IsToml {
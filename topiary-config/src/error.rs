@@ -8,6 +8,7 @@ pub enum TopiaryConfigError {
     UnknownLanguage(String),
     UnknownExtension(String),
     NoExtension(path::PathBuf),
+    InvalidGlob(String, glob::PatternError),
     #[cfg(not(target_arch = "wasm32"))]
     QueryFileNotFound(path::PathBuf),
     IoError(io::Error),
@@ -15,6 +16,8 @@ pub enum TopiaryConfigError {
     TreeSitterFacade(topiary_tree_sitter_facade::LanguageError),
     Nickel(nickel_lang_core::error::Error),
     NickelDeserialization(nickel_lang_core::deserialize::RustDeserializationError),
+    Deserialization(serde_json::Error),
+    InvalidInheritance(String, String),
     #[cfg(not(target_arch = "wasm32"))]
     LibLoading(libloading::Error),
     #[cfg(not(target_arch = "wasm32"))]
@@ -32,11 +35,14 @@ impl fmt::Display for TopiaryConfigError {
             TopiaryConfigError::NoExtension(path) => write!(f, "You tried to format {} without specifying a language, but we cannot automatically detect the language because we can't find the filetype extension.", path.to_string_lossy()),
             #[cfg(not(target_arch = "wasm32"))]
             TopiaryConfigError::QueryFileNotFound(path) => write!(f, "We could not find the query file: \"{}\" anywhere. If you use the TOPIARY_LANGUAGE_DIR environment variable, make sure it set set correctly.", path.to_string_lossy()),
+            TopiaryConfigError::InvalidGlob(pattern, e) => write!(f, "The ignore pattern \"{pattern}\" is not a valid glob: {e}"),
             TopiaryConfigError::IoError(error) => write!(f, "We encountered an io error: {error}"),
             TopiaryConfigError::Missing => write!(f, "A configuration file is missing. If you passed a configuration file, make sure it exists."),
             TopiaryConfigError::TreeSitterFacade(_) => write!(f, "We could not load the grammar for the given language"),
             TopiaryConfigError::Nickel(e) => write!(f, "Nickel error: {:?}", e),
             TopiaryConfigError::NickelDeserialization(e) => write!(f, "Nickel error: {:?}", e),
+            TopiaryConfigError::Deserialization(e) => write!(f, "The configuration is not valid: {e}"),
+            TopiaryConfigError::InvalidInheritance(lang, base) => write!(f, "Language \"{lang}\" inherits from \"{base}\", which is either not a known language or inherits from \"{lang}\" in turn."),
             #[cfg(not(target_arch = "wasm32"))]
             TopiaryConfigError::LibLoading(e) => write!(f, "Libloading error: {:?}", e),
             #[cfg(not(target_arch = "wasm32"))]
@@ -53,6 +59,12 @@ impl From<nickel_lang_core::deserialize::RustDeserializationError> for TopiaryCo
     }
 }
 
+impl From<serde_json::Error> for TopiaryConfigError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Deserialization(e)
+    }
+}
+
 impl From<nickel_lang_core::error::Error> for TopiaryConfigError {
     fn from(e: nickel_lang_core::error::Error) -> Self {
         Self::Nickel(e)
@@ -90,6 +102,8 @@ impl error::Error for TopiaryConfigError {
         match self {
             #[cfg(not(target_arch = "wasm32"))]
             TopiaryConfigError::IoError(e) => e.source(),
+            TopiaryConfigError::InvalidGlob(_, e) => Some(e),
+            TopiaryConfigError::Deserialization(e) => Some(e),
             _ => None,
         }
     }
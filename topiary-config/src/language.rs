@@ -1,10 +1,15 @@
 //! This module contains the `Language` struct, which represents a language configuration, and
 //! associated methods.
 
-#[cfg(not(target_arch = "wasm32"))]
-use crate::error::TopiaryConfigError;
-use crate::error::TopiaryConfigResult;
-use std::collections::HashSet;
+use crate::error::{TopiaryConfigError, TopiaryConfigResult};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    path::Path,
+};
+
+/// The post-processing passes of the formatter, named in snake case in the configuration
+pub use topiary_core::PostProcessPass;
 
 #[cfg(not(target_arch = "wasm32"))]
 use git2::Oid;
@@ -44,8 +49,72 @@ pub struct LanguageConfiguration {
     /// "\t", etc.)
     pub indent: Option<String>,
 
+    /// The width of a tab stop, used to compute the columns of the output and to expand tabs;
+    /// defaults to 8.
+    pub tab_width: Option<usize>,
+
+    /// Whether leading tabs in the input are expanded to spaces, with a tab stop every
+    /// `tab_width` columns, before formatting. Defaults to false.
+    pub expand_tabs: Option<bool>,
+
+    /// The string the formatted output ends with; defaults to "\n". It can be set, e.g., to
+    /// "\r\n", or to "" for the output not to end with a newline.
+    pub final_newline: Option<String>,
+
+    /// Whether the indentation of the formatted output is rewritten in the unit of `indent`, i.e.
+    /// tabs if it is made of tabs and spaces otherwise, including indentation of a width that is
+    /// not a multiple of it. Whitespace within tokens is left as is. Defaults to false.
+    pub normalise_indentation: Option<bool>,
+
+    /// A trailer, such as a comment recording that the file is generated, that ends the formatted
+    /// output unless it already does. It must be valid in the language, e.g. a comment.
+    pub file_trailer: Option<String>,
+
+    /// The order in which to run the post-processing passes on the formatted output. Passes that
+    /// are left out run afterwards, in their default order, as do all of them if not provided.
+    pub post_process: Option<Vec<PostProcessPass>>,
+
+    /// The kinds of the nodes that are comments, e.g. `["line_comment", "block_comment"]`. If not
+    /// provided, every kind whose name contains "comment" is.
+    pub comment_kinds: Option<Vec<String>>,
+
+    /// Spacing to insert around every node of the given kinds, in addition to what the query
+    /// specifies. This allows simple spacing rules to be declared without writing queries.
+    pub spacing: Option<BTreeMap<String, SpacingConfiguration>>,
+
+    /// Glob patterns of the files to skip when formatting, even though their extension is
+    /// associated with this language (e.g., generated files). Patterns are matched against the
+    /// whole path of the file, as given to Topiary.
+    pub ignore: Option<Vec<String>>,
+
+    /// The query used to format this language, given as a string rather than as a file. When it is
+    /// provided, no query file is looked up. This allows tools that embed Topiary to ship their
+    /// queries along with their configuration.
+    pub query: Option<String>,
+
+    /// Whether Topiary formats the files of this language; defaults to true. When it is false, the
+    /// language is still detected, but its files are left untouched unless formatting is forced.
+    #[serde(default = "format_by_default")]
+    pub format: bool,
+
     /// The tree-sitter source of the language, contains all that is needed to pull and compile the tree-sitter grammar
     pub grammar: GrammarSource,
+
+    /// The name of a language this one is a dialect of. Every field this language does not set is
+    /// taken from that language, and its query file is used if this language has none.
+    pub inherits: Option<String>,
+}
+
+fn format_by_default() -> bool {
+    true
+}
+
+/// The spacing to insert before and after the nodes of a kind. Each directive is the name of an
+/// append/prepend capture without its prefix, such as "space" or "hardline".
+#[derive(Debug, serde::Deserialize, PartialEq, serde::Serialize, Clone)]
+pub struct SpacingConfiguration {
+    pub before: Option<String>,
+    pub after: Option<String>,
 }
 
 #[derive(Debug, serde::Deserialize, PartialEq, serde::Serialize, Clone)]
@@ -63,28 +132,96 @@ pub struct GrammarSource {
     pub subdir: Option<String>,
 }
 
+/// The locations searched for query files, in order of precedence.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryLocation {
+    /// The directory given by the `TOPIARY_LANGUAGE_DIR` environment variable
+    Environment,
+    /// The directory given by `TOPIARY_LANGUAGE_DIR` when Topiary was built
+    Build,
+    /// `topiary-queries/queries` in the working directory
+    WorkingDirectory,
+    /// `topiary-queries/queries` in the parent of the working directory
+    ParentDirectory,
+}
+
+impl fmt::Display for QueryLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryLocation::Environment => {
+                write!(f, "the TOPIARY_LANGUAGE_DIR environment variable")
+            }
+            QueryLocation::Build => write!(f, "the TOPIARY_LANGUAGE_DIR set at build time"),
+            QueryLocation::WorkingDirectory => write!(f, "the working directory"),
+            QueryLocation::ParentDirectory => write!(f, "the parent of the working directory"),
+        }
+    }
+}
+
 impl Language {
     pub fn new(name: String, config: LanguageConfiguration) -> Self {
         Self { name, config }
     }
 
+    /// Whether the file at the given path is excluded by the ignore patterns of this language.
+    ///
+    /// # Errors
+    ///
+    /// If one of the ignore patterns is not a valid glob, a `TopiaryConfigError` is returned.
+    pub fn is_ignored<P: AsRef<Path>>(&self, path: P) -> TopiaryConfigResult<bool> {
+        for pattern in self.config.ignore.iter().flatten() {
+            let glob = glob::Pattern::new(pattern)
+                .map_err(|e| TopiaryConfigError::InvalidGlob(pattern.to_string(), e))?;
+
+            if glob.matches_path(path.as_ref()) {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
     #[cfg(not(target_arch = "wasm32"))]
     pub fn find_query_file(&self) -> TopiaryConfigResult<PathBuf> {
+        self.find_query_file_with_location().map(|(path, _)| path)
+    }
+
+    /// Like `find_query_file`, but also reports which of the searched locations the query file
+    /// was found in.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn find_query_file_with_location(&self) -> TopiaryConfigResult<(PathBuf, QueryLocation)> {
         let basename = PathBuf::from(self.name.as_str()).with_extension("scm");
+        // A dialect falls back to the query file of the language it inherits from
+        let basenames: Vec<PathBuf> = std::iter::once(basename.clone())
+            .chain(
+                self.config
+                    .inherits
+                    .iter()
+                    .map(|base| PathBuf::from(base).with_extension("scm")),
+            )
+            .collect();
 
         #[rustfmt::skip]
-        let potentials: [Option<PathBuf>; 4] = [
-            std::env::var("TOPIARY_LANGUAGE_DIR").map(PathBuf::from).ok(),
-            option_env!("TOPIARY_LANGUAGE_DIR").map(PathBuf::from),
-            Some(PathBuf::from("./topiary-queries/queries")),
-            Some(PathBuf::from("../topiary-queries/queries")),
+        let potentials: [(Option<PathBuf>, QueryLocation); 4] = [
+            (std::env::var("TOPIARY_LANGUAGE_DIR").map(PathBuf::from).ok(), QueryLocation::Environment),
+            (option_env!("TOPIARY_LANGUAGE_DIR").map(PathBuf::from), QueryLocation::Build),
+            (Some(PathBuf::from("./topiary-queries/queries")), QueryLocation::WorkingDirectory),
+            (Some(PathBuf::from("../topiary-queries/queries")), QueryLocation::ParentDirectory),
         ];
 
-        potentials
+        let directories: Vec<(PathBuf, QueryLocation)> = potentials
             .into_iter()
-            .flatten()
-            .map(|path| path.join(&basename))
-            .find(|path| path.exists())
+            .filter_map(|(path, location)| path.map(|path| (path, location)))
+            .collect();
+
+        basenames
+            .iter()
+            .flat_map(|basename| {
+                directories
+                    .iter()
+                    .map(move |(path, location)| (path.join(basename), *location))
+            })
+            .find(|(path, _)| path.exists())
             .ok_or_else(|| TopiaryConfigError::QueryFileNotFound(basename))
     }
 
@@ -9,6 +9,7 @@ use std::{
     collections::HashMap,
     fmt,
     path::{Path, PathBuf},
+    slice,
 };
 
 use language::{Language, LanguageConfiguration};
@@ -30,6 +31,23 @@ pub struct Configuration {
     languages: Vec<Language>,
 }
 
+/// How the configuration sources are collated, each source taking precedence over those before
+/// it. The mode is chosen by the `collation` field of the highest-priority configuration file that
+/// sets it. As merging is the default, the merge of the sources is evaluated first, and kept unless
+/// it sets another mode or fails; only then are the files evaluated on their own to find out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CollationMode {
+    /// The sources are merged by Nickel, which fails on values of the same priority that differ.
+    #[default]
+    Merge,
+    /// The values of each source replace those of the sources before it, records being merged
+    /// field by field.
+    Revise,
+    /// Only the highest-priority source is used.
+    Override,
+}
+
 /// Internal struct to help with deserialisation, converted to the actual Configuration in deserialization
 #[derive(Debug, serde::Deserialize, PartialEq, serde::Serialize, Clone)]
 struct SerdeConfiguration {
@@ -57,8 +75,89 @@ impl Configuration {
         // Otherwise, gather a list of all the files we want to look for
         let sources: Vec<Source> = Source::fetch(file);
 
-        // And ask nickel to parse and merge them
-        Self::parse_and_merge(&sources)
+        // And collate them as the highest-priority file asks, merging them by default. Their merge
+        // is kept, unless it asks for another mode or fails, as files that conflict make it do.
+        let merged = Self::evaluate(&sources);
+        let (values, collation) = match &merged {
+            // The files are then only evaluated on their own as the mode needs it
+            Ok(value) => (vec![None; sources.len()], Self::collation_of(value)?),
+            Err(_) => {
+                let values = Self::evaluate_files(&sources)?;
+                let collation = Self::declared_collation(&values)?;
+                (values, collation)
+            }
+        };
+
+        match collation.unwrap_or_default() {
+            CollationMode::Merge => Self::from_value(merged?),
+            collation => Self::from_value(Self::collate(&sources, values, collation)?),
+        }
+    }
+
+    /// Like `fetch`, but with an ordered list of explicit configuration files, layered over the
+    /// usual sources. A single file is merged with them, exactly as by `fetch`. Given several
+    /// files, each takes precedence over all the sources before it: its values replace theirs,
+    /// lists included, rather than being merged with them, so Nickel priorities do not carry
+    /// across files. If one of the files or sources sets a `collation`, all of them are collated
+    /// in that mode instead.
+    ///
+    /// # Errors
+    ///
+    /// If one of the configuration files does not exist, or is a directory without a
+    /// `languages.ncl` file, this function will return a `TopiaryConfigError` with the path that
+    /// was not found.
+    /// If the configuration files exist, but cannot be parsed, this function will return a
+    /// `TopiaryConfigError` with the error that occurred.
+    pub fn fetch_layered(files: &[PathBuf]) -> TopiaryConfigResult<Self> {
+        let layers = files
+            .iter()
+            .map(|file| {
+                Source::find(&Some(file.clone()))
+                    .ok_or_else(|| TopiaryConfigError::FileNotFound(file.clone()))
+            })
+            .collect::<TopiaryConfigResult<Vec<PathBuf>>>()?;
+
+        if layers.len() < 2 {
+            return Self::fetch(&layers.into_iter().next());
+        }
+
+        let sources: Vec<Source> = Source::fetch(&None);
+        let usual = sources.len();
+        let sources: Vec<Source> = sources
+            .into_iter()
+            .chain(layers.into_iter().map(Source::File))
+            .collect();
+
+        // The layers are evaluated on their own, and the usual sources merged, as layering needs.
+        // The usual files are only evaluated on their own if their merge fails, to find out whether
+        // one of them sets a collation.
+        let layers = Self::evaluate_files(&sources[usual..])?;
+        let merged = Self::evaluate(&sources[..usual]);
+        let mut values = match &merged {
+            Ok(_) => vec![None; usual],
+            Err(_) => Self::evaluate_files(&sources[..usual])?,
+        };
+        values.extend(layers);
+
+        // A declared collation governs all the sources, layers included
+        let collation = match (Self::declared_collation(&values)?, &merged) {
+            (Some(collation), _) => Some(collation),
+            (None, Ok(value)) => Self::collation_of(value)?,
+            (None, Err(_)) => None,
+        };
+        if let Some(collation) = collation {
+            return Self::from_value(Self::collate(&sources, values, collation)?);
+        }
+
+        // Nickel only merges values of the same priority if they are equal, so the values of each
+        // layer, evaluated on its own, replace those of the configuration built so far
+        let layers = values.split_off(usual);
+        let mut merged = merged?;
+        for layer in layers.into_iter().flatten() {
+            override_value(&mut merged, layer);
+        }
+
+        Self::from_value(merged)
     }
 
     /// Gets a language configuration from the entire configuration.
@@ -99,14 +198,112 @@ impl Configuration {
         Err(TopiaryConfigError::NoExtension(pb.clone()))
     }
 
-    fn parse_and_merge(sources: &[Source]) -> TopiaryConfigResult<Self> {
+    /// Reports, for each language of the configuration in alphabetical order, the query file it
+    /// resolves to on the current system, and the location it was found in, following the search
+    /// of `Language::find_query_file`. The search error is kept for languages whose query file
+    /// cannot be found.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn query_files(
+        &self,
+    ) -> Vec<(
+        &Language,
+        TopiaryConfigResult<(PathBuf, language::QueryLocation)>,
+    )> {
+        let mut languages: Vec<&Language> = self.languages.iter().collect();
+        languages.sort_by(|a, b| a.name.cmp(&b.name));
+
+        languages
+            .into_iter()
+            .map(|language| (language, language.find_query_file_with_location()))
+            .collect()
+    }
+
+    /// Evaluates the merge of the given sources to a plain value.
+    fn evaluate(sources: &[Source]) -> TopiaryConfigResult<serde_json::Value> {
         let inputs = sources.iter().map(|s| s.clone().into());
 
         let mut program = Program::<CacheImpl>::new_from_inputs(inputs, std::io::stderr())?;
 
         let term = program.eval_full_for_export()?;
 
-        let serde_config = SerdeConfiguration::deserialize(term)?;
+        Ok(serde_json::to_value(term)?)
+    }
+
+    /// Evaluates each configuration file on its own, to find the collation mode they set when it
+    /// cannot be read from their merge. The built-in configuration is left out, as `None`, and only
+    /// evaluated on its own by `collate` if needed.
+    fn evaluate_files(sources: &[Source]) -> TopiaryConfigResult<Vec<Option<serde_json::Value>>> {
+        sources
+            .iter()
+            .map(|source| match source {
+                Source::Builtin => Ok(None),
+                Source::File(_) => Self::evaluate(slice::from_ref(source)).map(Some),
+            })
+            .collect()
+    }
+
+    /// Finds the collation mode set by the highest-priority configuration file, given the values
+    /// of `evaluate_files`.
+    fn declared_collation(
+        values: &[Option<serde_json::Value>],
+    ) -> TopiaryConfigResult<Option<CollationMode>> {
+        values
+            .iter()
+            .rev()
+            .flatten()
+            .find(|value| value.get("collation").is_some())
+            .map_or(Ok(None), Self::collation_of)
+    }
+
+    /// Reads the collation mode set by an evaluated configuration, if any.
+    fn collation_of(value: &serde_json::Value) -> TopiaryConfigResult<Option<CollationMode>> {
+        Ok(value
+            .get("collation")
+            .map(|collation| serde_json::from_value(collation.clone()))
+            .transpose()?)
+    }
+
+    /// Collates the given sources, in increasing order of priority, in the given mode, reusing
+    /// their values from `evaluate_files`; those that are `None` are evaluated as needed. Merging
+    /// them is left to Nickel, which needs all of them at once to honour their priorities.
+    fn collate(
+        sources: &[Source],
+        values: Vec<Option<serde_json::Value>>,
+        collation: CollationMode,
+    ) -> TopiaryConfigResult<serde_json::Value> {
+        let mut values = sources
+            .iter()
+            .zip(values)
+            .map(|(source, value)| match value {
+                Some(value) => Ok(value),
+                None => Self::evaluate(slice::from_ref(source)),
+            });
+
+        match collation {
+            CollationMode::Merge => Self::evaluate(sources),
+            CollationMode::Revise => {
+                values.try_fold(serde_json::Value::Null, |mut revised, value| {
+                    override_value(&mut revised, value?);
+                    Ok(revised)
+                })
+            }
+            CollationMode::Override => values
+                .next_back()
+                .unwrap_or_else(|| Self::evaluate(sources)),
+        }
+    }
+
+    /// Builds the configuration from its evaluated value, once the languages inheriting from
+    /// others are resolved.
+    fn from_value(mut value: serde_json::Value) -> TopiaryConfigResult<Self> {
+        if let Some(languages) = value
+            .get_mut("languages")
+            .and_then(serde_json::Value::as_object_mut)
+        {
+            resolve_inheritance(languages)?;
+        }
+
+        let serde_config: SerdeConfiguration = serde_json::from_value(value)?;
 
         Ok(serde_config.into())
     }
@@ -169,7 +366,216 @@ impl From<SerdeConfiguration> for Configuration {
     }
 }
 
+/// Completes the configuration of each language that inherits from another with that of its base
+/// language, itself resolved first: the values the language sets override those it inherits.
+fn resolve_inheritance(
+    languages: &mut serde_json::Map<String, serde_json::Value>,
+) -> TopiaryConfigResult<()> {
+    let mut resolved = HashMap::new();
+    for name in languages.keys() {
+        resolve_language(name, languages, &mut resolved, &mut Vec::new())?;
+    }
+
+    languages.extend(resolved);
+
+    Ok(())
+}
+
+fn resolve_language(
+    name: &str,
+    languages: &serde_json::Map<String, serde_json::Value>,
+    resolved: &mut HashMap<String, serde_json::Value>,
+    descendants: &mut Vec<String>,
+) -> TopiaryConfigResult<serde_json::Value> {
+    if let Some(config) = resolved.get(name) {
+        return Ok(config.clone());
+    }
+
+    let config = languages[name].clone();
+    let config = match config.get("inherits").and_then(serde_json::Value::as_str) {
+        None => config,
+        Some(base) => {
+            if !languages.contains_key(base) || descendants.iter().any(|d| d == name) {
+                return Err(TopiaryConfigError::InvalidInheritance(
+                    name.to_string(),
+                    base.to_string(),
+                ));
+            }
+
+            descendants.push(name.to_string());
+            let mut inherited = resolve_language(base, languages, resolved, descendants)?;
+            descendants.pop();
+
+            override_value(&mut inherited, config);
+            inherited
+        }
+    };
+
+    resolved.insert(name.to_string(), config.clone());
+    Ok(config)
+}
+
+/// Overrides `base` with `layer`: records are merged field by field, any other value of `layer`
+/// replaces that of `base`.
+fn override_value(base: &mut serde_json::Value, layer: serde_json::Value) {
+    match (base, layer) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(layer)) => {
+            for (key, value) in layer {
+                match base.get_mut(&key) {
+                    Some(base_value) => override_value(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, layer) => *base = layer,
+    }
+}
+
 pub(crate) fn project_dirs() -> directories::ProjectDirs {
     directories::ProjectDirs::from("", "", "topiary")
         .expect("Could not access the OS's Home directory")
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use tempfile::TempDir;
+
+    use crate::{error::TopiaryConfigError, Configuration};
+
+    /// Writes each of the given Nickel configurations to a file of a fresh directory, returning
+    /// the directory, to be kept alive, along with the paths of the files in order.
+    fn config_files(configs: &[&str]) -> (TempDir, Vec<PathBuf>) {
+        let dir = TempDir::new().unwrap();
+        let paths = configs
+            .iter()
+            .enumerate()
+            .map(|(i, config)| {
+                let path = dir.path().join(format!("config{i}.ncl"));
+                fs::write(&path, config).unwrap();
+                path
+            })
+            .collect();
+
+        (dir, paths)
+    }
+
+    #[test]
+    fn fetch_overrides_the_builtin_indent() {
+        let (_dir, files) = config_files(&[r#"{ languages = { rust = { indent = "\t" } } }"#]);
+        let config = Configuration::fetch(&files.first().cloned()).unwrap();
+
+        // The user's indent takes precedence over the built-in four spaces
+        let rust = &config.get_language("rust").unwrap().config;
+        assert_eq!(rust.extensions, ["rs"].map(String::from).into());
+        assert_eq!(rust.indent.as_deref(), Some("\t"));
+    }
+
+    #[test]
+    fn fetch_resolves_inheritance() {
+        let (_dir, files) = config_files(&[r#"{ languages = {
+            ocaml = { indent = "\t" },
+            ocaml_interface = { inherits = "ocaml", extensions = ["mli"] },
+        } }"#]);
+        let config = Configuration::fetch(&files.first().cloned()).unwrap();
+
+        // The indent of the base language is inherited, the extensions are overridden
+        let ocaml_interface = &config.get_language("ocaml_interface").unwrap().config;
+        assert_eq!(ocaml_interface.extensions, ["mli"].map(String::from).into());
+        assert_eq!(ocaml_interface.indent.as_deref(), Some("\t"));
+    }
+
+    #[test]
+    fn fetch_collates_as_the_file_declares() {
+        // The merge of the file with the built-in configuration declares the mode
+        let (_dir, files) = config_files(&[r#"{
+            collation = "override",
+            languages = { bar = { extensions = ["bar"], grammar = { git = "bar", rev = "1" } } },
+        }"#]);
+        let config = Configuration::fetch(&files.first().cloned()).unwrap();
+        let names: Vec<&str> = config
+            .languages
+            .iter()
+            .map(|language| language.name.as_str())
+            .collect();
+        assert_eq!(names, ["bar"]);
+
+        // The file conflicts with the built-in extensions, so the merge fails, and the file is
+        // evaluated on its own to find the mode
+        let (_dir, files) = config_files(&[r#"{
+            collation = "revise",
+            languages = { bash = { extensions = ["zsh"] } },
+        }"#]);
+        let config = Configuration::fetch(&files.first().cloned()).unwrap();
+        let bash = &config.get_language("bash").unwrap().config;
+        assert_eq!(bash.extensions, ["zsh"].map(String::from).into());
+        assert!(!bash.grammar.rev.is_empty());
+    }
+
+    #[test]
+    fn fetch_layered_merges_a_single_file() {
+        let (_dir, files) = config_files(&[
+            r#"{ languages = { bash = { extensions | default = ["zsh"], indent = "\t" } } }"#,
+        ]);
+        let config = Configuration::fetch_layered(&files).unwrap();
+
+        // Nickel honours the priorities: the built-in extensions win over the default ones
+        let bash = &config.get_language("bash").unwrap().config;
+        assert_eq!(bash.extensions, ["sh", "bash"].map(String::from).into());
+        assert_eq!(bash.indent.as_deref(), Some("\t"));
+    }
+
+    #[test]
+    fn fetch_layered_replaces_the_values_of_earlier_files() {
+        let (_dir, files) = config_files(&[
+            r#"{ languages = { json = { indent = "a", tab_width = 2 } } }"#,
+            r#"{ languages = { json = { indent = "b", final_newline = "x" } } }"#,
+            r#"{ languages = { json = { indent = "c" } } }"#,
+        ]);
+        let config = Configuration::fetch_layered(&files).unwrap();
+
+        // The last file's indent wins, while the other files' fields are kept
+        let json = &config.get_language("json").unwrap().config;
+        assert_eq!(json.indent.as_deref(), Some("c"));
+        assert_eq!(json.tab_width, Some(2));
+        assert_eq!(json.final_newline.as_deref(), Some("x"));
+    }
+
+    #[test]
+    fn fetch_layered_collates_as_the_last_file_declares() {
+        let (_dir, files) = config_files(&[
+            r#"{ languages = { foo = { extensions = ["foo"], grammar = { git = "foo", rev = "1" } } } }"#,
+            r#"{
+                collation = "override",
+                languages = { bar = { extensions = ["bar"], grammar = { git = "bar", rev = "1" } } },
+            }"#,
+        ]);
+        let config = Configuration::fetch_layered(&files).unwrap();
+
+        // Only the languages of the highest-priority file are kept, not even the built-in ones
+        let names: Vec<&str> = config
+            .languages
+            .iter()
+            .map(|language| language.name.as_str())
+            .collect();
+        assert_eq!(names, ["bar"]);
+    }
+
+    #[test]
+    fn fetch_layered_rejects_missing_files() {
+        let (dir, mut files) = config_files(&[r#"{ languages = {} }"#]);
+
+        // A directory is only a configuration file if it holds a languages.ncl file
+        for missing in [dir.path().to_path_buf(), dir.path().join("missing.ncl")] {
+            files.push(missing.clone());
+            assert!(matches!(
+                Configuration::fetch_layered(&files),
+                Err(TopiaryConfigError::FileNotFound(path)) if path == missing
+            ));
+            files.pop();
+        }
+    }
+}
@@ -52,7 +52,7 @@ impl Source {
     /// within that directory. If the file exists, then it returns `Some(path.join("languages.ncl"))`.
     /// If the file does not exist, then it logs a warning and returns `None`. If the path is a file,
     /// then it returns `Some(path)`.
-    fn find(path: &Option<PathBuf>) -> Option<PathBuf> {
+    pub(crate) fn find(path: &Option<PathBuf>) -> Option<PathBuf> {
         match path {
             None => None,
             Some(path) => {
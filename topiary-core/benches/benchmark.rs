@@ -2,33 +2,41 @@ use criterion::async_executor::FuturesExecutor;
 use criterion::{criterion_group, criterion_main, Criterion};
 use std::fs;
 use std::io;
-use topiary_core::{formatter, Language, Operation, TopiaryQuery};
+use topiary_core::{formatter, FormatOptions, Language, Operation, TopiaryQuery};
 
-async fn format() {
+fn operation() -> Operation {
+    let mut options = FormatOptions::default();
+    options.skip_idempotence = true;
+    Operation::Format(options)
+}
+
+fn ocaml() -> (String, Language) {
     let input = fs::read_to_string("../topiary-cli/tests/samples/input/ocaml.ml").unwrap();
     let query_content = fs::read_to_string("../topiary-queries/queries/ocaml.scm").unwrap();
     let ocaml = tree_sitter_ocaml::language_ocaml();
 
-    let mut input = input.as_bytes();
-    let mut output = io::BufWriter::new(Vec::new());
-
     let language: Language = Language {
         name: "ocaml".to_owned(),
         query: TopiaryQuery::new(&ocaml.clone().into(), &query_content).unwrap(),
         grammar: ocaml.into(),
         indent: None,
+        tab_width: 8,
+        expand_tabs: false,
+        final_newline: None,
+        normalise_indentation: false,
+        file_trailer: None,
+        post_process: None,
+        comment_kinds: None,
     };
 
-    formatter(
-        &mut input,
-        &mut output,
-        &language,
-        Operation::Format {
-            skip_idempotence: true,
-            tolerate_parsing_errors: false,
-        },
-    )
-    .unwrap();
+    (input, language)
+}
+
+async fn format() {
+    let (input, language) = ocaml();
+    let mut output = io::BufWriter::new(Vec::new());
+
+    formatter(&mut input.as_bytes(), &mut output, &language, operation()).unwrap();
 }
 
 fn criterion_benchmark(c: &mut Criterion) {
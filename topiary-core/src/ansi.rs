@@ -0,0 +1,70 @@
+//! ANSI syntax highlighting of formatted code, for previews in a terminal.
+//! The output contains escape sequences, so it is not meant to be written to
+//! files.
+use std::{collections::HashMap, fmt::Write};
+
+use topiary_tree_sitter_facade::Node;
+
+use crate::{tree_sitter, FormatterResult, Language};
+
+/// Highlights formatted code by wrapping the text of its syntax nodes in ANSI
+/// colour escape sequences, chosen by their kind.
+#[derive(Clone, Debug, Default)]
+pub struct AnsiRenderer {
+    /// The SGR parameters of the colour of each node kind, e.g. `"1;34"` for
+    /// bold blue.
+    colours: HashMap<String, String>,
+}
+
+impl AnsiRenderer {
+    /// Creates a renderer that colours the nodes of each of the given kinds,
+    /// named or anonymous (e.g. `"string"` or `"let"`), with the given SGR
+    /// parameters (e.g. `"32"` for green).
+    pub fn new(colours: HashMap<String, String>) -> Self {
+        Self { colours }
+    }
+
+    /// Parses the given code, typically output by the formatter, and returns
+    /// it with the text of each node whose kind has a colour wrapped in that
+    /// colour, including its descendants. All other text is left plain.
+    /// Parsing errors are tolerated, as this is only a preview.
+    ///
+    /// # Errors
+    ///
+    /// If the code cannot be parsed at all, a `FormatterError` is returned.
+    pub fn render(&self, code: &str, language: &Language) -> FormatterResult<String> {
+        let (tree, _) = tree_sitter::parse(code, &language.grammar, true)?;
+        let mut output = String::with_capacity(code.len());
+        let mut offset = 0;
+
+        self.highlight(&tree.root_node(), code, &mut offset, &mut output);
+        output.push_str(&code[offset..]);
+
+        Ok(output)
+    }
+
+    /// Appends the text up to the end of the node to the output, highlighted,
+    /// from the given offset, which is moved to the end of the node.
+    fn highlight(&self, node: &Node, code: &str, offset: &mut usize, output: &mut String) {
+        let start = node.start_byte() as usize;
+        let end = node.end_byte() as usize;
+
+        match self.colours.get(node.kind().as_ref()) {
+            Some(colour) if start < end => {
+                output.push_str(&code[*offset..start]);
+                // Writing to a String cannot fail
+                let _ = write!(output, "\x1b[{colour}m{}\x1b[0m", &code[start..end]);
+                *offset = end;
+            }
+            _ if node.child_count() == 0 => {
+                output.push_str(&code[*offset..end.max(*offset)]);
+                *offset = end.max(*offset);
+            }
+            _ => {
+                for child in node.children(&mut node.walk()) {
+                    self.highlight(&child, code, offset, output);
+                }
+            }
+        }
+    }
+}
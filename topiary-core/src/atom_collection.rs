@@ -1,14 +1,18 @@
 use std::{
     borrow::Cow,
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     mem,
     ops::Deref,
+    str,
 };
 
 use topiary_tree_sitter_facade::Node;
 
 use crate::{
-    tree_sitter::NodeExt, Atom, FormatterError, FormatterResult, ScopeCondition, ScopeInformation,
+    timings::{Stopwatch, Timings},
+    tree_sitter::{is_comment, CaptureSettings, NodeExt, Position},
+    Atom, FormatterError, FormatterResult, IndentKind, ScopeCondition, ScopeInformation,
+    StringWrap,
 };
 
 /// A struct that holds sets of node IDs that have line breaks before or after them.
@@ -23,6 +27,117 @@ struct NodesWithLinebreaks {
     after: HashSet<usize>,
 }
 
+/// The ids of the nodes that the captures of a query single out before the
+/// leaves of a syntax tree are collected.
+#[derive(Debug, Default)]
+pub(crate) struct MarkedNodes {
+    /// The nodes that are leaves, not recursed into.
+    pub leaves: HashSet<usize>,
+    /// Multi-line strings, which do not make their ancestors multi-line.
+    pub strings: HashSet<usize>,
+    /// The nodes within which softlines are never expanded into hardlines.
+    pub inline: HashSet<usize>,
+    /// The nodes within which softlines follow whether the node itself is
+    /// multi-line.
+    pub input_breaks: HashSet<usize>,
+    /// The nodes that are output exactly as they are in the input.
+    pub protected: HashSet<usize>,
+}
+
+/// A comment containing this marker starts a region that is left untouched by
+/// the formatter.
+const FORMAT_OFF_MARKER: &str = "topiary: off";
+
+/// A comment containing this marker ends a region started by `FORMAT_OFF_MARKER`.
+const FORMAT_ON_MARKER: &str = "topiary: on";
+
+/// The side of a leaf on which to look for a neighbouring leaf.
+#[derive(Clone, Copy, Debug)]
+enum Boundary {
+    Before,
+    After,
+}
+
+/// A region of the input, delimited by `FORMAT_OFF_MARKER` and
+/// `FORMAT_ON_MARKER` comments (both included), that is emitted verbatim.
+#[derive(Debug)]
+struct FormatOffRegion {
+    start_byte: u32,
+    end_byte: u32,
+    /// The id of the comment node closing the region, if any. If a region
+    /// opened by a comment is not closed, it extends to the end of the input.
+    /// Protected regions have no closer.
+    closer: Option<usize>,
+    /// The id of the leaf that holds the verbatim content of the region, once
+    /// it has been collected.
+    leaf_id: Option<usize>,
+    /// Whether the region is a node marked with `@protect`, whose content is
+    /// output verbatim, trailing whitespace included.
+    protected: bool,
+}
+
+/// An entry among the children of a node marked with `@sort_children`: a named
+/// child, together with the comments attached to it.
+#[derive(Debug)]
+struct SortEntry {
+    /// The id of the first leaf of the entry, that of its first leading
+    /// comment if any.
+    first_leaf: usize,
+    /// The id of the last leaf of the named child.
+    last_leaf: usize,
+    /// The id of the first leaf of the named child, whose content is the
+    /// sorting key.
+    key_leaf: usize,
+    /// The first and last leaf ids of the trailing comments of the child,
+    /// which may come after a separator.
+    trailing: Vec<(usize, usize)>,
+}
+
+/// The comments attached to a code node, which move along with it when it is
+/// moved, e.g. by `@sort_children`. Each comment is given by the ids of its
+/// first and last leaves.
+#[derive(Debug, Default)]
+struct AttachedComments {
+    /// The comments on their own lines directly before the node.
+    leading: Vec<(usize, usize)>,
+    /// The comments after the node, starting on the line on which it ends.
+    trailing: Vec<(usize, usize)>,
+}
+
+/// A pass of the post-processing of the atoms, run in the order a language
+/// gives, before whitespace is merged. It is named in snake case in the
+/// configuration.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PostProcessPass {
+    /// Resolves the scoped softlines and conditionals by whether their scope
+    /// is multi-line.
+    Scopes,
+    /// Removes the atoms of deleted nodes.
+    Deletes,
+    /// Removes the leaves marked with `@dedup_adjacent` repeating the previous.
+    Dedup,
+    /// Turns pairs of hardlines into blank lines.
+    HardlinePairs,
+    /// Removes the line breaks around `Glue` atoms.
+    Glue,
+    /// Removes the blank lines at the end of the nodes marked with
+    /// `@remove_trailing_blank_lines`.
+    TrailingBlankLines,
+}
+
+impl PostProcessPass {
+    /// The order in which the passes run unless a language gives another.
+    pub const DEFAULT_ORDER: &'static [PostProcessPass] = &[
+        PostProcessPass::Scopes,
+        PostProcessPass::Deletes,
+        PostProcessPass::Dedup,
+        PostProcessPass::HardlinePairs,
+        PostProcessPass::Glue,
+        PostProcessPass::TrailingBlankLines,
+    ];
+}
+
 /// Contains Topiary's internal representation parsed document.
 #[derive(Debug)]
 pub struct AtomCollection {
@@ -52,23 +167,82 @@ pub struct AtomCollection {
     /// During initial Atom collection, any node that has a blank lines above
     /// the node is added to this HashSet.
     blank_lines_before: HashSet<usize>,
+    /// During initial Atom collection, any node that has a blank lines below
+    /// the node is added to this HashSet.
+    blank_lines_after: HashSet<usize>,
     /// During initial Atom collection, any node that has a linebreak directly
     /// before it is added to this HashSet.
     line_break_before: HashSet<usize>,
     /// During initial Atom collection, any node that has a linebreak directly
     /// after it is added to this HashSet.
     line_break_after: HashSet<usize>,
+    /// The ids of the leaves that are comments.
+    comment_leaves: HashSet<usize>,
+    /// The comments attached to each code node, keyed by the node's id. A
+    /// comment is attached to the nearest named sibling that is not a
+    /// comment: the one it follows on the same line, or else the next one.
+    attached_comments: HashMap<usize, AttachedComments>,
+    /// The ids of the leaves marked with `@dedup_adjacent`.
+    dedup_leaves: HashSet<usize>,
+    /// The ids of the last leaves of the nodes marked with
+    /// `@remove_trailing_blank_lines`.
+    trailing_blank_line_leaves: HashSet<usize>,
+    /// The warnings raised while formatting.
+    warnings: Vec<String>,
+    /// Regions of the input in which formatting is disabled, by marker
+    /// comments or by `@protect`.
+    format_off_regions: Vec<FormatOffRegion>,
+    /// Maps the ids of the comments closing a format-off region to the id of
+    /// the leaf holding the region, so that appends to the closing comment are
+    /// applied after the region.
+    format_off_closers: HashMap<usize, usize>,
+    /// The ids of the atoms added by `@append_scope_open_literal` (`true`)
+    /// and `@append_scope_close_literal` (`false`).
+    scope_literals: HashMap<usize, bool>,
+    /// The ids of the literals added by `@append_scope_line_literal`, in which
+    /// `{line}` stands for the line on which their scope starts.
+    scope_line_literals: HashSet<usize>,
+    /// The entries of the nodes marked with `@sort_children`, in their
+    /// original order.
+    sorted_children: Vec<Vec<SortEntry>>,
+    /// The ids of the leaves marked with `@embedded`, along with the name of
+    /// the language of their content and the column, counted in characters,
+    /// at which they start.
+    embedded: Vec<(usize, String, usize)>,
+    /// The kinds of the nodes that are comments, if the language sets them.
+    comment_kinds: Option<Vec<String>>,
+    /// The index in `atoms` of each leaf, by id, kept up to date as atoms are
+    /// added and moved.
+    leaf_indices: HashMap<usize, usize>,
+    /// The ids of the parents of the nodes to which softlines were prepended
+    /// or appended, in order, keyed by the id of the leaf they are attached to
+    /// and whether they are appended to it.
+    queued_softlines: HashMap<(usize, bool), VecDeque<usize>>,
+    /// The index in `atoms` of each softline that is yet to be expanded, and
+    /// the id of the parent whose layout decides its expansion.
+    softlines: HashMap<usize, usize>,
     /// Used to generate unique IDs
     counter: usize,
+    /// When enabled, records which capture names contributed which atoms,
+    /// keyed by the id of the leaf the atoms were attached to. This is `None`
+    /// by default, to avoid the bookkeeping overhead during normal formatting.
+    provenance: Option<HashMap<usize, Vec<(String, Atom)>>>,
+    /// The time taken by the stages of formatting this collection went through.
+    timings: Timings,
+    /// Whether the post-processing is timed. Timings are only measured when
+    /// asked for, so that normal formatting does not read the clock.
+    record_timings: bool,
 }
 
 impl AtomCollection {
-    /// Returns a basic AtomCollection with the supplied atoms. Only used for
-    /// testing. Normally you should use `AtomCollection::collect_leafs`
-    /// instead.
-    #[cfg(test)]
+    /// Returns a basic AtomCollection with the supplied atoms, for building
+    /// atoms outside of the query pipeline. Ids allocated afterwards, e.g. by
+    /// `AtomCollection::scoped_softline`, follow the largest id in `atoms`.
+    /// To format a syntax tree, use `apply_query` instead.
     pub fn new(atoms: Vec<Atom>) -> Self {
-        Self {
+        let counter = atoms.iter().filter_map(Atom::id).max().unwrap_or(0);
+
+        let mut collection = Self {
             atoms,
             prepend: HashMap::new(),
             append: HashMap::new(),
@@ -76,67 +250,234 @@ impl AtomCollection {
             parent_leaf_nodes: HashMap::new(),
             multi_line_nodes: HashSet::new(),
             blank_lines_before: HashSet::new(),
+            blank_lines_after: HashSet::new(),
             line_break_before: HashSet::new(),
             line_break_after: HashSet::new(),
-            counter: 0,
-        }
+            comment_leaves: HashSet::new(),
+            attached_comments: HashMap::new(),
+            dedup_leaves: HashSet::new(),
+            trailing_blank_line_leaves: HashSet::new(),
+            warnings: Vec::new(),
+            format_off_regions: Vec::new(),
+            format_off_closers: HashMap::new(),
+            scope_literals: HashMap::new(),
+            scope_line_literals: HashSet::new(),
+            sorted_children: Vec::new(),
+            embedded: Vec::new(),
+            comment_kinds: None,
+            leaf_indices: HashMap::new(),
+            queued_softlines: HashMap::new(),
+            softlines: HashMap::new(),
+            counter,
+            provenance: None,
+            timings: Timings::default(),
+            record_timings: false,
+        };
+        collection.index_leaves();
+        collection
     }
 
     /// Use this to create an initial `AtomCollection`
-    pub fn collect_leafs(
+    pub(crate) fn collect_leafs(
         root: &Node,
         source: &[u8],
-        specified_leaf_nodes: HashSet<usize>,
+        nodes: &MarkedNodes,
+        settings: CaptureSettings,
     ) -> FormatterResult<Self> {
         // Flatten the tree, from the root node, in a depth-first traversal
         let dfs_nodes = dfs_flatten(root);
 
-        // Detect user specified line breaks
-        let multi_line_nodes = detect_multi_line_nodes(&dfs_nodes);
-        let blank_line_nodes = detect_line_breaks(&dfs_nodes, 2);
-        let line_break_nodes = detect_line_breaks(&dfs_nodes, 1);
+        // Detect user specified line breaks. Zero-width nodes, such as those
+        // inserted by error recovery, are ignored: they are not output, and
+        // would otherwise take the line breaks of the nodes around them.
+        let sized_nodes: Vec<Node> = dfs_nodes
+            .iter()
+            .filter(|node| node.end_byte() > node.start_byte())
+            .cloned()
+            .collect();
+        let mut multi_line_nodes = detect_multi_line_nodes(&sized_nodes, &nodes.strings);
+        let blank_line_nodes = detect_line_breaks(&sized_nodes, 2);
+        let line_break_nodes = detect_line_breaks(&sized_nodes, 1);
+
+        // All the nodes within those marked with @respect_input_breaks are
+        // considered multi-line if, and only if, the marked node is. Nested
+        // marked nodes come later in the traversal, so they take precedence.
+        for node in sized_nodes
+            .iter()
+            .filter(|node| nodes.input_breaks.contains(&node.id()))
+        {
+            let multi_line = multi_line_nodes.contains(&node.id());
+            for descendant in dfs_flatten(node) {
+                if multi_line {
+                    multi_line_nodes.insert(descendant.id());
+                } else {
+                    multi_line_nodes.remove(&descendant.id());
+                }
+            }
+        }
+
+        // Nodes marked with @inline, and all the nodes within them, are
+        // considered single-line, whatever their enclosing nodes
+        for node in sized_nodes
+            .iter()
+            .filter(|node| nodes.inline.contains(&node.id()))
+        {
+            for descendant in dfs_flatten(node) {
+                multi_line_nodes.remove(&descendant.id());
+            }
+        }
+
+        // Detect regions protected by format-off comments
+        let mut format_off_regions =
+            detect_format_off_regions(&dfs_nodes, source, settings.comment_kinds);
+        let unclosed_region = format_off_regions
+            .last()
+            .is_some_and(|region| region.closer.is_none());
+
+        // Nodes marked with @protect are regions of their own. They come after
+        // the format-off regions, so that those that contain them take
+        // precedence.
+        format_off_regions.extend(
+            sized_nodes
+                .iter()
+                .filter(|node| nodes.protected.contains(&node.id()))
+                .map(|node| FormatOffRegion {
+                    start_byte: node.start_byte(),
+                    end_byte: node.end_byte(),
+                    closer: None,
+                    leaf_id: None,
+                    protected: true,
+                }),
+        );
 
         let mut atoms = Self {
             atoms: Vec::new(),
             prepend: HashMap::new(),
             append: HashMap::new(),
-            specified_leaf_nodes,
+            specified_leaf_nodes: nodes.leaves.clone(),
             parent_leaf_nodes: HashMap::new(),
             multi_line_nodes,
             blank_lines_before: blank_line_nodes.before,
+            blank_lines_after: blank_line_nodes.after,
             line_break_before: line_break_nodes.before,
             line_break_after: line_break_nodes.after,
+            comment_leaves: HashSet::new(),
+            attached_comments: HashMap::new(),
+            dedup_leaves: HashSet::new(),
+            trailing_blank_line_leaves: HashSet::new(),
+            warnings: Vec::new(),
+            format_off_regions,
+            format_off_closers: HashMap::new(),
+            scope_literals: HashMap::new(),
+            scope_line_literals: HashSet::new(),
+            sorted_children: Vec::new(),
+            embedded: Vec::new(),
+            comment_kinds: settings.comment_kinds.map(<[String]>::to_vec),
+            leaf_indices: HashMap::new(),
+            queued_softlines: HashMap::new(),
+            softlines: HashMap::new(),
             counter: 0,
+            provenance: settings.record_provenance.then(HashMap::new),
+            timings: Timings::default(),
+            record_timings: settings.record_timings,
         };
 
+        if unclosed_region {
+            warn(
+                &mut atoms.warnings,
+                "Formatting was disabled, but never enabled again".into(),
+            );
+        }
+
         atoms.collect_leafs_inner(root, source, &Vec::new(), 0)?;
 
         Ok(atoms)
     }
 
-    // wrap inside a conditional atom if #single/multi_line_scope_only! is set
+    // wrap inside a conditional atom if #single/multi_line_scope_only! or
+    // #innermost_scope_only! is set
     fn wrap(&mut self, atom: Atom, predicates: &QueryPredicates) -> Atom {
         if let Some(scope_id) = &predicates.single_line_scope_only {
-            let id = self.next_id();
-            Atom::ScopedConditional {
-                id,
-                scope_id: scope_id.to_string(),
-                condition: ScopeCondition::SingleLineOnly,
-                atom: Box::new(atom),
-            }
+            self.scoped_conditional(scope_id, ScopeCondition::SingleLineOnly, atom)
         } else if let Some(scope_id) = &predicates.multi_line_scope_only {
-            let id = self.next_id();
-            Atom::ScopedConditional {
-                id,
-                scope_id: scope_id.to_string(),
-                condition: ScopeCondition::MultiLineOnly,
-                atom: Box::new(atom),
-            }
+            self.scoped_conditional(scope_id, ScopeCondition::MultiLineOnly, atom)
+        } else if let Some(scope_id) = &predicates.innermost_scope_only {
+            self.scoped_conditional(scope_id, ScopeCondition::InnermostOnly, atom)
         } else {
             atom
         }
     }
 
+    /// Appends an atom to the collection.
+    pub fn push(&mut self, atom: Atom) {
+        if let Atom::Leaf { id, .. } = atom {
+            self.leaf_indices.insert(id, self.atoms.len());
+        }
+        self.atoms.push(atom);
+    }
+
+    /// Whether a node is a comment, as far as the language is concerned.
+    fn is_comment(&self, node: &Node) -> bool {
+        is_comment(node, self.comment_kinds.as_deref())
+    }
+
+    /// Records the index of every leaf, after atoms have been moved.
+    fn index_leaves(&mut self) {
+        self.leaf_indices = self
+            .atoms
+            .iter()
+            .enumerate()
+            .filter_map(|(index, atom)| match atom {
+                Atom::Leaf { id, .. } => Some((*id, index)),
+                _ => None,
+            })
+            .collect();
+    }
+
+    /// The leaf with the given id, if any, mutably.
+    fn leaf_mut(&mut self, leaf_id: usize) -> Option<&mut Atom> {
+        let index = *self.leaf_indices.get(&leaf_id)?;
+        self.atoms.get_mut(index)
+    }
+
+    /// Returns a new `Atom::Leaf` with a freshly allocated id.
+    pub fn leaf(&mut self, content: impl Into<String>, original_position: Position) -> Atom {
+        Atom::Leaf {
+            content: content.into(),
+            id: self.next_id(),
+            original_position,
+            single_line_no_indent: false,
+            multi_line_indent_all: false,
+            wrap_string: None,
+            verbatim: false,
+        }
+    }
+
+    /// Returns a new `Atom::ScopedSoftline` with a freshly allocated id.
+    pub fn scoped_softline(&mut self, scope_id: &str, spaced: bool) -> Atom {
+        Atom::ScopedSoftline {
+            id: self.next_id(),
+            scope_id: scope_id.to_string(),
+            spaced,
+        }
+    }
+
+    /// Returns a new `Atom::ScopedConditional` wrapping `atom`, with a freshly
+    /// allocated id.
+    pub fn scoped_conditional(
+        &mut self,
+        scope_id: &str,
+        condition: ScopeCondition,
+        atom: Atom,
+    ) -> Atom {
+        Atom::ScopedConditional {
+            id: self.next_id(),
+            scope_id: scope_id.to_string(),
+            condition,
+            atom: Box::new(atom),
+        }
+    }
+
     /// Resolves a capture name by modifying the AtomCollection based on the
     /// instructions provided by the capture name on the Node.
     ///
@@ -166,12 +507,53 @@ impl AtomCollection {
 
         let requires_delimiter = || {
             predicates.delimiter.as_deref().ok_or_else(|| {
-                FormatterError::Query(format!("@{name} requires a #delimiter! predicate"), None)
+                FormatterError::query(format!("@{name} requires a #delimiter! predicate"))
+            })
+        };
+        let requires_kinds = || {
+            predicates.kinds.as_deref().ok_or_else(|| {
+                FormatterError::query(format!("@{name} requires a #kinds! predicate"))
+            })
+        };
+        let requires_max_width = || {
+            predicates.max_width.ok_or_else(|| {
+                FormatterError::query(format!("@{name} requires a #max_width! predicate"))
+            })
+        };
+        let requires_child_count = || {
+            predicates.child_count.ok_or_else(|| {
+                FormatterError::query(format!("@{name} requires a #child_count! predicate"))
+            })
+        };
+        let requires_indent_width = || {
+            predicates.indent_width.ok_or_else(|| {
+                FormatterError::query(format!("@{name} requires a #indent_width! predicate"))
+            })
+        };
+        let requires_indent_guide = || {
+            predicates.indent_guide.clone().ok_or_else(|| {
+                FormatterError::query(format!("@{name} requires a #indent_guide! predicate"))
+            })
+        };
+        let requires_row_size = || {
+            predicates.row_size.ok_or_else(|| {
+                FormatterError::query(format!("@{name} requires a #row_size! predicate"))
+            })
+        };
+        let requires_comment_delimiters = || match predicates.comment_delimiters.as_deref() {
+            Some([line, open, close]) => Ok([line, open, close]),
+            _ => Err(FormatterError::query(format!(
+                "@{name} requires a #comment_delimiters! predicate"
+            ))),
+        };
+        let requires_embedded_language = || {
+            predicates.embedded_language.as_deref().ok_or_else(|| {
+                FormatterError::query(format!("@{name} requires an #embedded_language! predicate"))
             })
         };
         let requires_scope_id = || {
             predicates.scope_id.as_deref().ok_or_else(|| {
-                FormatterError::Query(format!("@{name} requires a #scope_id! predicate"), None)
+                FormatterError::query(format!("@{name} requires a #scope_id! predicate"))
             })
         };
 
@@ -207,20 +589,63 @@ impl AtomCollection {
         }
         if let Some(parent_id) = self.parent_leaf_nodes.get(&node.id()) {
             if *parent_id != node.id() {
-                log::warn!(
-                    "Skipping because the match occurred below a leaf node: {}",
-                    node.display_one_based()
-                );
+                // Matches within a format-off region are expected
+                if self
+                    .format_off_regions
+                    .iter()
+                    .any(|region| region.leaf_id == Some(*parent_id))
+                {
+                    log::debug!(
+                        "Skipping because the match occurred in a format-off region: {}",
+                        node.display_one_based()
+                    );
+                } else {
+                    warn(
+                        &mut self.warnings,
+                        format!(
+                            "Skipping because the match occurred below a leaf node: {}",
+                            node.display_one_based()
+                        ),
+                    );
+                }
                 return Ok(());
             }
         }
 
+        // When recording provenance, remember how many atoms were already queued
+        // on the leaves this capture can target, so we can attribute the new ones.
+        let provenance_marks = self.provenance.is_some().then(|| {
+            let first = self.first_leaf(node).id();
+            let last = self.last_leaf(node).id();
+            (
+                first,
+                self.prepend.get(&first).map_or(0, Vec::len),
+                last,
+                self.append.get(&last).map_or(0, Vec::len),
+            )
+        });
+
         match name {
             "allow_blank_line_before" => {
                 if self.blank_lines_before.contains(&node.id()) {
                     self.prepend(Atom::Blankline, node, predicates);
                 }
             }
+            "align_trailing_comment" => {
+                self.prepend(Atom::Space, node, predicates);
+                self.prepend(Atom::Align, node, predicates);
+            }
+            "align" => self.prepend(Atom::Align, node, predicates),
+            "align_right" => self.prepend(Atom::AlignRight, node, predicates),
+            "allow_hardline_pairs" => {
+                self.prepend(Atom::HardlinePairsBegin, node, predicates);
+                self.append(Atom::HardlinePairsEnd, node, predicates);
+            }
+            "allow_blank_line_after" => {
+                if self.blank_lines_after.contains(&node.id()) {
+                    self.append(Atom::Blankline, node, predicates);
+                }
+            }
             "append_delimiter" => self.append(
                 Atom::Literal(requires_delimiter()?.to_string()),
                 node,
@@ -229,9 +654,45 @@ impl AtomCollection {
             "append_empty_softline" => {
                 self.append(Atom::Softline { spaced: false }, node, predicates);
             }
+            "append_multiline_delimiter" => {
+                let delimiter = requires_delimiter()?.to_string();
+                if self.parent_is_multi_line(node) {
+                    self.append(Atom::Literal(delimiter), node, predicates);
+                }
+            }
             "append_hardline" => self.append(Atom::Hardline, node, predicates),
-            "append_indent_start" => self.append(Atom::IndentStart, node, predicates),
+            // Break after the node only if its parent has more named children than the threshold
+            "append_hardline_if_parent_over" => {
+                let threshold = requires_child_count()?;
+                let child_count = node
+                    .parent()
+                    .map_or(0, |parent| parent.named_child_count() as usize);
+
+                if child_count > threshold {
+                    self.append(Atom::Hardline, node, predicates);
+                }
+            }
+            "append_indent_start" => {
+                self.append(Atom::IndentStart(IndentKind::Default), node, predicates)
+            }
+            "append_indent_start_width" => {
+                let width = requires_indent_width()?;
+                self.append(
+                    Atom::IndentStart(IndentKind::Width(width)),
+                    node,
+                    predicates,
+                );
+            }
+            "append_indent_start_guide" => {
+                let guide = requires_indent_guide()?;
+                self.append(
+                    Atom::IndentStart(IndentKind::Guide(guide)),
+                    node,
+                    predicates,
+                );
+            }
             "append_indent_end" => self.append(Atom::IndentEnd, node, predicates),
+            "append_indent_literal" => self.append(Atom::IndentLiteral, node, predicates),
             "append_input_softline" => {
                 let space = if self.line_break_after.contains(&node.id()) {
                     Atom::Hardline
@@ -241,8 +702,52 @@ impl AtomCollection {
 
                 self.append(space, node, predicates);
             }
+            "append_scope_open_literal" => {
+                self.append_scope_literal(
+                    node,
+                    requires_scope_id()?,
+                    requires_delimiter()?,
+                    true,
+                    predicates,
+                );
+            }
+            "append_scope_close_literal" => {
+                self.append_scope_literal(
+                    node,
+                    requires_scope_id()?,
+                    requires_delimiter()?,
+                    false,
+                    predicates,
+                );
+            }
+            "append_scope_line_literal" => {
+                let id = self.append_scope_literal(
+                    node,
+                    requires_scope_id()?,
+                    requires_delimiter()?,
+                    true,
+                    predicates,
+                );
+                self.scope_line_literals.insert(id);
+            }
             "append_space" => self.append(Atom::Space, node, predicates),
+            "append_space_before_kinds" => {
+                let kinds = requires_kinds()?;
+                if node
+                    .next_sibling()
+                    .is_some_and(|sibling| kinds.iter().any(|kind| *kind == sibling.kind()))
+                {
+                    self.append(Atom::Space, node, predicates);
+                }
+            }
+            "append_space_if_adjacent_word" => {
+                let last = self.last_leaf(node).id();
+                if self.touches_word(last, Boundary::After) {
+                    self.append(Atom::Space, node, predicates);
+                }
+            }
             "append_antispace" => self.append(Atom::Antispace, node, predicates),
+            "glue_to_next" => self.append(Atom::Glue, node, predicates),
             "append_spaced_softline" => {
                 self.append(Atom::Softline { spaced: true }, node, predicates);
             }
@@ -254,9 +759,34 @@ impl AtomCollection {
             "prepend_empty_softline" => {
                 self.prepend(Atom::Softline { spaced: false }, node, predicates);
             }
+            "prepend_multiline_delimiter" => {
+                let delimiter = requires_delimiter()?.to_string();
+                if self.parent_is_multi_line(node) {
+                    self.prepend(Atom::Literal(delimiter), node, predicates);
+                }
+            }
             "prepend_hardline" => self.prepend(Atom::Hardline, node, predicates),
-            "prepend_indent_start" => self.prepend(Atom::IndentStart, node, predicates),
+            "prepend_indent_start" => {
+                self.prepend(Atom::IndentStart(IndentKind::Default), node, predicates)
+            }
+            "prepend_indent_start_width" => {
+                let width = requires_indent_width()?;
+                self.prepend(
+                    Atom::IndentStart(IndentKind::Width(width)),
+                    node,
+                    predicates,
+                );
+            }
+            "prepend_indent_start_guide" => {
+                let guide = requires_indent_guide()?;
+                self.prepend(
+                    Atom::IndentStart(IndentKind::Guide(guide)),
+                    node,
+                    predicates,
+                );
+            }
             "prepend_indent_end" => self.prepend(Atom::IndentEnd, node, predicates),
+            "prepend_indent_literal" => self.prepend(Atom::IndentLiteral, node, predicates),
             "prepend_input_softline" => {
                 let space = if self.line_break_before.contains(&node.id()) {
                     Atom::Hardline
@@ -267,17 +797,335 @@ impl AtomCollection {
                 self.prepend(space, node, predicates);
             }
             "prepend_space" => self.prepend(Atom::Space, node, predicates),
+            "prepend_space_if_adjacent_word" => {
+                let first = self.first_leaf(node).id();
+                if self.touches_word(first, Boundary::Before) {
+                    self.prepend(Atom::Space, node, predicates);
+                }
+            }
             "prepend_antispace" => self.prepend(Atom::Antispace, node, predicates),
             "prepend_spaced_softline" => {
                 self.prepend(Atom::Softline { spaced: true }, node, predicates);
             }
             // Skip over leafs
             "leaf" => {}
+            // Nodes whose line breaks are set are known before collecting the leaves
+            "inline" | "protect" | "respect_input_breaks" => {}
+            // Nodes collapsed onto one line are decided once all captures are applied
+            "collapse_if_fits" | "collapse_single_statement" => {
+                requires_max_width()?;
+            }
             // Deletion
             "delete" => {
                 self.prepend(Atom::DeleteBegin, node, predicates);
                 self.append(Atom::DeleteEnd, node, predicates);
             }
+            // Delete the brackets around the node's single child, if it is of
+            // one of the #kinds!, or bracketed in turn
+            "strip_redundant_parens" => {
+                let kinds = requires_kinds()?;
+                let children: Vec<Node> = node.children(&mut node.walk()).collect();
+                if let [open, child, close] = children.as_slice() {
+                    let redundant = child.kind() == node.kind()
+                        || kinds.iter().any(|kind| *kind == child.kind());
+                    if !open.is_named() && !close.is_named() && child.is_named() && redundant {
+                        for bracket in [open, close] {
+                            self.prepend(Atom::DeleteBegin, bracket, predicates);
+                            self.append(Atom::DeleteEnd, bracket, predicates);
+                        }
+                    }
+                }
+            }
+            // Exactly one space on each side, as consecutive spaces are collapsed
+            "surround_space" => {
+                self.prepend(Atom::Space, node, predicates);
+                self.append(Atom::Space, node, predicates);
+            }
+            // Separate a comment from the code before it on the same line
+            "space_before_comment" => {
+                let first = self.first_leaf(node).id();
+                let follows_code = self
+                    .atoms
+                    .iter()
+                    .position(|a| matches!(a, Atom::Leaf { .. }))
+                    .zip(self.leaf_indices.get(&first))
+                    .is_some_and(|(code, first)| code < *first);
+
+                if self.is_comment(node)
+                    && follows_code
+                    && !self.line_break_before.contains(&node.id())
+                {
+                    self.prepend(Atom::Space, node, predicates);
+                }
+            }
+            // Put a single space after each comma among the node's children,
+            // and none before it
+            "comma_spacing" => {
+                let is_comma = |child: &Node| match predicates.kinds.as_deref() {
+                    Some(kinds) => kinds.iter().any(|kind| *kind == child.kind()),
+                    None => child.kind() == ",",
+                };
+                let children: Vec<Node> = node.children(&mut node.walk()).collect();
+                for comma in children.iter().filter(|child| is_comma(child)) {
+                    self.prepend(Atom::Antispace, comma, predicates);
+                    self.append(Atom::Space, comma, predicates);
+                }
+            }
+            // Surround the children of the given kinds, such as operators, with
+            // a single space on each side
+            "space_around_operator" => {
+                let kinds = requires_kinds()?;
+                let children: Vec<Node> = node.children(&mut node.walk()).collect();
+                for child in children
+                    .iter()
+                    .filter(|child| kinds.iter().any(|kind| *kind == child.kind()))
+                {
+                    self.prepend(Atom::Space, child, predicates);
+                    self.append(Atom::Space, child, predicates);
+                }
+            }
+            // Allow a line break on one side of an operator, and put a space
+            // on the other
+            "softline_around_operator" => {
+                let softline = Atom::Softline { spaced: true };
+                match predicates.operator_break.unwrap_or_default() {
+                    OperatorBreak::Before => {
+                        self.prepend(softline, node, predicates);
+                        self.append(Atom::Space, node, predicates);
+                    }
+                    OperatorBreak::After => {
+                        self.prepend(Atom::Space, node, predicates);
+                        self.append(softline, node, predicates);
+                    }
+                }
+            }
+            // Start a new line before every Nth named child, except the first
+            "hardline_every_n" => {
+                let row_size = requires_row_size()?;
+                if row_size == 0 {
+                    return Err(FormatterError::query(format!(
+                        "@{name} requires a positive #row_size! predicate"
+                    )));
+                }
+
+                let children: Vec<Node> = node.named_children(&mut node.walk()).collect();
+                for child in children.iter().skip(row_size).step_by(row_size) {
+                    self.prepend(Atom::Hardline, child, predicates);
+                }
+            }
+            // Put each comment of the node on its own line, if the node
+            // contains comments and nothing else
+            "expand_if_comment_only" => {
+                let children: Vec<Node> = node.named_children(&mut node.walk()).collect();
+                if !children.is_empty() && children.iter().all(|child| self.is_comment(child)) {
+                    for comment in &children {
+                        self.prepend(Atom::Hardline, comment, predicates);
+                        self.append(Atom::Hardline, comment, predicates);
+                    }
+                }
+            }
+            // Between the node's opening and closing delimiters, put each named
+            // child on its own indented line if the node is multi-line
+            "expand_arguments" => {
+                let children: Vec<Node> = node.children(&mut node.walk()).collect();
+                if let [open, .., close] = children.as_slice() {
+                    if open.is_named() || close.is_named() {
+                        return Err(FormatterError::query(format!(
+                            "@{name} requires a node enclosed in delimiters"
+                        )));
+                    }
+
+                    self.append(Atom::IndentStart(IndentKind::Default), open, predicates);
+                    for argument in node.named_children(&mut node.walk()) {
+                        self.prepend(Atom::Softline { spaced: false }, &argument, predicates);
+                    }
+                    self.prepend(Atom::Softline { spaced: false }, close, predicates);
+                    self.prepend(Atom::IndentEnd, close, predicates);
+                }
+            }
+            // Indent the lines of the node relative to the column at which its
+            // previous named sibling starts
+            "indent_from_previous_sibling" => {
+                if let Some(previous) = node.prev_named_sibling() {
+                    let anchor = self.first_leaf(&previous).id();
+                    self.prepend(
+                        Atom::IndentStart(IndentKind::From(anchor)),
+                        node,
+                        predicates,
+                    );
+                    self.append(Atom::IndentEnd, node, predicates);
+                }
+            }
+            // Between the node's opening and closing delimiters, keep the first
+            // named child after the opening delimiter and, if the node is
+            // multi-line, put each of the others on its own line, aligned with it
+            "hang_arguments" => {
+                let children: Vec<Node> = node.children(&mut node.walk()).collect();
+                if let [open, .., close] = children.as_slice() {
+                    if open.is_named() || close.is_named() {
+                        return Err(FormatterError::query(format!(
+                            "@{name} requires a node enclosed in delimiters"
+                        )));
+                    }
+
+                    let arguments: Vec<Node> = node.named_children(&mut node.walk()).collect();
+                    if let [first, rest @ ..] = arguments.as_slice() {
+                        let anchor = self.first_leaf(first).id();
+                        self.prepend(Atom::IndentStart(IndentKind::At(anchor)), first, predicates);
+                        for argument in rest {
+                            self.prepend(Atom::Softline { spaced: false }, argument, predicates);
+                        }
+                        self.prepend(Atom::IndentEnd, close, predicates);
+                    }
+                }
+            }
+            // Put each call of a chain after the first on its own indented
+            // line, if the chain is multi-line or wider than #max_width!
+            "method_chain" => {
+                let delimiter = requires_delimiter()?;
+                let kinds = predicates.kinds.as_deref().unwrap_or_default();
+                let is_link = |link: &Node| {
+                    link.kind() == node.kind() || kinds.iter().any(|kind| *kind == link.kind())
+                };
+
+                // The chain is laid out from its outermost link only
+                if let Some(parent) = node.parent() {
+                    let first_named = parent.named_children(&mut parent.walk()).next();
+                    if is_link(&parent) && first_named.is_some_and(|child| child.id() == node.id())
+                    {
+                        return Ok(());
+                    }
+                }
+
+                // The links of the chain are nested in their first named
+                // child, so the operators are found from the last one inwards
+                let mut operators: Vec<Node> = Vec::new();
+                let mut link = node.clone();
+                loop {
+                    let children: Vec<Node> = link.children(&mut link.walk()).collect();
+                    operators.extend(
+                        children
+                            .iter()
+                            .find(|child| !child.is_named() && child.kind() == delimiter)
+                            .cloned(),
+                    );
+                    match children.into_iter().find(|child| child.is_named()) {
+                        Some(inner) if is_link(&inner) => link = inner,
+                        _ => break,
+                    }
+                }
+                operators.reverse();
+
+                if let [_, rest @ ..] = operators.as_slice() {
+                    if !rest.is_empty()
+                        && (self.multi_line_nodes.contains(&node.id())
+                            || predicates
+                                .max_width
+                                .is_some_and(|max_width| self.token_width(node) > max_width))
+                    {
+                        self.prepend(Atom::IndentStart(IndentKind::Default), &rest[0], predicates);
+                        for operator in rest {
+                            self.prepend(Atom::Hardline, operator, predicates);
+                        }
+                        self.append(Atom::IndentEnd, node, predicates);
+                    }
+                }
+            }
+            // Remove the spaces just inside the brackets of the node, which are
+            // its first and last anonymous children
+            "tight_brackets" => {
+                let brackets: Vec<Node> = node
+                    .children(&mut node.walk())
+                    .filter(|child| !child.is_named())
+                    .collect();
+                let [open, .., close] = brackets.as_slice() else {
+                    return Err(FormatterError::query(format!(
+                        "@{name} requires a node enclosed in brackets"
+                    )));
+                };
+
+                self.append(Atom::Antispace, open, predicates);
+                self.prepend(Atom::Antispace, close, predicates);
+            }
+            // Separate the node from its previous named sibling by a blank
+            // line, if they are of different kinds
+            "blankline_on_kind_change" => {
+                if let Some(previous) = node.prev_named_sibling() {
+                    if previous.kind() != node.kind() {
+                        self.prepend(Atom::Blankline, node, predicates);
+                    }
+                }
+            }
+            // Separate the node from its previous named sibling by a blank
+            // line, if that is of one of the #kinds!. Comments before the node
+            // stay with it.
+            "blankline_after_kinds" => {
+                let kinds = requires_kinds()?;
+                let mut first = node.clone();
+                while let Some(comment) = first
+                    .prev_named_sibling()
+                    .filter(|previous| self.is_comment(previous))
+                {
+                    first = comment;
+                }
+
+                if first
+                    .prev_named_sibling()
+                    .is_some_and(|previous| kinds.iter().any(|kind| *kind == previous.kind()))
+                {
+                    self.prepend(Atom::Blankline, &first, predicates);
+                }
+            }
+            // Separate a doc comment from the code it documents by a blank
+            // line, unless it is the last thing in its scope or is continued
+            // by another comment
+            "blankline_after_doc_comment" => {
+                let documents_code = node
+                    .next_sibling()
+                    .is_some_and(|next| next.is_named() && !self.is_comment(&next));
+                if self.is_comment(node) && documents_code {
+                    self.append(Atom::Blankline, node, predicates);
+                }
+            }
+            // Mark the leaves of the node to be removed when they repeat the
+            // previous leaf
+            "dedup_adjacent" => {
+                let first = self.first_leaf(node).id();
+                let last = self.last_leaf(node).id();
+
+                if let (Some(&start), Some(&end)) =
+                    (self.leaf_indices.get(&first), self.leaf_indices.get(&last))
+                {
+                    for atom in self.atoms.iter().take(end + 1).skip(start) {
+                        if let Atom::Leaf { id, .. } = atom {
+                            self.dedup_leaves.insert(*id);
+                        }
+                    }
+                }
+            }
+            // Mark the node's last leaf, typically its closing delimiter, to
+            // have the blank lines before it removed
+            "remove_trailing_blank_lines" => {
+                let last = self.last_leaf(node).id();
+                self.trailing_blank_line_leaves.insert(last);
+            }
+            // Terminate the node with the delimiter, unless it already is
+            "ensure_semicolon" => {
+                let delimiter = requires_delimiter()?;
+                if self.terminator(node, delimiter).is_none() {
+                    let leaf = self.last_output_leaf(node);
+                    self.append(Atom::Literal(delimiter.to_string()), &leaf, predicates);
+                }
+            }
+            // Delete the delimiter terminating the node, if any
+            "remove_semicolon" => {
+                if let Some(terminator) = self.terminator(node, requires_delimiter()?) {
+                    self.prepend(Atom::DeleteBegin, &terminator, predicates);
+                    self.append(Atom::DeleteEnd, &terminator, predicates);
+                }
+            }
+            // Reorder the named children of the node by their first leaf
+            "sort_children" => self.mark_sorted_children(node),
             // Scope manipulation
             "prepend_begin_scope" => {
                 self.prepend(
@@ -309,52 +1157,20 @@ impl AtomCollection {
             }
             // Scoped softlines
             "append_empty_scoped_softline" => {
-                let id = self.next_id();
-                self.append(
-                    Atom::ScopedSoftline {
-                        id,
-                        scope_id: requires_scope_id()?.to_string(),
-                        spaced: false,
-                    },
-                    node,
-                    predicates,
-                );
+                let atom = self.scoped_softline(requires_scope_id()?, false);
+                self.append(atom, node, predicates);
             }
             "append_spaced_scoped_softline" => {
-                let id = self.next_id();
-                self.append(
-                    Atom::ScopedSoftline {
-                        id,
-                        scope_id: requires_scope_id()?.to_string(),
-                        spaced: true,
-                    },
-                    node,
-                    predicates,
-                );
+                let atom = self.scoped_softline(requires_scope_id()?, true);
+                self.append(atom, node, predicates);
             }
             "prepend_empty_scoped_softline" => {
-                let id = self.next_id();
-                self.prepend(
-                    Atom::ScopedSoftline {
-                        id,
-                        scope_id: requires_scope_id()?.to_string(),
-                        spaced: false,
-                    },
-                    node,
-                    predicates,
-                );
+                let atom = self.scoped_softline(requires_scope_id()?, false);
+                self.prepend(atom, node, predicates);
             }
             "prepend_spaced_scoped_softline" => {
-                let id = self.next_id();
-                self.prepend(
-                    Atom::ScopedSoftline {
-                        id,
-                        scope_id: requires_scope_id()?.to_string(),
-                        spaced: true,
-                    },
-                    node,
-                    predicates,
-                );
+                let atom = self.scoped_softline(requires_scope_id()?, true);
+                self.prepend(atom, node, predicates);
             }
             // Mark a leaf to be printed on an single line, with no indentation
             "single_line_no_indent" => {
@@ -388,26 +1204,517 @@ impl AtomCollection {
                     }
                 }
             }
+            // Mark a string leaf to be split at word boundaries when too long
+            "wrap_string" => {
+                let wrap = StringWrap {
+                    max_width: requires_max_width()?,
+                    continuation: requires_delimiter()?.to_string(),
+                };
+                for a in &mut self.atoms {
+                    if let Atom::Leaf {
+                        id, wrap_string, ..
+                    } = a
+                    {
+                        if *id == node.id() {
+                            *wrap_string = Some(wrap.clone());
+                        }
+                    }
+                }
+            }
+            // Mark a leaf to be output exactly as it is in the input
+            "verbatim" | "multi_line_string" => {
+                for a in &mut self.atoms {
+                    if let Atom::Leaf { id, verbatim, .. } = a {
+                        if *id == node.id() {
+                            *verbatim = true;
+                        }
+                    }
+                }
+            }
+            // Remove trailing whitespace from every line of a leaf
+            "trim_trailing_whitespace" => {
+                for a in &mut self.atoms {
+                    if let Atom::Leaf { id, content, .. } = a {
+                        if *id == node.id() {
+                            *content = trim_trailing_whitespace(content);
+                        }
+                    }
+                }
+            }
+            // Rewrite a leaf that is a synonym listed by #canonical! to the
+            // canonical form
+            "canonicalise" => {
+                let Some([canonical, synonyms @ ..]) = predicates.canonical.as_deref() else {
+                    return Err(FormatterError::query(format!(
+                        "@{name} requires a #canonical! predicate"
+                    )));
+                };
+                for a in &mut self.atoms {
+                    if let Atom::Leaf { id, content, .. } = a {
+                        if *id == node.id() && synonyms.contains(content) {
+                            *content = canonical.clone();
+                        }
+                    }
+                }
+            }
+            // Rewrite a numeric leaf in the format given by #number_format!
+            "normalise_number" => {
+                let format = predicates.number_format.ok_or_else(|| {
+                    FormatterError::query(format!("@{name} requires a #number_format! predicate"))
+                })?;
+                let separator = match format {
+                    NumberFormat::TrimZeros => "",
+                    NumberFormat::GroupDigits => requires_delimiter()?,
+                };
+                for a in &mut self.atoms {
+                    if let Atom::Leaf { id, content, .. } = a {
+                        if *id == node.id() {
+                            *content = normalise_number(content, format, separator);
+                        }
+                    }
+                }
+            }
+            // Rewrite the path separators of a string leaf to the one given by
+            // #path_separator!
+            "normalise_path_separator" => {
+                let separator = predicates.path_separator.ok_or_else(|| {
+                    FormatterError::query(format!("@{name} requires a #path_separator! predicate"))
+                })?;
+                if let Some(Atom::Leaf { content, .. }) = self.leaf_mut(node.id()) {
+                    *content = normalise_path_separators(content, separator);
+                }
+            }
+            // Mark a leaf whose content is in another language, to be formatted
+            // once the query has been applied. Its lines are aligned at the
+            // column at which it starts in the input, so they are moved along
+            // with it, as with `@multi_line_indent_all`.
+            "embedded" => {
+                let language = requires_embedded_language()?.to_string();
+                let column = self.leaf_column(node.id()).unwrap_or_default();
+                self.embedded.push((node.id(), language, column));
+                if let Some(Atom::Leaf {
+                    multi_line_indent_all,
+                    ..
+                }) = self.leaf_mut(node.id())
+                {
+                    *multi_line_indent_all = true;
+                }
+            }
+            // Rewrite a line comment as a block comment, into which the line
+            // comments on the lines that follow it are merged, unless one of
+            // them holds the closing delimiter. Doc comments are left alone.
+            "to_block_comment" => {
+                let [line, open, close] = requires_comment_delimiters()?;
+                let is_doc = self.is_comment(node)
+                    && self
+                        .leaf_content(node.id())
+                        .is_some_and(|content| is_doc_comment(content, line, ""));
+                let is_line_comment = |comment: &Node| {
+                    self.is_comment(comment)
+                        && self.leaf_content(comment.id()).is_some_and(|content| {
+                            content.starts_with(line.as_str()) && !is_doc_comment(content, line, "")
+                        })
+                };
+                let continues = |previous: &Node, next: &Node| {
+                    is_line_comment(previous)
+                        && is_line_comment(next)
+                        && next.start_position().row() == previous.end_position().row() + 1
+                };
+
+                // The comments that continue a run are merged by its first one
+                let continued = node
+                    .prev_sibling()
+                    .is_some_and(|previous| continues(&previous, node));
+                if is_doc {
+                    warn(
+                        &mut self.warnings,
+                        format!(
+                            "The comment at {} is a doc comment, so it is not rewritten as a block comment",
+                            node.display_one_based()
+                        ),
+                    );
+                } else if is_line_comment(node) && !continued {
+                    let mut run = vec![node.clone()];
+                    while let Some(next) = run[run.len() - 1].next_sibling() {
+                        if !continues(&run[run.len() - 1], &next) {
+                            break;
+                        }
+                        run.push(next);
+                    }
+
+                    let lines: Vec<String> = run
+                        .iter()
+                        .filter_map(|comment| self.leaf_content(comment.id()))
+                        .map(|content| content[line.len()..].trim_end().to_string())
+                        .collect();
+
+                    if lines.iter().any(|text| text.contains(close.as_str())) {
+                        warn(
+                            &mut self.warnings,
+                            format!(
+                                "The comments from {} contain {close}, so they are not merged into a block comment",
+                                node.display_one_based()
+                            ),
+                        );
+                    } else {
+                        // Continuation lines are aligned after the opening
+                        // delimiter, at the column of the comment in the input,
+                        // and move with it, as with `@multi_line_indent_all`
+                        let indentation = " ".repeat(
+                            self.leaf_column(node.id()).unwrap_or_default() + open.chars().count(),
+                        );
+                        let block =
+                            format!("{open}{} {close}", lines.join(&format!("\n{indentation}")));
+
+                        self.set_leaf_content(node.id(), block);
+                        if let Some(Atom::Leaf {
+                            multi_line_indent_all,
+                            ..
+                        }) = self.leaf_mut(node.id())
+                        {
+                            *multi_line_indent_all = true;
+                        }
+                        for comment in &run[1..] {
+                            self.prepend(Atom::DeleteBegin, comment, predicates);
+                            self.append(Atom::DeleteEnd, comment, predicates);
+                        }
+                    }
+                }
+            }
+            // Rewrite a block comment as line comments, one per line, ending
+            // the last line, so that no code follows it. Doc comments are left
+            // alone.
+            "to_line_comment" => {
+                let [line, open, close] = requires_comment_delimiters()?;
+                let content = self
+                    .leaf_content(node.id())
+                    .filter(|_| self.is_comment(node));
+                let is_doc = content.is_some_and(|content| is_doc_comment(content, open, close));
+                let body = content
+                    .filter(|_| !is_doc)
+                    .and_then(|content| content.strip_prefix(open.as_str()))
+                    .and_then(|content| content.strip_suffix(close.as_str()));
+
+                if is_doc {
+                    warn(
+                        &mut self.warnings,
+                        format!(
+                            "The comment at {} is a doc comment, so it is not rewritten as line comments",
+                            node.display_one_based()
+                        ),
+                    );
+                } else if let Some(body) = body {
+                    // Decorations at the start of continuation lines, as in
+                    // "/*\n * text\n */", are dropped along with empty lines
+                    // at either end
+                    let texts: Vec<&str> = body
+                        .lines()
+                        .enumerate()
+                        .map(|(index, text)| {
+                            let text = text.trim();
+                            match text.strip_prefix('*') {
+                                Some(undecorated) if index > 0 => undecorated.trim(),
+                                _ => text,
+                            }
+                        })
+                        .collect();
+                    let first = texts.iter().position(|text| !text.is_empty());
+                    let last = texts.iter().rposition(|text| !text.is_empty());
+                    let texts = match (first, last) {
+                        (Some(first), Some(last)) => &texts[first..=last],
+                        _ => &[""][..],
+                    };
+
+                    let indentation = " ".repeat(self.leaf_column(node.id()).unwrap_or_default());
+                    let comments = texts
+                        .iter()
+                        .map(|text| format!("{line} {text}").trim_end().to_string())
+                        .collect::<Vec<String>>()
+                        .join(&format!("\n{indentation}"));
+
+                    self.set_leaf_content(node.id(), comments);
+                    self.append(Atom::Hardline, node, predicates);
+                }
+            }
             // Return a query parsing error on unknown capture names
             unknown => {
-                return Err(FormatterError::Query(
-                    format!("@{unknown} is not a valid capture name"),
-                    None,
-                ))
+                return Err(FormatterError::query(format!(
+                    "@{unknown} is not a valid capture name"
+                )))
             }
         }
 
+        if let Some((first, prepended, last, appended)) = provenance_marks {
+            self.record_provenance(name, (first, prepended), (last, appended));
+        }
+
         Ok(())
     }
 
+    /// Attributes the atoms queued on the given leaves since the given counts
+    /// to the capture `name`.
+    fn record_provenance(
+        &mut self,
+        name: &str,
+        (first, prepended): (usize, usize),
+        (last, appended): (usize, usize),
+    ) {
+        let Some(provenance) = &mut self.provenance else {
+            return;
+        };
+
+        let new_prepends = self
+            .prepend
+            .get(&first)
+            .map_or(&[][..], |atoms| &atoms[prepended..]);
+        for atom in new_prepends {
+            provenance
+                .entry(first)
+                .or_default()
+                .push((name.to_owned(), atom.clone()));
+        }
+
+        let new_appends = self
+            .append
+            .get(&last)
+            .map_or(&[][..], |atoms| &atoms[appended..]);
+        for atom in new_appends {
+            provenance
+                .entry(last)
+                .or_default()
+                .push((name.to_owned(), atom.clone()));
+        }
+    }
+
+    /// Returns the warnings raised while formatting, such as matches that were
+    /// skipped or scopes that were not closed.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Records a warning about the input that was raised outside of the atom
+    /// collection, such as mixed indentation.
+    pub(crate) fn add_warning(&mut self, message: String) {
+        warn(&mut self.warnings, message);
+    }
+
+    /// Returns the capture names, and the atoms they contributed, for the leaf
+    /// with the given node id. This is only available if provenance recording
+    /// was enabled, with `apply_query_with_provenance`; otherwise `None` is
+    /// returned.
+    pub fn provenance(&self, id: usize) -> Option<&[(String, Atom)]> {
+        self.provenance.as_ref()?.get(&id).map(Vec::as_slice)
+    }
+
+    /// Whether the node spans several lines in the input, and is not kept on one line.
+    pub(crate) fn is_multi_line(&self, node: &Node) -> bool {
+        self.multi_line_nodes.contains(&node.id())
+    }
+
+    /// Keeps the given nodes on one line, as if they were marked with
+    /// `@inline`, provided that they then fit within their maximum width. The
+    /// nodes marked as holding a single statement also have their hardlines
+    /// replaced by spaces. Nodes are decided innermost first, so that
+    /// collapsing a node can let its ancestors fit in turn.
+    ///
+    /// Returns the collapsed nodes, and whether their hardlines were replaced.
+    pub(crate) fn collapse_fitting<'tree>(
+        &mut self,
+        candidates: &[(Node<'tree>, usize, bool)],
+    ) -> Vec<(Node<'tree>, bool)> {
+        let mut collapsed: Vec<(Node<'tree>, bool)> = Vec::new();
+        let mut decided: HashSet<(usize, bool)> = HashSet::new();
+
+        for (node, max_width, flatten) in candidates {
+            if !decided.insert((node.id(), *flatten)) || !(*flatten || self.is_multi_line(node)) {
+                continue;
+            }
+
+            let within: HashSet<usize> = dfs_flatten(node).iter().map(Node::id).collect();
+            if self
+                .single_line_width(node, &within, *flatten)
+                .is_some_and(|width| width <= *max_width)
+            {
+                log::debug!("Collapsing {} onto one line", node.display_one_based());
+                for id in &within {
+                    self.multi_line_nodes.remove(id);
+                }
+                if *flatten {
+                    self.flatten_hardlines(node);
+                }
+                collapsed.push((node.clone(), *flatten));
+            }
+        }
+
+        collapsed
+    }
+
+    /// Replaces the hardlines and blank lines within a node by spaces, so
+    /// that it is output on one line.
+    pub(crate) fn flatten_hardlines(&mut self, node: &Node) {
+        let (Some(&start), Some(&end)) = (
+            self.leaf_indices.get(&self.first_leaf(node).id()),
+            self.leaf_indices.get(&self.last_leaf(node).id()),
+        ) else {
+            return;
+        };
+
+        for atom in &mut self.atoms[start..end] {
+            if matches!(atom, Atom::Hardline | Atom::Blankline) {
+                *atom = Atom::Space;
+            }
+        }
+    }
+
+    /// The width of the node once output on one line, with the softlines of
+    /// the nodes whose ids are in `within` expanded as if they were
+    /// single-line, or `None` if the node would still span several lines.
+    /// With `flatten`, its hardlines count as spaces, unless the node holds a
+    /// comment followed by a line break in the input, such as a line comment,
+    /// which the line break may be needed to end.
+    ///
+    /// This is measured before post-processing: the whitespace between two
+    /// tokens counts as one space, unless it holds an antispace, and deleted
+    /// atoms are skipped.
+    fn single_line_width(
+        &self,
+        node: &Node,
+        within: &HashSet<usize>,
+        flatten: bool,
+    ) -> Option<usize> {
+        let start = *self.leaf_indices.get(&self.first_leaf(node).id())?;
+        let end = *self.leaf_indices.get(&self.last_leaf(node).id())?;
+
+        let mut width = 0;
+        let mut deleted = 0;
+        // The whitespace since the last token
+        let (mut space, mut line_break, mut antispace, mut glue) = (false, false, false, false);
+
+        for (index, atom) in self.atoms[start..=end].iter().enumerate() {
+            let atom = match atom {
+                Atom::ScopedConditional {
+                    condition: ScopeCondition::MultiLineOnly,
+                    ..
+                } => continue,
+                Atom::ScopedConditional { atom, .. } => atom.as_ref(),
+                atom => atom,
+            };
+
+            match atom {
+                Atom::DeleteBegin => deleted += 1,
+                Atom::DeleteEnd => deleted -= 1,
+                _ if deleted > 0 => {}
+                Atom::Leaf { id, .. }
+                    if flatten
+                        && start + index < end
+                        && self.comment_leaves.contains(id)
+                        && self.line_break_after.contains(id) =>
+                {
+                    return None;
+                }
+                Atom::Leaf { content, .. } | Atom::Literal(content) => {
+                    if content.contains('\n') || (line_break && !glue) {
+                        return None;
+                    }
+                    if space && !antispace {
+                        width += 1;
+                    }
+                    width += content.chars().count();
+                    (space, line_break, antispace, glue) = (false, false, false, false);
+                }
+                Atom::Softline { spaced } => {
+                    let multi_line = self.softlines.get(&(start + index)).is_some_and(|parent| {
+                        !within.contains(parent) && self.multi_line_nodes.contains(parent)
+                    });
+                    if multi_line {
+                        line_break = true;
+                    } else {
+                        space |= *spaced;
+                    }
+                }
+                Atom::Hardline | Atom::Blankline if flatten => space = true,
+                Atom::Hardline | Atom::Blankline => line_break = true,
+                Atom::Space | Atom::ScopedSoftline { spaced: true, .. } => space = true,
+                Atom::Antispace => antispace = true,
+                Atom::Glue => glue = true,
+                _ => {}
+            }
+        }
+
+        Some(width)
+    }
+
+    /// Returns the leaves marked with `@embedded`: their ids, the name of the
+    /// language of their content, and the column at which they start.
+    pub(crate) fn embedded(&self) -> &[(usize, String, usize)] {
+        &self.embedded
+    }
+
+    /// The content of the leaf with the given id, if any.
+    pub(crate) fn leaf_content(&self, leaf_id: usize) -> Option<&str> {
+        match self.atoms.get(*self.leaf_indices.get(&leaf_id)?) {
+            Some(Atom::Leaf { content, .. }) => Some(content.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Returns the column, counted in characters, at which the leaf with the
+    /// given id starts in the input.
+    fn leaf_column(&self, leaf_id: usize) -> Option<usize> {
+        match self.atoms.get(*self.leaf_indices.get(&leaf_id)?) {
+            Some(Atom::Leaf {
+                original_position, ..
+            }) => Some(original_position.column as usize - 1),
+            _ => None,
+        }
+    }
+
+    /// Replaces the content of the leaf with the given id.
+    pub(crate) fn set_leaf_content(&mut self, leaf_id: usize, new_content: String) {
+        if let Some(Atom::Leaf { content, .. }) = self.leaf_mut(leaf_id) {
+            *content = new_content;
+        }
+    }
+
+    /// The total width of the leaves of a node, not counting any whitespace
+    /// between them.
+    fn token_width(&self, node: &Node) -> usize {
+        let (Some(first), Some(last)) = (
+            self.leaf_indices.get(&self.first_leaf(node).id()),
+            self.leaf_indices.get(&self.last_leaf(node).id()),
+        ) else {
+            return 0;
+        };
+
+        self.atoms[*first..=*last]
+            .iter()
+            .map(|atom| match atom {
+                Atom::Leaf { content, .. } => content.chars().count(),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// The time taken by the stages of formatting this collection went
+    /// through so far. Rendering is not included. The durations are zero
+    /// unless the collection was built to record timings.
+    pub fn timings(&self) -> &Timings {
+        &self.timings
+    }
+
+    pub(crate) fn timings_mut(&mut self) -> &mut Timings {
+        &mut self.timings
+    }
+
     /// After query processing is done, a flattened/expanded vector of atoms can be created.
     pub fn apply_prepends_and_appends(&mut self) {
         let mut expanded: Vec<Atom> = Vec::new();
 
         for atom in &mut self.atoms {
             if let Atom::Leaf { id, .. } = atom {
-                let prepends = self.prepend.entry(*id).or_default();
-                let appends = self.append.entry(*id).or_default();
+                let leaf_id = *id;
+                let prepends = self.prepend.entry(leaf_id).or_default();
+                let appends = self.append.entry(leaf_id).or_default();
 
                 // Rather than cloning the atom from the old vector, we
                 // simply take it. This will leave a default (empty) atom
@@ -421,6 +1728,21 @@ impl AtomCollection {
                     log::debug!("Applying append of {appends:?} to {:?}.", &swapped_atom);
                 }
 
+                place_scope_literals(prepends, appends, &self.scope_literals);
+
+                for (atoms, appended) in [(&*prepends, false), (&*appends, true)] {
+                    let start = expanded.len() + if appended { prepends.len() + 1 } else { 0 };
+                    let queue = self.queued_softlines.get_mut(&(leaf_id, appended));
+                    let mut parents = queue.into_iter().flat_map(|queue| queue.drain(..));
+                    for (offset, atom) in atoms.iter().enumerate() {
+                        if holds_softline(atom) {
+                            if let Some(parent_id) = parents.next() {
+                                self.softlines.insert(start + offset, parent_id);
+                            }
+                        }
+                    }
+                }
+
                 expanded.append(prepends);
                 expanded.push(swapped_atom);
 
@@ -432,6 +1754,141 @@ impl AtomCollection {
         }
 
         self.atoms = expanded;
+        self.index_leaves();
+    }
+
+    /// Records the entries of a node marked with `@sort_children`, to be sorted
+    /// by `sort_children`. The comments attached to a named child move with
+    /// it; other comments keep their place.
+    fn mark_sorted_children(&mut self, node: &Node) {
+        let mut entries = Vec::new();
+
+        for child in node.named_children(&mut node.walk()) {
+            if self.is_comment(&child) {
+                continue;
+            }
+
+            let key_leaf = self.first_leaf(&child).id();
+            let attached = self.attached_comments.get(&child.id());
+            entries.push(SortEntry {
+                first_leaf: attached
+                    .and_then(|attached| attached.leading.first())
+                    .map_or(key_leaf, |(first, _)| *first),
+                last_leaf: self.last_leaf(&child).id(),
+                key_leaf,
+                trailing: attached.map_or_else(Vec::new, |attached| attached.trailing.clone()),
+            });
+        }
+
+        self.sorted_children.push(entries);
+    }
+
+    /// Reorders the leaves of the entries recorded by `@sort_children`, by
+    /// the content of their key leaf. This is done before prepends and appends
+    /// are applied, so that these move along with their leaves. Nodes whose
+    /// entries cannot all be found, e.g. within a format-off region, are left
+    /// unchanged.
+    pub fn sort_children(&mut self) {
+        for entries in mem::take(&mut self.sorted_children) {
+            // The position and content of the leaf with the given id
+            let leaf = |id: usize| {
+                let index = *self.leaf_indices.get(&id)?;
+                match &self.atoms[index] {
+                    Atom::Leaf { content, .. } => Some((index, content.clone())),
+                    _ => None,
+                }
+            };
+
+            let Some(ranges) = entries
+                .iter()
+                .map(|entry| {
+                    let trailing = entry
+                        .trailing
+                        .iter()
+                        .map(|(first, last)| Some((leaf(*first)?.0, leaf(*last)?.0)))
+                        .collect::<Option<Vec<_>>>()?;
+                    Some((
+                        leaf(entry.first_leaf)?.0,
+                        leaf(entry.last_leaf)?.0,
+                        leaf(entry.key_leaf)?.1,
+                        trailing,
+                    ))
+                })
+                .collect::<Option<Vec<_>>>()
+            else {
+                continue;
+            };
+
+            if ranges.len() < 2
+                || ranges.iter().any(|(first, last, _, _)| first > last)
+                || ranges.windows(2).any(|pair| pair[0].1 >= pair[1].0)
+            {
+                continue;
+            }
+
+            // Trailing comments are only moved if they lie between their entry
+            // and the next one
+            let trailing: Vec<Vec<(usize, usize)>> = ranges
+                .iter()
+                .enumerate()
+                .map(|(i, (_, last, _, trailing))| {
+                    let next = ranges.get(i + 1).map(|(first, _, _, _)| *first);
+                    trailing
+                        .iter()
+                        .copied()
+                        .filter(|(first, end)| {
+                            last < first && first <= end && next.is_none_or(|next| *end < next)
+                        })
+                        .collect()
+                })
+                .collect();
+            let moved: HashSet<usize> = trailing
+                .iter()
+                .flatten()
+                .flat_map(|(first, last)| *first..=*last)
+                .collect();
+            let between = |from: usize, to: usize| -> Vec<Atom> {
+                (from..to)
+                    .filter(|i| !moved.contains(i))
+                    .map(|i| self.atoms[i].clone())
+                    .collect()
+            };
+
+            // The atoms between consecutive entries, e.g. separators, stay in
+            // place, as do those after the last entry and before its trailing
+            // comments
+            let start = ranges[0].0;
+            let last = ranges[ranges.len() - 1].1;
+            let end = trailing[ranges.len() - 1]
+                .iter()
+                .map(|(_, end)| *end)
+                .fold(last, usize::max);
+            let mut gaps: Vec<Vec<Atom>> = ranges
+                .windows(2)
+                .map(|pair| between(pair[0].1 + 1, pair[1].0))
+                .collect();
+            gaps.push(between(last + 1, end + 1));
+
+            let mut sorted: Vec<usize> = (0..ranges.len()).collect();
+            sorted.sort_by(|a, b| ranges[*a].2.cmp(&ranges[*b].2));
+
+            let mut reordered = Vec::new();
+            for (gap, entry) in gaps.iter().zip(sorted) {
+                let (first, last, _, _) = &ranges[entry];
+                reordered.extend_from_slice(&self.atoms[*first..=*last]);
+                reordered.extend_from_slice(gap);
+                for (first, last) in &trailing[entry] {
+                    reordered.extend_from_slice(&self.atoms[*first..=*last]);
+                }
+            }
+
+            self.atoms.splice(start..=end, reordered);
+            for (index, atom) in self.atoms.iter().enumerate().take(end + 1).skip(start) {
+                if let Atom::Leaf { id, .. } = atom {
+                    self.leaf_indices.insert(*id, index);
+                }
+            }
+        }
     }
 
     /// Marks the provided node as the parent of all its child nodes by adding
@@ -477,6 +1934,8 @@ impl AtomCollection {
 
         if node.end_byte() == node.start_byte() {
             log::debug!("Skipping zero-byte node: {}", node.display_one_based());
+        } else if let Some(index) = self.format_off_region_containing(node) {
+            self.collect_format_off(node, source, index)?;
         } else if node.child_count() == 0
             || self.specified_leaf_nodes.contains(&node.id())
             // We treat error nodes as leafs when `tolerate_parsing_errors` is set to true.
@@ -485,24 +1944,150 @@ impl AtomCollection {
             // and as such the check below would be redundant.
             || node.kind() == "ERROR"
         {
-            self.atoms.push(Atom::Leaf {
+            self.push(Atom::Leaf {
                 content: String::from(node.utf8_text(source)?),
                 id,
-                original_position: node.start_position().into(),
+                original_position: leaf_position(node, source),
+                single_line_no_indent: false,
+                multi_line_indent_all: false,
+                wrap_string: None,
+                verbatim: false,
+            });
+            if self.is_comment(node) {
+                self.comment_leaves.insert(id);
+            }
+            // Mark all sub-nodes as having this node as a "leaf parent"
+            self.mark_leaf_parent(node, node.id());
+        } else {
+            for child in node.children(&mut node.walk()) {
+                self.collect_leafs_inner(&child, source, &parent_ids, level + 1)?;
+            }
+            self.attach_comments(node);
+        }
+
+        Ok(())
+    }
+
+    /// Attaches each comment among the named children of a node to the nearest
+    /// code sibling: the one after which it starts on the same line, as a
+    /// trailing comment, or else the next one, as a leading comment. Comments
+    /// after the last code sibling, on lines of their own, are not attached.
+    fn attach_comments(&mut self, node: &Node) {
+        let children: Vec<Node> = node.named_children(&mut node.walk()).collect();
+        let mut previous_code: Option<&Node> = None;
+        let mut leading = Vec::new();
+
+        for child in &children {
+            let leaves = (self.first_leaf(child).id(), self.last_leaf(child).id());
+            if !self.is_comment(child) {
+                let attached = self.attached_comments.entry(child.id()).or_default();
+                attached.leading.append(&mut leading);
+                previous_code = Some(child);
+                continue;
+            }
+
+            match previous_code {
+                Some(code) if code.end_position().row() == child.start_position().row() => self
+                    .attached_comments
+                    .entry(code.id())
+                    .or_default()
+                    .trailing
+                    .push(leaves),
+                _ => leading.push(leaves),
+            }
+        }
+    }
+
+    /// Returns the index of the format-off region that fully contains the given
+    /// node, if any.
+    fn format_off_region_containing(&self, node: &Node) -> Option<usize> {
+        self.format_off_regions.iter().position(|region| {
+            region.start_byte <= node.start_byte() && node.end_byte() <= region.end_byte
+        })
+    }
+
+    /// Collects a node that lies within a format-off region. The first such
+    /// node is turned into a leaf holding the original content of the whole
+    /// region; all other nodes in the region are marked as belonging to it.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the content of the region is not valid UTF-8.
+    fn collect_format_off(
+        &mut self,
+        node: &Node,
+        source: &[u8],
+        index: usize,
+    ) -> FormatterResult<()> {
+        let region = &mut self.format_off_regions[index];
+
+        if let Some(leaf_id) = region.leaf_id {
+            let is_closer = region.closer == Some(node.id());
+            self.mark_leaf_parent(node, leaf_id);
+
+            // Captures on the closing comment still apply, so that whatever
+            // follows the region can be formatted as it would after a comment.
+            if is_closer {
+                self.parent_leaf_nodes.insert(node.id(), node.id());
+                self.format_off_closers.insert(node.id(), leaf_id);
+            }
+        } else {
+            region.leaf_id = Some(node.id());
+            let content =
+                str::from_utf8(&source[region.start_byte as usize..region.end_byte as usize])?;
+
+            log::debug!(
+                "Formatting disabled from {}: {content:?}",
+                node.display_one_based()
+            );
+
+            let verbatim = region.protected;
+            self.push(Atom::Leaf {
+                content: String::from(content),
+                id: node.id(),
+                original_position: leaf_position(node, source),
                 single_line_no_indent: false,
                 multi_line_indent_all: false,
+                wrap_string: None,
+                verbatim,
             });
-            // Mark all sub-nodes as having this node as a "leaf parent"
             self.mark_leaf_parent(node, node.id());
-        } else {
-            for child in node.children(&mut node.walk()) {
-                self.collect_leafs_inner(&child, source, &parent_ids, level + 1)?;
-            }
         }
 
         Ok(())
     }
 
+    /// Checks whether the leaf with the given id and its neighbouring leaf, on
+    /// the given side, meet with word characters, i.e. whether removing the
+    /// whitespace between them would make them run into each other.
+    fn touches_word(&self, leaf_id: usize, boundary: Boundary) -> bool {
+        fn leaf_contents<'a>(mut atoms: impl Iterator<Item = &'a Atom>) -> Option<&'a str> {
+            atoms.find_map(|atom| match atom {
+                Atom::Leaf { content, .. } => Some(content.as_str()),
+                _ => None,
+            })
+        }
+
+        let Some(&index) = self.leaf_indices.get(&leaf_id) else {
+            return false;
+        };
+        let leaf = leaf_contents(self.atoms[index..].iter());
+
+        let (before, after) = match boundary {
+            Boundary::Before => (leaf_contents(self.atoms[..index].iter().rev()), leaf),
+            Boundary::After => (leaf, leaf_contents(self.atoms[index + 1..].iter())),
+        };
+
+        let is_word = |c: char| c.is_alphanumeric() || c == '_';
+        matches!(
+            (
+                before.and_then(|s| s.chars().next_back()),
+                after.and_then(|s| s.chars().next())
+            ),
+            (Some(b), Some(a)) if is_word(b) && is_word(a)
+        )
+    }
+
     /// Prepend an atom to the first leaf node in the subtree of a given node.
     ///
     /// # Arguments
@@ -511,10 +2096,10 @@ impl AtomCollection {
     /// * `node` - The node to which the atom is prepended.
     /// * `predicates` - The query predicates to wrap the atom with.
     fn prepend(&mut self, atom: Atom, node: &Node, predicates: &QueryPredicates) {
-        let atom = self.expand_multiline(atom, node);
-        let atom = self.wrap(atom, predicates);
         // TODO: Pre-populate these
         let target_node = self.first_leaf(node);
+        let atom = self.queue_softline(atom, node, (target_node.id(), false));
+        let atom = self.wrap(atom, predicates);
 
         log::debug!(
             "Prepending {atom:?} to node {}",
@@ -532,67 +2117,108 @@ impl AtomCollection {
     /// * `node` - The node to which the atom is appended.
     /// * `predicates` - The query predicates to wrap the atom with.
     fn append(&mut self, atom: Atom, node: &Node, predicates: &QueryPredicates) {
-        let atom = self.expand_multiline(atom, node);
-        let atom = self.wrap(atom, predicates);
         let target_node = self.last_leaf(node);
 
+        // Appends to the comment closing a format-off region go after the region
+        let target_id = self
+            .format_off_closers
+            .get(&target_node.id())
+            .copied()
+            .unwrap_or(target_node.id());
+
+        let atom = self.queue_softline(atom, node, (target_id, true));
+        let atom = self.wrap(atom, predicates);
+
         log::debug!(
             "Appending {atom:?} to node {}",
             target_node.display_one_based()
         );
 
-        self.append.entry(target_node.id()).or_default().push(atom);
+        self.append.entry(target_id).or_default().push(atom);
     }
 
-    /// Expands a softline atom to a hardline, space or empty atom depending on
-    /// if we are in a multiline context or not.
-    ///
-    /// If the node's parent is labelled as a multi-line node, it expands the
-    /// softline to a hardline.
-    /// If the node's parent is not labelled as such, but the Atom is labelled
-    /// as `spaced`, the Atom is turned into a space. The Atom is discarded if it is not
-    /// labelled as `spaced`.
-    /// If the node has no parent, the softline atom is discarded by returning an empty atom.
-    ///
-    /// The function ignores all atoms that are not softlines.
-    ///
-    /// # Arguments
-    ///
-    /// * `atom` - An atom to be expanded.
-    /// * `node` - The node to which the atom applies.
-    ///
-    /// # Returns
-    ///
-    /// A new atom after expanding the softline if applicable.
-    fn expand_multiline(&self, atom: Atom, node: &Node) -> Atom {
-        if let Atom::Softline { spaced } = atom {
-            if let Some(parent) = node.parent() {
-                let parent_id = parent.id();
+    /// Appends a literal that is only output if the given scope is multi-line.
+    /// Its position relative to the beginning or end of the scope is fixed
+    /// once all captures have been applied; see `place_scope_literals`.
+    /// Returns the id of the literal.
+    fn append_scope_literal(
+        &mut self,
+        node: &Node,
+        scope_id: &str,
+        literal: &str,
+        opening: bool,
+        predicates: &QueryPredicates,
+    ) -> usize {
+        let id = self.next_id();
+        self.scope_literals.insert(id, opening);
+        self.append(
+            Atom::ScopedConditional {
+                id,
+                scope_id: scope_id.to_string(),
+                condition: ScopeCondition::MultiLineOnly,
+                atom: Box::new(Atom::Literal(literal.to_string())),
+            },
+            node,
+            predicates,
+        );
+        id
+    }
 
-                if self.multi_line_nodes.contains(&parent_id) {
-                    log::debug!(
-                        "Expanding softline to hardline in node {} with parent {}: {}",
-                        node.display_one_based(),
-                        parent_id,
-                        parent.display_one_based()
-                    );
+    /// Whether the parent of a node is multi-line, as softlines within it are
+    /// expanded. This is decided from the layout of the input, adjusted by
+    /// `@inline` and the like, so that delimiters added to multi-line nodes
+    /// cannot change the decision when their output is formatted again.
+    fn parent_is_multi_line(&self, node: &Node) -> bool {
+        node.parent()
+            .is_some_and(|parent| self.multi_line_nodes.contains(&parent.id()))
+    }
+
+    /// Queues the parent of the node to which a softline is attached, under
+    /// the leaf and side it is attached to. Softlines are only expanded once
+    /// the nodes kept on one line are known, see `expand_softlines`. The
+    /// softlines of a node without a parent are discarded.
+    fn queue_softline(&mut self, atom: Atom, node: &Node, target: (usize, bool)) -> Atom {
+        match (&atom, node.parent()) {
+            (Atom::Softline { .. }, Some(parent)) => {
+                self.queued_softlines
+                    .entry(target)
+                    .or_default()
+                    .push_back(parent.id());
+                atom
+            }
+            (Atom::Softline { .. }, None) => Atom::Empty,
+            _ => atom,
+        }
+    }
+
+    /// Expands the softlines to hardlines if the parent of their node is
+    /// multi-line. Otherwise, they are turned into spaces if they are
+    /// `spaced`, and discarded if not.
+    pub(crate) fn expand_softlines(&mut self) {
+        for (index, parent_id) in mem::take(&mut self.softlines) {
+            let multi_line = self.multi_line_nodes.contains(&parent_id);
+            let atom = match &mut self.atoms[index] {
+                Atom::ScopedConditional { atom, .. } => atom.as_mut(),
+                atom => atom,
+            };
+
+            if let Atom::Softline { spaced } = *atom {
+                log::debug!(
+                    "Expanding softline in node with parent {parent_id} ({})",
+                    if multi_line {
+                        "multi-line"
+                    } else {
+                        "single-line"
+                    }
+                );
+                *atom = if multi_line {
                     Atom::Hardline
                 } else if spaced {
-                    log::debug!(
-                        "Expanding softline to space in node {} with parent {}: {}",
-                        node.display_one_based(),
-                        parent_id,
-                        parent.display_one_based()
-                    );
                     Atom::Space
                 } else {
                     Atom::Empty
-                }
-            } else {
-                Atom::Empty
+                };
             }
-        } else {
-            atom
         }
     }
 
@@ -621,6 +2247,9 @@ impl AtomCollection {
         // of the processing, even if the `modifications` map is empty. This is to ensure we will
         // get rid of misplaced scoped atoms.
         let mut force_apply_modifications = false;
+        // `innermost_scopes` is the stack of all opened scopes, whatever their `scope_id`,
+        // used to resolve `ScopedConditional` atoms with the `InnermostOnly` condition.
+        let mut innermost_scopes: Vec<&ScopeId> = Vec::new();
 
         for atom in &self.atoms {
             if let Atom::ScopeBegin(ScopeInformation {
@@ -632,11 +2261,15 @@ impl AtomCollection {
                     .entry(scope_id)
                     .or_default()
                     .push((*line_start, Vec::new()));
+                innermost_scopes.push(scope_id);
             } else if let Atom::ScopeEnd(ScopeInformation {
                 line_number: line_end,
                 scope_id,
             }) = atom
             {
+                if let Some(index) = innermost_scopes.iter().rposition(|id| *id == scope_id) {
+                    innermost_scopes.remove(index);
+                }
                 if let Some((line_start, atoms)) =
                     opened_scopes.get_mut(scope_id).and_then(Vec::pop)
                 {
@@ -660,7 +2293,18 @@ impl AtomCollection {
                         {
                             let multiline_only = *condition == ScopeCondition::MultiLineOnly;
                             let new_atom = if multiline == multiline_only {
-                                atom.deref().clone()
+                                match atom.deref() {
+                                    Atom::Literal(literal)
+                                        if self.scope_line_literals.contains(id) =>
+                                    {
+                                        // Lines are 0-based in scope information
+                                        Atom::Literal(
+                                            literal
+                                                .replace("{line}", &(line_start + 1).to_string()),
+                                        )
+                                    }
+                                    atom => atom.clone(),
+                                }
                             } else {
                                 Atom::Empty
                             };
@@ -668,7 +2312,10 @@ impl AtomCollection {
                         }
                     }
                 } else {
-                    log::warn!("Closing unopened scope {scope_id:?}");
+                    warn(
+                        &mut self.warnings,
+                        format!("Closing unopened scope {scope_id:?}"),
+                    );
                     force_apply_modifications = true;
                 }
             // Register the ScopedSoftline in the correct scope
@@ -677,16 +2324,36 @@ impl AtomCollection {
                 {
                     vec.push(atom);
                 } else {
-                    log::warn!("Found scoped softline {:?} outside of its scope", atom);
+                    warn(
+                        &mut self.warnings,
+                        format!("Found scoped softline {atom:?} outside of its scope"),
+                    );
                     force_apply_modifications = true;
                 }
+            // Resolve the ScopedConditional right away if it depends on the innermost scope
+            } else if let Atom::ScopedConditional {
+                id,
+                scope_id,
+                condition: ScopeCondition::InnermostOnly,
+                atom,
+            } = atom
+            {
+                let new_atom = if innermost_scopes.last() == Some(&scope_id) {
+                    atom.deref().clone()
+                } else {
+                    Atom::Empty
+                };
+                modifications.insert(*id, new_atom);
             // Register the ScopedConditional in the correct scope
             } else if let Atom::ScopedConditional { scope_id, .. } = atom {
                 if let Some((_, vec)) = opened_scopes.get_mut(&scope_id).and_then(|v| v.last_mut())
                 {
                     vec.push(atom);
                 } else {
-                    log::warn!("Found scoped conditional {:?} outside of its scope", atom);
+                    warn(
+                        &mut self.warnings,
+                        format!("Found scoped conditional {atom:?} outside of its scope"),
+                    );
                     force_apply_modifications = true;
                 }
             }
@@ -696,7 +2363,10 @@ impl AtomCollection {
             .filter_map(|(scope_id, vec)| if vec.is_empty() { None } else { Some(scope_id) })
             .collect();
         if !still_opened.is_empty() {
-            log::warn!("Some scopes have been left opened: {:?}", still_opened);
+            warn(
+                &mut self.warnings,
+                format!("Some scopes have been left opened: {still_opened:?}"),
+            );
             force_apply_modifications = true;
         }
 
@@ -718,9 +2388,11 @@ impl AtomCollection {
                     if let Some(replacement) = modifications.remove(id) {
                         *atom = replacement;
                     } else {
-                        log::warn!(
-                            "Found scoped softline {:?}, but was unable to replace it.",
-                            atom
+                        warn(
+                            &mut self.warnings,
+                            format!(
+                                "Found scoped softline {atom:?}, but was unable to replace it."
+                            ),
                         );
                         *atom = Atom::Empty;
                     }
@@ -728,9 +2400,11 @@ impl AtomCollection {
                     if let Some(replacement) = modifications.remove(id) {
                         *atom = replacement;
                     } else {
-                        log::warn!(
-                            "Found scoped conditional {:?}, but was unable to replace it.",
-                            atom
+                        warn(
+                            &mut self.warnings,
+                            format!(
+                                "Found scoped conditional {atom:?}, but was unable to replace it."
+                            ),
                         );
                         *atom = Atom::Empty;
                     }
@@ -739,28 +2413,193 @@ impl AtomCollection {
         }
     }
 
+    /// Turns pairs of consecutive hardlines between `HardlinePairsBegin` and
+    /// `HardlinePairsEnd` into blank lines. Hardlines are consecutive if only
+    /// empty and indentation atoms lie between them.
+    fn post_process_hardline_pairs(&mut self) {
+        let mut level: usize = 0;
+        let mut previous_hardline: Option<usize> = None;
+
+        for i in 0..self.atoms.len() {
+            match self.atoms[i] {
+                Atom::HardlinePairsBegin => {
+                    level += 1;
+                    self.atoms[i] = Atom::Empty;
+                }
+                Atom::HardlinePairsEnd => {
+                    level = level.saturating_sub(1);
+                    self.atoms[i] = Atom::Empty;
+                }
+                Atom::Empty | Atom::IndentStart(_) | Atom::IndentEnd => {}
+                Atom::Hardline if level > 0 => {
+                    if let Some(previous) = previous_hardline.take() {
+                        self.atoms[previous] = Atom::Empty;
+                        self.atoms[i] = Atom::Blankline;
+                    } else {
+                        previous_hardline = Some(i);
+                    }
+                }
+                _ => previous_hardline = None,
+            }
+        }
+    }
+
+    /// Removes the line breaks between the leaf or literal preceding each
+    /// `Glue` atom and the one following it, so that they stay on the same
+    /// line. Atoms appended to the same leaf as the `Glue` may lie on either
+    /// side of it, depending on the order of the captures.
+    fn post_process_glue(&mut self) {
+        let is_boundary = |atom: &Atom| matches!(atom, Atom::Leaf { .. } | Atom::Literal(_));
+
+        for i in 0..self.atoms.len() {
+            if self.atoms[i] != Atom::Glue {
+                continue;
+            }
+            self.atoms[i] = Atom::Empty;
+
+            let start = self.atoms[..i]
+                .iter()
+                .rposition(is_boundary)
+                .map_or(0, |j| j + 1);
+            let end = self.atoms[i..]
+                .iter()
+                .position(is_boundary)
+                .map_or(self.atoms.len(), |j| i + j);
+            for atom in &mut self.atoms[start..end] {
+                if matches!(atom, Atom::Hardline | Atom::Blankline) {
+                    *atom = Atom::Empty;
+                }
+            }
+        }
+    }
+
+    /// Turns the blank lines directly before each leaf marked by
+    /// `@remove_trailing_blank_lines` into line breaks. Only whitespace and
+    /// indentation atoms may lie between them and the leaf.
+    fn post_process_trailing_blank_lines(&mut self) {
+        if self.trailing_blank_line_leaves.is_empty() {
+            return;
+        }
+
+        for i in 0..self.atoms.len() {
+            let Atom::Leaf { id, .. } = &self.atoms[i] else {
+                continue;
+            };
+            if !self.trailing_blank_line_leaves.contains(id) {
+                continue;
+            }
+
+            for atom in self.atoms[..i].iter_mut().rev() {
+                match atom {
+                    Atom::Blankline => *atom = Atom::Hardline,
+                    Atom::Empty
+                    | Atom::Space
+                    | Atom::Antispace
+                    | Atom::Hardline
+                    | Atom::IndentStart(_)
+                    | Atom::IndentEnd => {}
+                    _ => break,
+                }
+            }
+        }
+    }
+
+    /// Removes the leaves marked with `@dedup_adjacent` which repeat the
+    /// previous leaf, if it is also marked, along with the line breaks and
+    /// spaces between them. Leaves separated by a literal are not adjacent.
+    fn post_process_dedup(&mut self) {
+        if self.dedup_leaves.is_empty() {
+            return;
+        }
+
+        // The index and content of the previous marked leaf
+        let mut previous: Option<(usize, String)> = None;
+
+        for i in 0..self.atoms.len() {
+            match &self.atoms[i] {
+                Atom::Leaf { id, content, .. } if self.dedup_leaves.contains(id) => match &previous
+                {
+                    Some((start, previous_content)) if previous_content == content => {
+                        for atom in &mut self.atoms[start + 1..=i] {
+                            if matches!(
+                                atom,
+                                Atom::Leaf { .. } | Atom::Space | Atom::Hardline | Atom::Blankline
+                            ) {
+                                *atom = Atom::Empty;
+                            }
+                        }
+                    }
+                    _ => previous = Some((i, content.clone())),
+                },
+                Atom::Leaf { .. } | Atom::Literal(_) => previous = None,
+                _ => {}
+            }
+        }
+    }
+
     /// Separate post_processing of Delete sections, to avoid interference with whitespace logic
+    ///
+    /// Empties all atoms between `DeleteBegin` and `DeleteEnd`, except for
+    /// indentation atoms.
+    ///
+    /// If a deleted segment is directly preceded by a comment, with no blank
+    /// line in between, that comment is considered to be attached to the
+    /// deleted node. It is reattached to whatever follows the deleted segment,
+    /// by removing the whitespace between the segment and the next atom.
     fn post_process_deletes(&mut self) {
-        let mut delete_level = 0;
+        let mut delete_level: usize = 0;
+        // Whether the last non-whitespace atom is an attached comment
+        let mut after_comment = false;
+        // Whether we are removing the whitespace after a deleted segment
+        let mut reattaching = false;
+        // Whether we have seen a DeleteEnd without a matching DeleteBegin
+        let mut unmatched_end = false;
+
         for atom in &mut self.atoms {
             match atom {
                 Atom::DeleteBegin => {
+                    if delete_level == 0 && after_comment {
+                        reattaching = true;
+                    }
                     delete_level += 1;
                     *atom = Atom::Empty;
                 }
                 Atom::DeleteEnd => {
-                    delete_level -= 1;
+                    // An unmatched DeleteEnd is ignored, rather than
+                    // underflowing the level
+                    match delete_level.checked_sub(1) {
+                        Some(level) => delete_level = level,
+                        None => unmatched_end = true,
+                    }
                     *atom = Atom::Empty;
                 }
-                _ => {
-                    if delete_level > 0 {
-                        *atom = Atom::Empty;
-                    }
+                // Indentation atoms are kept, as their counterparts may lie
+                // outside of the deleted segment: an indentation block may
+                // start within a deleted node and end after it, e.g. when the
+                // node is the first of the block. Emptying just one of them
+                // would close an unopened block, or leave one open.
+                Atom::IndentStart(_) | Atom::IndentEnd => {}
+                _ if delete_level > 0 => *atom = Atom::Empty,
+                Atom::Space | Atom::Hardline | Atom::Blankline if reattaching => {
+                    *atom = Atom::Empty;
+                }
+                Atom::Blankline => after_comment = false,
+                Atom::Leaf { id, .. } => {
+                    after_comment = self.comment_leaves.contains(id);
+                    reattaching = false;
+                }
+                Atom::Literal(_) => {
+                    after_comment = false;
+                    reattaching = false;
                 }
+                _ => {}
             }
         }
-        if delete_level != 0 {
-            log::warn!("The number of DeleteBegin is different from the number of DeleteEnd.");
+        if delete_level != 0 || unmatched_end {
+            warn(
+                &mut self.warnings,
+                "The number of DeleteBegin is different from the number of DeleteEnd.".into(),
+            );
         }
     }
 
@@ -769,8 +2608,37 @@ impl AtomCollection {
     /// the blank line is kept over the new line which itself is kept over the space.
     /// Furthermore, this function put the indentation delimiters before any space/line atom.
     pub fn post_process(&mut self) {
-        self.post_process_scopes();
-        self.post_process_deletes();
+        self.post_process_with(PostProcessPass::DEFAULT_ORDER);
+    }
+
+    /// Post-processes the atoms like `post_process`, running the given passes
+    /// in the given order. The passes left out run afterwards, in their
+    /// default order, so that the order can be changed but no pass skipped.
+    pub fn post_process_with(&mut self, passes: &[PostProcessPass]) {
+        let stopwatch = Stopwatch::start(self.record_timings);
+
+        let mut ran: Vec<PostProcessPass> =
+            Vec::with_capacity(PostProcessPass::DEFAULT_ORDER.len());
+        for pass in passes.iter().chain(PostProcessPass::DEFAULT_ORDER) {
+            if ran.contains(pass) {
+                continue;
+            }
+            ran.push(*pass);
+
+            match pass {
+                PostProcessPass::Scopes => {
+                    let scopes_stopwatch = Stopwatch::start(self.record_timings);
+                    self.post_process_scopes();
+                    self.timings.post_process_scopes = scopes_stopwatch.elapsed();
+                }
+                PostProcessPass::Deletes => self.post_process_deletes(),
+                PostProcessPass::Dedup => self.post_process_dedup(),
+                PostProcessPass::HardlinePairs => self.post_process_hardline_pairs(),
+                PostProcessPass::Glue => self.post_process_glue(),
+                PostProcessPass::TrailingBlankLines => self.post_process_trailing_blank_lines(),
+            }
+        }
+
         self.post_process_inner();
 
         // We have taken care of spaces following an antispace. Now fix the
@@ -781,6 +2649,7 @@ impl AtomCollection {
         // antispaces may have produced more empty atoms.
         self.post_process_inner();
 
+        self.timings.post_process = stopwatch.elapsed();
         log::debug!("List of atoms after post-processing: {:?}", self.atoms);
     }
 
@@ -841,7 +2710,7 @@ impl AtomCollection {
                 // If a whitespace atom is followed by an indent atom, swap their positions.
                 (
                     moved_prev @ (Atom::Space | Atom::Hardline | Atom::Blankline),
-                    moved_remaining @ [Atom::IndentStart | Atom::IndentEnd, ..],
+                    moved_remaining @ [Atom::IndentStart(_) | Atom::IndentEnd, ..],
                 ) => {
                     let old_prev = moved_prev.clone();
                     let indent = moved_remaining.first_mut().unwrap();
@@ -887,8 +2756,11 @@ impl AtomCollection {
     /// A `Cow` enum that wraps a borrowed node.
     fn first_leaf<'tree, 'node: 'tree>(&self, node: &'node Node<'tree>) -> Cow<'node, Node<'tree>> {
         let mut node = Cow::Borrowed(node);
-        while node.child_count() != 0 && !self.specified_leaf_nodes.contains(&node.id()) {
-            node = Cow::Owned(node.child(0).unwrap());
+        while !self.specified_leaf_nodes.contains(&node.id()) {
+            match node.child(0) {
+                Some(child) => node = Cow::Owned(child),
+                None => break,
+            }
         }
         node
     }
@@ -909,11 +2781,51 @@ impl AtomCollection {
     /// A `Cow` enum that wraps a borrowed node.
     fn last_leaf<'tree, 'node: 'tree>(&self, node: &'node Node<'tree>) -> Cow<'node, Node<'tree>> {
         let mut node = Cow::Borrowed(node);
-        while node.child_count() != 0 && !self.specified_leaf_nodes.contains(&node.id()) {
-            node = Cow::Owned(node.child(node.child_count() - 1).unwrap());
+        while !self.specified_leaf_nodes.contains(&node.id()) {
+            let last_child = node
+                .child_count()
+                .checked_sub(1)
+                .and_then(|index| node.child(index));
+            match last_child {
+                Some(child) => node = Cow::Owned(child),
+                None => break,
+            }
+        }
+        node
+    }
+
+    /// Returns the last leaf of a node's subtree that is output, skipping
+    /// the zero-width leaves, such as automatically inserted terminators.
+    fn last_output_leaf<'tree>(&self, node: &Node<'tree>) -> Node<'tree> {
+        let mut node = node.clone();
+        while !self.specified_leaf_nodes.contains(&node.id()) {
+            let last_child = node
+                .children(&mut node.walk())
+                .filter(|child| child.end_byte() > child.start_byte())
+                .last();
+            match last_child {
+                Some(child) => node = child,
+                None => break,
+            }
         }
         node
     }
+
+    /// Returns the leaf terminating a node with the given delimiter: either
+    /// the last leaf of the node, or the leaf right after it.
+    fn terminator<'tree>(&self, node: &Node<'tree>, delimiter: &str) -> Option<Node<'tree>> {
+        let is_delimiter = |leaf: &Node| {
+            self.atoms.iter().any(|atom| {
+                matches!(atom, Atom::Leaf { id, content, .. } if *id == leaf.id() && content == delimiter)
+            })
+        };
+
+        let last = self.last_output_leaf(node);
+        if is_delimiter(&last) {
+            return Some(last);
+        }
+        node.next_sibling().filter(is_delimiter)
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -934,6 +2846,137 @@ pub struct QueryPredicates {
     /// The flag that indicates that the query only triggers if the associated
     /// custom scope containing the matched nodes is multi-line.
     pub multi_line_scope_only: Option<String>,
+    /// The flag that indicates that the query only triggers if the associated
+    /// custom scope is the innermost scope containing the matched nodes.
+    pub innermost_scope_only: Option<String>,
+    /// The predicate used to list the node kinds relevant to the
+    /// `@append_space_before_kinds`, `@space_around_operator` and
+    /// `@strip_redundant_parens` directives.
+    pub kinds: Option<Vec<String>>,
+    /// The predicate used to set the width beyond which `@wrap_string`
+    /// splits a string.
+    pub max_width: Option<usize>,
+    /// The predicate used to set the number of named children of the parent
+    /// node above which `@append_hardline_if_parent_over` breaks the line.
+    pub child_count: Option<usize>,
+    /// The predicate used to set the number of spaces by which
+    /// `@append_indent_start_width` and `@prepend_indent_start_width` indent.
+    pub indent_width: Option<usize>,
+    /// The predicate used to set the literal by which
+    /// `@append_indent_start_guide` and `@prepend_indent_start_guide` indent.
+    pub indent_guide: Option<String>,
+    /// The predicate used to set the number of children on each line for
+    /// `@hardline_every_n`.
+    pub row_size: Option<usize>,
+    /// The predicate used to choose how `@normalise_number` rewrites a
+    /// numeric literal.
+    pub number_format: Option<NumberFormat>,
+    /// The predicate used to choose on which side of an operator
+    /// `@softline_around_operator` puts the softline.
+    pub operator_break: Option<OperatorBreak>,
+    /// The predicate used to give the separator to which
+    /// `@normalise_path_separator` rewrites paths.
+    pub path_separator: Option<PathSeparator>,
+    /// The predicate used to list, for `@canonicalise`, a canonical form
+    /// followed by its synonyms.
+    pub canonical: Option<Vec<String>>,
+    /// The predicate used to give, for `@to_block_comment` and
+    /// `@to_line_comment`, the line comment prefix, and the opening and
+    /// closing delimiters of block comments.
+    pub comment_delimiters: Option<Vec<String>>,
+    /// The predicate used to name the language of the content of a leaf
+    /// marked with `@embedded`.
+    pub embedded_language: Option<String>,
+}
+
+/// Where `@softline_around_operator` allows a line break.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OperatorBreak {
+    /// Before the operator, which starts the continuation line.
+    #[default]
+    Before,
+    /// After the operator, which ends the line before the break.
+    After,
+}
+
+/// The path separators to which `@normalise_path_separator` can rewrite.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PathSeparator {
+    /// `/`, as on Unix.
+    Slash,
+    /// `\`, as on Windows, which is escaped as `\\` in string literals.
+    Backslash,
+}
+
+/// The ways in which `@normalise_number` can rewrite a numeric literal.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NumberFormat {
+    /// Remove the trailing zeros of the fractional part, and the decimal
+    /// point if nothing is left after it: `1.50` becomes `1.5`, `2.0`
+    /// becomes `2`.
+    TrimZeros,
+    /// Separate the digits of the integer part in groups of three, with the
+    /// `#delimiter!` literal: `1000000` becomes `1_000_000`.
+    GroupDigits,
+}
+
+/// Moves the literals added by `@append_scope_open_literal` (resp.
+/// `@append_scope_close_literal`) right after the beginning (resp. before the
+/// end) of their scope, if it is prepended or appended to the same leaf. This
+/// way they lie within the scope, whatever the order in which the captures
+/// were applied, and a closing literal appended to a leaf that the scope ends
+/// before is output before that leaf.
+fn place_scope_literals(
+    prepends: &mut Vec<Atom>,
+    appends: &mut Vec<Atom>,
+    scope_literals: &HashMap<usize, bool>,
+) {
+    let ids: Vec<usize> = appends
+        .iter()
+        .filter_map(Atom::id)
+        .filter(|id| scope_literals.contains_key(id))
+        .collect();
+
+    for id in ids {
+        let Some(index) = appends.iter().position(|a| a.id() == Some(id)) else {
+            continue;
+        };
+        let literal = appends.remove(index);
+
+        let Atom::ScopedConditional { scope_id, .. } = &literal else {
+            appends.insert(index, literal);
+            continue;
+        };
+        let begins = |a: &Atom| matches!(a, Atom::ScopeBegin(info) if &info.scope_id == scope_id);
+        let ends = |a: &Atom| matches!(a, Atom::ScopeEnd(info) if &info.scope_id == scope_id);
+
+        // The beginning is looked for after the leaf first, the end before it
+        let target = if scope_literals[&id] {
+            match appends.iter().rposition(begins) {
+                Some(i) => Some((false, i + 1)),
+                None => prepends.iter().rposition(begins).map(|i| (true, i + 1)),
+            }
+        } else {
+            match prepends.iter().position(ends) {
+                Some(i) => Some((true, i)),
+                None => appends.iter().position(ends).map(|i| (false, i)),
+            }
+        };
+
+        match target {
+            Some((true, i)) => prepends.insert(i, literal),
+            Some((false, i)) => appends.insert(i, literal),
+            None => appends.insert(index, literal),
+        }
+    }
+}
+
+/// Whether an atom is a softline, possibly wrapped in a scoped conditional.
+fn holds_softline(atom: &Atom) -> bool {
+    match atom {
+        Atom::ScopedConditional { atom, .. } => holds_softline(atom),
+        atom => matches!(atom, Atom::Softline { .. }),
+    }
 }
 
 /// Collapses spaces before antispace atoms in a vector of atoms.
@@ -997,6 +3040,205 @@ fn dfs_flatten<'tree>(node: &Node<'tree>) -> Vec<Node<'tree>> {
     dfs_nodes
 }
 
+/// Logs a warning, and records it so that it can be reported to the caller.
+fn warn(warnings: &mut Vec<String>, message: String) {
+    log::warn!("{message}");
+    warnings.push(message);
+}
+
+/// The position at which a leaf starts in the input. Unlike the byte column
+/// tree-sitter gives, its column counts characters, so that it can be compared
+/// with the columns of the output.
+fn leaf_position(node: &Node, source: &[u8]) -> Position {
+    let start = node.start_byte() as usize;
+    let line_start = source[..start]
+        .iter()
+        .rposition(|&byte| byte == b'\n')
+        .map_or(0, |newline| newline + 1);
+    let column = String::from_utf8_lossy(&source[line_start..start])
+        .chars()
+        .count();
+
+    Position {
+        row: node.start_position().row() + 1,
+        column: column as u32 + 1,
+    }
+}
+
+/// Whether a comment is a doc comment, by the usual convention: its opening
+/// delimiter is followed by the last character of that delimiter, or by `!`,
+/// as in `/// text`, `//! text` or `/** text */`, but not `/**/`. Line comments
+/// have no closing delimiter.
+fn is_doc_comment(content: &str, open: &str, close: &str) -> bool {
+    content.strip_prefix(open).is_some_and(|rest| {
+        (close.is_empty() || !rest.starts_with(close))
+            && open
+                .chars()
+                .last()
+                .into_iter()
+                .chain(['!'])
+                .any(|marker| rest.starts_with(marker))
+    })
+}
+
+/// Removes trailing whitespace from every line of the given text, preserving
+/// its line endings.
+fn trim_trailing_whitespace(content: &str) -> String {
+    content
+        .split('\n')
+        .map(|line| match line.strip_suffix('\r') {
+            Some(line) => format!("{}\r", line.trim_end()),
+            None => line.trim_end().to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Rewrites the path separators of a string literal, in which a backslash is
+/// escaped as `\\`, to the given separator. Other escape sequences, such as
+/// `\n` or `\"`, are kept as they are.
+fn normalise_path_separators(content: &str, separator: PathSeparator) -> String {
+    let separator = match separator {
+        PathSeparator::Slash => "/",
+        PathSeparator::Backslash => "\\\\",
+    };
+    let mut normalised = String::with_capacity(content.len());
+    let mut chars = content.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '/' => normalised.push_str(separator),
+            '\\' => match chars.next() {
+                Some('\\') => normalised.push_str(separator),
+                Some(escaped) => {
+                    normalised.push(c);
+                    normalised.push(escaped);
+                }
+                None => normalised.push(c),
+            },
+            _ => normalised.push(c),
+        }
+    }
+
+    normalised
+}
+
+/// Rewrites a decimal numeric literal in the given format, keeping its value.
+/// Literals that are not plain decimal numbers, such as hexadecimal ones or
+/// ones with a type suffix, are returned unchanged, as are integers with a
+/// leading zero, which some languages read as octal.
+fn normalise_number(content: &str, format: NumberFormat, separator: &str) -> String {
+    let (sign, unsigned) = match content.strip_prefix(['+', '-']) {
+        Some(rest) => content.split_at(content.len() - rest.len()),
+        None => ("", content),
+    };
+    let (mantissa, exponent) = match unsigned.find(['e', 'E']) {
+        Some(index) => unsigned.split_at(index),
+        None => (unsigned, ""),
+    };
+    let (integer, fraction) = match mantissa.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (mantissa, None),
+    };
+
+    let is_digits = |s: &str| s.chars().all(|c| c.is_ascii_digit() || c == '_');
+    let exponent_digits = exponent
+        .get(1..)
+        .map(|e| e.strip_prefix(['+', '-']).unwrap_or(e));
+    if !is_digits(integer)
+        || !fraction.is_none_or(is_digits)
+        || !exponent_digits.is_none_or(|e| !e.is_empty() && is_digits(e))
+        || (integer.is_empty() && fraction.is_none_or(str::is_empty))
+    {
+        return content.to_string();
+    }
+
+    match format {
+        NumberFormat::TrimZeros => {
+            let fraction = fraction.map_or("", |f| f.trim_end_matches(['0', '_']));
+            let integer = if integer.is_empty() && fraction.is_empty() {
+                "0"
+            } else {
+                integer
+            };
+            if fraction.is_empty() {
+                format!("{sign}{integer}{exponent}")
+            } else {
+                format!("{sign}{integer}.{fraction}{exponent}")
+            }
+        }
+        NumberFormat::GroupDigits => {
+            if integer.contains('_') || (integer.len() > 1 && integer.starts_with('0')) {
+                return content.to_string();
+            }
+            let mut grouped = String::new();
+            for (i, digit) in integer.chars().enumerate() {
+                if i > 0 && (integer.len() - i) % 3 == 0 {
+                    grouped.push_str(separator);
+                }
+                grouped.push(digit);
+            }
+            let rest = &mantissa[integer.len()..];
+            format!("{sign}{grouped}{rest}{exponent}")
+        }
+    }
+}
+
+/// Detects the regions of the input in which formatting is disabled.
+///
+/// A region starts at a comment containing `FORMAT_OFF_MARKER` and ends at the
+/// end of the next comment containing `FORMAT_ON_MARKER`, or at the end of the
+/// input if there is no such comment.
+///
+/// # Arguments
+///
+/// * `dfs_nodes` - A slice of nodes in depth-first search order.
+/// * `source` - The full source code as a byte slice.
+fn detect_format_off_regions(
+    dfs_nodes: &[Node],
+    source: &[u8],
+    comment_kinds: Option<&[String]>,
+) -> Vec<FormatOffRegion> {
+    let mut regions = Vec::new();
+    let mut start_byte = None;
+
+    for node in dfs_nodes {
+        if !is_comment(node, comment_kinds) {
+            continue;
+        }
+        let Ok(text) = node.utf8_text(source) else {
+            continue;
+        };
+
+        match start_byte {
+            None if text.contains(FORMAT_OFF_MARKER) => start_byte = Some(node.start_byte()),
+            Some(start) if text.contains(FORMAT_ON_MARKER) => {
+                regions.push(FormatOffRegion {
+                    start_byte: start,
+                    end_byte: node.end_byte(),
+                    closer: Some(node.id()),
+                    leaf_id: None,
+                    protected: false,
+                });
+                start_byte = None;
+            }
+            _ => {}
+        }
+    }
+
+    if let (Some(start), Some(root)) = (start_byte, dfs_nodes.first()) {
+        regions.push(FormatOffRegion {
+            start_byte: start,
+            end_byte: root.end_byte(),
+            closer: None,
+            leaf_id: None,
+            protected: false,
+        });
+    }
+
+    regions
+}
+
 /// Detects multi-line nodes in a vector of nodes and returns a set of their ids.
 ///
 /// This function takes a slice of `Node`s that represents the nodes in a depth-first search
@@ -1004,21 +3246,43 @@ fn dfs_flatten<'tree>(node: &Node<'tree>) -> Vec<Node<'tree>> {
 /// line numbers and checks if they are different. If they are, it means the node spans multiple
 /// lines and its id is added to the returned set.
 ///
+/// The lines spanned by the given string nodes are not counted, so that a multi-line string does
+/// not, on its own, make the nodes that contain it multi-line.
+///
+/// Zero-width nodes are expected to have been filtered out of `dfs_nodes`, as they are in
+/// `collect_leafs`: their position is not meaningful.
+///
 /// # Arguments
 ///
 /// * `dfs_nodes` - A slice of nodes in depth-first search order.
+/// * `string_nodes` - The ids of the multi-line string nodes.
 ///
 /// # Returns
 ///
 /// A `HashSet` containing the ids of the multi-line nodes.
-fn detect_multi_line_nodes(dfs_nodes: &[Node]) -> HashSet<usize> {
+fn detect_multi_line_nodes(dfs_nodes: &[Node], string_nodes: &HashSet<usize>) -> HashSet<usize> {
+    // The number of line breaks within strings, for each node containing strings
+    let mut string_line_breaks: HashMap<usize, u32> = HashMap::new();
+    for node in dfs_nodes
+        .iter()
+        .filter(|node| string_nodes.contains(&node.id()))
+    {
+        let line_breaks = node.end_position().row() - node.start_position().row();
+        let mut ancestor = Some(node.clone());
+        while let Some(current) = ancestor {
+            *string_line_breaks.entry(current.id()).or_default() += line_breaks;
+            ancestor = current.parent();
+        }
+    }
+
     dfs_nodes
         .iter()
         .filter_map(|node| {
             let start_line = node.start_position().row();
             let end_line = node.end_position().row();
+            let string_lines = string_line_breaks.get(&node.id()).copied().unwrap_or(0);
 
-            if end_line > start_line {
+            if end_line - start_line > string_lines {
                 log::debug!(
                     "Multi-line node {}: {}",
                     node.id(),
@@ -1054,7 +3318,7 @@ fn detect_line_breaks(dfs_nodes: &[Node], minimum_line_breaks: u32) -> NodesWith
     // Unzip into "nodes with spaces before" and "after" sets, respectively
     let (before, after) = dfs_nodes
         .iter()
-        .zip(dfs_nodes[1..].iter())
+        .zip(dfs_nodes.iter().skip(1))
         .filter_map(|(left, right)| {
             let last = left.end_position().row();
             let next = right.start_position().row();
@@ -1098,7 +3362,10 @@ where
 
 #[cfg(test)]
 mod test {
-    use crate::{atom_collection::AtomCollection, Atom};
+    use crate::{
+        apply_query, apply_query_with_provenance, atom_collection::AtomCollection, Atom, Position,
+        ScopeCondition, ScopeInformation, TopiaryQuery,
+    };
     use test_log::test;
 
     #[test]
@@ -1190,4 +3457,128 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn provenance_records_contributing_captures() {
+        let grammar = tree_sitter_json::language().into();
+        let query = TopiaryQuery::new(&grammar, "\":\" @prepend_antispace @append_space").unwrap();
+
+        let atoms =
+            apply_query_with_provenance(r#"{"a":1}"#, &query, &grammar, false, false).unwrap();
+
+        let colon = atoms
+            .atoms
+            .iter()
+            .find_map(|atom| match atom {
+                Atom::Leaf { content, id, .. } if content == ":" => Some(*id),
+                _ => None,
+            })
+            .unwrap();
+
+        let provenance = atoms.provenance(colon).unwrap();
+        assert_eq!(provenance.len(), 2);
+        assert!(provenance.contains(&("prepend_antispace".into(), Atom::Antispace)));
+        assert!(provenance.contains(&("append_space".into(), Atom::Space)));
+    }
+
+    #[test]
+    fn trim_trailing_whitespace_in_leaf() {
+        let grammar = tree_sitter_ocaml::language_ocaml().into();
+        let query =
+            TopiaryQuery::new(&grammar, "(quoted_string) @leaf @trim_trailing_whitespace").unwrap();
+
+        let atoms = apply_query(
+            "let s = {|foo   \n  bar\t \r\n|}",
+            &query,
+            &grammar,
+            false,
+            false,
+        )
+        .unwrap();
+
+        assert!(atoms.atoms.iter().any(|atom| matches!(
+            atom,
+            Atom::Leaf { content, .. } if content == "{|foo\n  bar\r\n|}"
+        )));
+    }
+
+    #[test]
+    fn append_space_before_kinds_only_fires_before_listed_kinds() {
+        let grammar = tree_sitter_ocaml::language_ocaml().into();
+        let query = TopiaryQuery::new(
+            &grammar,
+            "((_) @append_space_before_kinds (#kinds! \"else_clause\"))",
+        )
+        .unwrap();
+
+        let atoms = apply_query("if a then b else c; d", &query, &grammar, false, false).unwrap();
+
+        let spaces: Vec<_> = atoms
+            .atoms
+            .windows(2)
+            .filter_map(|pair| match pair {
+                [Atom::Leaf { content, .. }, Atom::Space] => Some(content.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(spaces, ["b"]);
+    }
+
+    #[test]
+    fn atoms_built_through_public_constructors() {
+        let position = Position { row: 1, column: 1 };
+        let mut atom_collection =
+            AtomCollection::new(vec![Atom::ScopeBegin(ScopeInformation::new(0, "list"))]);
+
+        let first = atom_collection.leaf("[1,", position);
+        let softline = atom_collection.scoped_softline("list", true);
+        let conditional = atom_collection.scoped_conditional(
+            "list",
+            ScopeCondition::SingleLineOnly,
+            Atom::Literal(";".into()),
+        );
+        let second = atom_collection.leaf("2]", position);
+
+        // Every allocated id is distinct
+        let ids: Vec<_> = [&first, &softline, &conditional, &second]
+            .iter()
+            .filter_map(|atom| atom.id())
+            .collect();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+
+        for atom in [first, softline, conditional, second] {
+            atom_collection.push(atom);
+        }
+        atom_collection.push(Atom::ScopeEnd(ScopeInformation::new(1, "list")));
+
+        atom_collection.post_process();
+
+        // The scope spans two lines, so the softline becomes a hardline and
+        // the single-line conditional is dropped
+        assert_eq!(
+            crate::pretty::render(&atom_collection[..], Default::default())
+                .unwrap()
+                .output,
+            "[1,\n2]"
+        );
+    }
+
+    #[test]
+    fn normalise_number_leaves_non_decimal_literals_alone() {
+        use super::{normalise_number, NumberFormat};
+
+        for (input, format, expected) in [
+            ("1.50", NumberFormat::TrimZeros, "1.5"),
+            ("-2.000e+3", NumberFormat::TrimZeros, "-2e+3"),
+            (".0", NumberFormat::TrimZeros, "0"),
+            ("0x1F0", NumberFormat::TrimZeros, "0x1F0"),
+            ("1.0f", NumberFormat::TrimZeros, "1.0f"),
+            ("1234567.5", NumberFormat::GroupDigits, "1_234_567.5"),
+            ("123", NumberFormat::GroupDigits, "123"),
+            ("0755", NumberFormat::GroupDigits, "0755"),
+            ("0o755", NumberFormat::GroupDigits, "0o755"),
+        ] {
+            assert_eq!(normalise_number(input, format, "_"), expected, "{input}");
+        }
+    }
 }
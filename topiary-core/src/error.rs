@@ -1,11 +1,24 @@
 //! This module defines all errors that might be propagated out of the library,
 //! including all of the trait implementations one might expect for Errors.
 
-use std::{error::Error, fmt, io, ops::Deref, str, string};
+use std::{
+    error::Error,
+    fmt, io,
+    ops::{Deref, Range},
+    str, string,
+};
 
 /// The various errors the formatter may return.
 #[derive(Debug)]
 pub enum FormatterError {
+    /// The grammar was generated for a tree-sitter language version (ABI) that
+    /// is not within the range supported by Topiary.
+    GrammarVersion {
+        version: u32,
+        min_version: u32,
+        max_version: u32,
+    },
+
     /// The input produced output that isn't idempotent, i.e. formatting the
     /// output again made further changes. If this happened using our provided
     /// query files, it is a bug. Please log an issue.
@@ -19,12 +32,17 @@ pub enum FormatterError {
     /// An internal error occurred. This is a bug. Please log an issue.
     Internal(String, Option<Box<dyn Error>>),
 
+    /// The byte range to format is not within the input, of the given length.
+    InvalidRange { range: Range<usize>, length: usize },
+
     /// Tree-sitter could not parse the input without errors.
     Parsing {
         start_line: u32,
         start_column: u32,
         end_line: u32,
         end_column: u32,
+        /// The byte offsets of the part of the input that could not be parsed
+        span: Option<Range<usize>>,
     },
 
     /// The query contains a pattern that had no match in the input file.
@@ -32,11 +50,37 @@ pub enum FormatterError {
     PatternDoesNotMatch(String),
 
     /// There was an error in the query file. If this happened using our
-    /// provided query files, it is a bug. Please log an issue.
-    Query(String, Option<topiary_tree_sitter_facade::QueryError>),
+    /// provided query files, it is a bug. Please log an issue. When the error
+    /// was raised by a match of the query, the byte offsets of the matched
+    /// node in the input are given.
+    Query {
+        message: String,
+        source: Option<topiary_tree_sitter_facade::QueryError>,
+        span: Option<Range<usize>>,
+    },
+
+    /// With `verify_tokens`, the output does not have the same tokens as the
+    /// input, whitespace aside. The first tokens that differ are given, with
+    /// the byte offsets of the one in the input; `None` stands for the end of
+    /// the input or of the output.
+    TokenMismatch {
+        expected: Option<String>,
+        found: Option<String>,
+        span: Option<Range<usize>>,
+    },
+
+    /// With `max_depth`, the syntax tree of the input is deeper than allowed.
+    /// The byte offsets of the first node beyond the maximum depth are given.
+    TooDeep {
+        max_depth: usize,
+        span: Option<Range<usize>>,
+    },
 
     /// I/O-related errors
     Io(IoError),
+
+    /// Warnings were raised while formatting, and were denied.
+    Warnings(Vec<String>),
 }
 
 /// A subtype of `FormatterError::Io`
@@ -58,6 +102,17 @@ impl fmt::Display for FormatterError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let please_log_message = "If this happened with the built-in query files, it is a bug. It would be\nhelpful if you logged this error at\nhttps://github.com/tweag/topiary/issues/new?assignees=&labels=type%3A+bug&template=bug_report.md";
         match self {
+            Self::GrammarVersion {
+                version,
+                min_version,
+                max_version,
+            } => {
+                write!(
+                    f,
+                    "The grammar uses tree-sitter language version {version}, but Topiary only supports\nversions {min_version} to {max_version}. Please use a grammar generated with a compatible version\nof tree-sitter."
+                )
+            }
+
             Self::Idempotence => {
                 write!(
                     f,
@@ -72,11 +127,20 @@ impl fmt::Display for FormatterError {
                 )
             }
 
+            Self::InvalidRange { range, length } => {
+                write!(
+                    f,
+                    "The range {}..{} is not within the input, of length {length}",
+                    range.start, range.end
+                )
+            }
+
             Self::Parsing {
                 start_line,
                 start_column,
                 end_line,
                 end_column,
+                ..
             } => {
                 write!(f, "Parsing error between line {start_line}, column {start_column} and line {end_line}, column {end_column}")
             }
@@ -88,8 +152,41 @@ impl fmt::Display for FormatterError {
                 )
             }
 
+            Self::TokenMismatch {
+                expected, found, ..
+            } => {
+                let token = |token: &Option<String>, end: &str| {
+                    token
+                        .as_ref()
+                        .map_or(format!("the end of the {end}"), |token| {
+                            format!("`{token}`")
+                        })
+                };
+                write!(
+                    f,
+                    "The output does not have the same tokens as the input: expected {}, found {}.\nThe query may have deleted or altered a token, so nothing was written.",
+                    token(expected, "input"),
+                    token(found, "output")
+                )
+            }
+
+            Self::TooDeep { max_depth, .. } => {
+                write!(
+                    f,
+                    "The input is nested too deeply: its syntax tree is deeper than the maximum of {max_depth}"
+                )
+            }
+
+            Self::Warnings(warnings) => {
+                write!(
+                    f,
+                    "Formatting raised the following warnings, which are denied:\n{}",
+                    warnings.join("\n")
+                )
+            }
+
             Self::Internal(message, _)
-            | Self::Query(message, _)
+            | Self::Query { message, .. }
             | Self::Io(IoError::Filesystem(message, _) | IoError::Generic(message, _)) => {
                 write!(f, "{message}")
             }
@@ -100,12 +197,17 @@ impl fmt::Display for FormatterError {
 impl Error for FormatterError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
-            Self::Idempotence
+            Self::GrammarVersion { .. }
+            | Self::Idempotence
+            | Self::InvalidRange { .. }
             | Self::Parsing { .. }
             | Self::PatternDoesNotMatch(_)
+            | Self::TokenMismatch { .. }
+            | Self::TooDeep { .. }
+            | Self::Warnings(_)
             | Self::Io(IoError::Generic(_, None)) => None,
             Self::Internal(_, source) => source.as_ref().map(Deref::deref),
-            Self::Query(_, source) => source.as_ref().map(|e| e as &dyn Error),
+            Self::Query { source, .. } => source.as_ref().map(|e| e as &dyn Error),
             Self::Io(IoError::Filesystem(_, source)) => Some(source),
             Self::Io(IoError::Generic(_, Some(source))) => Some(source.as_ref()),
             Self::IdempotenceParsing(source) => Some(source),
@@ -113,6 +215,48 @@ impl Error for FormatterError {
     }
 }
 
+impl FormatterError {
+    /// An error in the query, with the given message, raised by neither
+    /// tree-sitter nor a match of the query.
+    pub(crate) fn query(message: impl Into<String>) -> Self {
+        Self::Query {
+            message: message.into(),
+            source: None,
+            span: None,
+        }
+    }
+
+    /// Gives a query error the span of the input it was raised for, unless it
+    /// already has one. Other errors are returned as they are.
+    pub(crate) fn with_span(self, span: Range<usize>) -> Self {
+        match self {
+            Self::Query {
+                message,
+                source,
+                span: None,
+            } => Self::Query {
+                message,
+                source,
+                span: Some(span),
+            },
+            error => error,
+        }
+    }
+
+    /// The byte offsets of the part of the input the error relates to, if
+    /// known.
+    pub fn span(&self) -> Option<Range<usize>> {
+        match self {
+            Self::InvalidRange { range, .. } => Some(range.clone()),
+            Self::Parsing { span, .. }
+            | Self::Query { span, .. }
+            | Self::TokenMismatch { span, .. }
+            | Self::TooDeep { span, .. } => span.clone(),
+            _ => None,
+        }
+    }
+}
+
 // NOTE: Filesystem-based IO errors _ought_ to become a thing of the past, once
 // the library and binary code have been completely separated (see Issue #303).
 impl From<io::Error> for FormatterError {
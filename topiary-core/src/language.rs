@@ -1,6 +1,9 @@
 use std::fmt;
 
-use crate::TopiaryQuery;
+use crate::{
+    pretty::RenderOptions, tree_sitter::CaptureSettings, FormatOptions, PostProcessPass,
+    TopiaryQuery,
+};
 
 /// A Language contains all the information Topiary requires to format that
 /// specific languages.
@@ -19,6 +22,61 @@ pub struct Language {
     /// if not provided. Any string can be provided, but in most instances will be
     /// some whitespace: "  ", "    ", or "\t".
     pub indent: Option<String>,
+    /// The width of a tab stop, used to compute the columns of the output,
+    /// when aligning positions or wrapping strings, and to expand tabs if
+    /// `expand_tabs` is set. It is usually 8.
+    pub tab_width: usize,
+    /// Whether tabs at the start of each line of the input are expanded to
+    /// spaces, with a tab stop every `tab_width` columns, before formatting,
+    /// except where they are part of a token (e.g., within a multi-line string
+    /// literal).
+    pub expand_tabs: bool,
+    /// The string the output ends with, replacing the final newline. Defaults
+    /// to "\n" if not provided; an empty string means no final newline. Empty
+    /// output is never terminated.
+    pub final_newline: Option<String>,
+    /// Whether the indentation Topiary outputs at the start of each line is
+    /// rewritten in the unit of `indent`: tabs, with spaces for any remainder,
+    /// if it is made of tabs, and spaces otherwise. Whitespace within tokens,
+    /// such as multi-line string literals, is left as is.
+    pub normalise_indentation: bool,
+    /// A trailer, such as a comment recording that the file is generated,
+    /// that ends the output, on lines of its own before the final newline. An
+    /// input that already ends with it is formatted without it, so that it is
+    /// not repeated. Empty output has no trailer.
+    pub file_trailer: Option<String>,
+    /// The order in which to run the post-processing passes on the atoms.
+    /// Passes that are left out run afterwards, in their default order.
+    /// Defaults to `PostProcessPass::DEFAULT_ORDER` if not provided.
+    pub post_process: Option<Vec<PostProcessPass>>,
+    /// The kinds of the nodes that are comments, which are treated specially,
+    /// e.g. by `@space_before_comment`, or when children are sorted. Defaults
+    /// to every kind whose name contains "comment" if not provided.
+    pub comment_kinds: Option<Vec<String>>,
+}
+
+impl Language {
+    /// The settings to apply the query of this language with, for the given
+    /// options.
+    pub(crate) fn capture_settings(&self, options: FormatOptions) -> CaptureSettings<'_> {
+        CaptureSettings {
+            named_only: options.named_only,
+            max_line_width: options.max_line_width,
+            comment_kinds: self.comment_kinds.as_deref(),
+            ..CaptureSettings::default()
+        }
+    }
+
+    /// The options to render the atoms of this language with.
+    pub(crate) fn render_options(&self) -> RenderOptions<'_> {
+        RenderOptions {
+            // Default to "  " is the language has no indentation specified
+            indent: self.indent.as_deref().unwrap_or("  "),
+            tab_width: self.tab_width,
+            normalise_indentation: self.normalise_indentation,
+            record_depths: false,
+        }
+    }
 }
 
 impl fmt::Display for Language {
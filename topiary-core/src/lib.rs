@@ -7,26 +7,42 @@
 //! available, and there must be a query file that dictates how that language is
 //! to be formatted. We include query files for some languages.
 //!
+//! Formatting is entirely synchronous: [`formatter`] blocks until the output
+//! has been written, and does not require an async runtime. On native targets,
+//! grammars can be loaded synchronously too (e.g., with `topiary-config`'s
+//! `Language::grammar`).
+//!
 //! More details can be found on
 //! [GitHub](https://github.com/tweag/topiary).
 
-use std::io;
+use std::{collections::HashSet, io, ops::Range};
 
 use itertools::Itertools;
 use pretty_assertions::StrComparison;
-use tree_sitter::Position;
+use topiary_tree_sitter_facade::{InputEdit, Node, Tree};
+
+use crate::{timings::Stopwatch, tree_sitter::CaptureSettings};
 
 pub use crate::{
+    ansi::AnsiRenderer,
+    atom_collection::{AtomCollection, PostProcessPass},
     error::{FormatterError, IoError},
     language::Language,
-    tree_sitter::{apply_query, SyntaxNode, TopiaryQuery, Visualisation},
+    pretty::{RenderOptions, Rendered},
+    timings::Timings,
+    tree_sitter::{
+        apply_query, apply_query_with_provenance, KindSpacing, Position, SyntaxNode, TopiaryQuery,
+        Visualisation, MAX_GRAMMAR_VERSION, MIN_GRAMMAR_VERSION,
+    },
 };
 
+mod ansi;
 mod atom_collection;
 mod error;
 mod graphviz;
 mod language;
 mod pretty;
+mod timings;
 mod tree_sitter;
 
 #[doc(hidden)]
@@ -38,6 +54,48 @@ pub struct ScopeInformation {
     scope_id: String,
 }
 
+impl ScopeInformation {
+    /// Describes the boundary of the scope named `scope_id` lying on the
+    /// (0-based) line `line_number`, for use in `Atom::ScopeBegin` and
+    /// `Atom::ScopeEnd`.
+    pub fn new(line_number: u32, scope_id: impl Into<String>) -> Self {
+        Self {
+            line_number,
+            scope_id: scope_id.into(),
+        }
+    }
+}
+
+/// How a string leaf marked with `@wrap_string` is split when rendered.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StringWrap {
+    /// The column the rendered string should not go beyond.
+    max_width: usize,
+    /// The literal inserted at each split, e.g. `"\" +\n\""`. Newlines in it
+    /// are followed by the current indentation.
+    continuation: String,
+}
+
+/// How the lines of an indentation block are indented, relative to those
+/// around it.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum IndentKind {
+    /// By the indentation string of the language.
+    #[default]
+    Default,
+    /// By the given number of spaces.
+    Width(usize),
+    /// By the given literal, such as `"│ "`. Nested blocks thus draw a guide
+    /// at each level.
+    Guide(String),
+    /// One level further than the column at which the leaf with the given id
+    /// starts, whatever the current indentation.
+    From(usize),
+    /// To the column at which the leaf with the given id starts, whatever the
+    /// current indentation.
+    At(usize),
+}
+
 /// An atom represents a small piece of the output. We turn Tree-sitter nodes
 /// into atoms, and we add white-space atoms where appropriate. The final list
 /// of atoms is rendered to the output.
@@ -54,10 +112,24 @@ pub enum Atom {
     /// Signals the end of an indentation block.
     IndentEnd,
     /// Signals the start of an indentation block. Any lines between the
-    /// beginning and the end will be indented. In single-line constructs where
-    /// the beginning and the end occurs on the same line, there will be no
-    /// indentation.
-    IndentStart,
+    /// beginning and the end will be indented, by the given kind of
+    /// indentation. In single-line constructs where the beginning and the end
+    /// occurs on the same line, there will be no indentation.
+    IndentStart(IndentKind),
+    /// Marks a position that is padded with spaces, so that it lines up with
+    /// the other such positions of the same rank on their lines, in its block
+    /// of lines: the first position of a line lines up with the first ones of
+    /// the other lines, the second with the second ones, and so on. A block
+    /// ends at a blank line or a change of indentation. Positions at the start
+    /// of a line are not aligned.
+    Align,
+    /// Marks the start of a leaf that is padded on the left with spaces, so
+    /// that its end lines up with those of the other such leaves in its block
+    /// of lines. Unlike `Align`, it also applies at the start of a line.
+    AlignRight,
+    /// Represents a copy of the indentation string configured for the
+    /// language, output as a literal regardless of the indentation level.
+    IndentLiteral,
     /// Represents the contents of a named Tree-sitter node. We track the node id here
     /// as well.
     Leaf {
@@ -68,6 +140,10 @@ pub enum Atom {
         single_line_no_indent: bool,
         // if the leaf is multi-line, each line will be indented, not just the first
         multi_line_indent_all: bool,
+        // if set, the leaf is split at word boundaries when it exceeds the width
+        wrap_string: Option<StringWrap>,
+        // the leaf is output exactly as it is in the input, whitespace included
+        verbatim: bool,
     },
     /// Represents a literal string, such as a semicolon.
     Literal(String),
@@ -81,11 +157,19 @@ pub enum Atom {
     /// Represents the destruction of errant spaces. Adjacent consecutive spaces are
     /// reduced to zero before rendering.
     Antispace,
+    /// Prevents a line break between the surrounding leaves or literals: the
+    /// hardlines and blank lines between them are removed before rendering,
+    /// spaces are kept.
+    Glue,
     /// Represents a segment to be deleted.
     // It is a segment, because if one wants to delete a node,
     // it might happen that it contains several leaves.
     DeleteBegin,
     DeleteEnd,
+    /// Represents a segment in which two consecutive hardlines are turned into
+    /// a blank line, rather than merged into a single hardline.
+    HardlinePairsBegin,
+    HardlinePairsEnd,
     /// Indicates the beginning of a scope, use in combination with the
     /// ScopedSoftlines and ScopedConditionals below.
     ScopeBegin(ScopeInformation),
@@ -115,15 +199,25 @@ pub enum Atom {
 }
 
 impl Atom {
-    /// This function is only expected to take spaces and newlines as argument.
-    /// It defines the order Blankline > Hardline > Space > Empty.
+    /// The id carried by leaves and scoped atoms, if any.
+    pub fn id(&self) -> Option<usize> {
+        match self {
+            Atom::Leaf { id, .. }
+            | Atom::ScopedSoftline { id, .. }
+            | Atom::ScopedConditional { id, .. } => Some(*id),
+            _ => None,
+        }
+    }
+
+    /// This function defines the order Blankline > Hardline > Space > Empty.
+    /// Any other atom neither dominates nor is dominated.
     pub(crate) fn dominates(&self, other: &Atom) -> bool {
         match self {
             Atom::Empty => false,
             Atom::Space => matches!(other, Atom::Empty),
             Atom::Hardline => matches!(other, Atom::Space | Atom::Empty),
             Atom::Blankline => matches!(other, Atom::Hardline | Atom::Space | Atom::Empty),
-            _ => panic!("Unexpected character in is_dominant"),
+            _ => false,
         }
     }
 }
@@ -136,24 +230,55 @@ pub enum ScopeCondition {
     SingleLineOnly,
     /// The Atom is only applied if the matching node spans two or more lines
     MultiLineOnly,
+    /// The Atom is only applied if the scope is the innermost one around it,
+    /// whatever the ids of the other scopes
+    InnermostOnly,
 }
 
 /// A convenience wrapper around `std::result::Result<T, FormatterError>`.
 pub type FormatterResult<T> = std::result::Result<T, FormatterError>;
 
+/// The options of `Operation::Format`. All of them are off by default.
+///
+/// New options may be added, so this cannot be built with a struct expression
+/// outside of this crate; start from `FormatOptions::default()` instead.
+#[derive(Clone, Copy, Debug, Default)]
+#[non_exhaustive]
+pub struct FormatOptions {
+    /// If true, skips the idempotence check (where we format twice,
+    /// succeeding only if the intermediate and final result are identical)
+    pub skip_idempotence: bool,
+    /// If true, Topiary will consider an ERROR as it does a leaf node,
+    /// and continues formatting instead of exiting with an error
+    pub tolerate_parsing_errors: bool,
+    /// If true, any warning raised while formatting, such as a scope that
+    /// was not closed, is turned into an error
+    pub deny_warnings: bool,
+    /// If true, captures on anonymous nodes (e.g., punctuation) are
+    /// ignored, so that only named nodes drive the formatting. This is
+    /// mostly useful to experiment with queries
+    pub named_only: bool,
+    /// If set, the width every width-sensitive capture (e.g.,
+    /// `@wrap_string`) works with, instead of the `#max_width!` of the
+    /// query. This lets the width vary per call, as with an editor's ruler
+    pub max_line_width: Option<usize>,
+    /// If true, the output is parsed again and must have the same tokens
+    /// as the input, whitespace aside; otherwise nothing is written, and
+    /// the first tokens that differ are reported. This guards against
+    /// queries that delete or alter tokens
+    pub verify_tokens: bool,
+    /// If set, the depth of the syntax tree of the input beyond which
+    /// formatting fails, rather than doing unbounded work on deeply
+    /// nested, possibly untrusted, input
+    pub max_depth: Option<usize>,
+}
+
 /// Operations that can be performed by the formatter.
 #[derive(Clone, Copy, Debug)]
 pub enum Operation {
     /// Formatting is the default operation of the formatter, it applies the
     /// formatting rules defined in the query file and outputs the result
-    Format {
-        /// If true, skips the idempotence check (where we format twice,
-        /// succeeding only if the intermediate and final result are identical)
-        skip_idempotence: bool,
-        /// If true, Topiary will consider an ERROR as it does a leaf node,
-        /// and continues formatting instead of exiting with an error
-        tolerate_parsing_errors: bool,
-    },
+    Format(FormatOptions),
     /// Visualises the parsed file's tree-sitter tree
     Visualise {
         /// Choose the type of visualation Topiary should ouput
@@ -163,6 +288,10 @@ pub enum Operation {
 
 /// The function that takes an input and formats, or visualises an output.
 ///
+/// The input is read to its end once, into memory, before anything else is
+/// done; every later pass, such as the idempotence check, works on that copy.
+/// It therefore need not be seekable, and can be a pipe.
+///
 /// # Errors
 ///
 /// If formatting fails for any reason, a `FormatterError` will be returned.
@@ -170,10 +299,9 @@ pub enum Operation {
 /// # Examples
 ///
 /// ```
-/// # tokio_test::block_on(async {
 /// use std::fs::File;
 /// use std::io::{BufReader, Read};
-/// use topiary_core::{formatter, Language, FormatterError, TopiaryQuery, Operation};
+/// use topiary_core::{formatter, FormatOptions, Language, FormatterError, TopiaryQuery, Operation};
 ///
 /// let input = "[1,2]".to_string();
 /// let mut input = input.as_bytes();
@@ -189,20 +317,26 @@ pub enum Operation {
 ///     query: TopiaryQuery::new(&json.clone().into(), &query_content).unwrap(),
 ///     grammar: json.into(),
 ///     indent: None,
+///     tab_width: 8,
+///     expand_tabs: false,
+///     final_newline: None,
+///     normalise_indentation: false,
+///     file_trailer: None,
+///     post_process: None,
+///     comment_kinds: None,
 /// };
 ///
-/// match formatter(&mut input, &mut output, &language, Operation::Format{ skip_idempotence: false, tolerate_parsing_errors: false }) {
+/// match formatter(&mut input, &mut output, &language, Operation::Format(FormatOptions::default())) {
 ///   Ok(()) => {
 ///     let formatted = String::from_utf8(output).expect("valid utf-8");
 ///   }
-///   Err(FormatterError::Query(message, _)) => {
+///   Err(FormatterError::Query { message, .. }) => {
 ///     panic!("Error in query file: {message}");
 ///   }
 ///   Err(_) => {
 ///     panic!("An error occurred");
 ///   }
 /// }
-/// # }) // end tokio_test
 /// ```
 pub fn formatter(
     input: &mut impl io::Read,
@@ -210,6 +344,254 @@ pub fn formatter(
     language: &Language,
     operation: Operation,
 ) -> FormatterResult<()> {
+    formatter_incremental(input, output, language, operation, None).map(|_| ())
+}
+
+/// A convenience wrapper around `formatter`, taking the input as a string and
+/// returning the output as a string, rather than using readers and writers.
+///
+/// The language is given ready-built, with its grammar and query: this crate
+/// does not depend on `topiary-config`, so it cannot resolve them from a
+/// `Configuration` by name.
+///
+/// # Errors
+///
+/// If formatting fails for any reason, a `FormatterError` will be returned.
+///
+/// # Examples
+///
+/// ```
+/// use topiary_core::{formatter_to_string, FormatOptions, Language, TopiaryQuery, Operation};
+///
+/// let json = tree_sitter_json::language();
+/// let query_content = std::fs::read_to_string("../topiary-queries/queries/json.scm").unwrap();
+///
+/// let language: Language = Language {
+///     name: "json".to_owned(),
+///     query: TopiaryQuery::new(&json.clone().into(), &query_content).unwrap(),
+///     grammar: json.into(),
+///     indent: None,
+///     tab_width: 8,
+///     expand_tabs: false,
+///     final_newline: None,
+///     normalise_indentation: false,
+///     file_trailer: None,
+///     post_process: None,
+///     comment_kinds: None,
+/// };
+///
+/// let mut options = FormatOptions::default();
+/// options.verify_tokens = true;
+/// let operation = Operation::Format(options);
+///
+/// assert_eq!(formatter_to_string("[1,2]", &language, operation).unwrap(), "[ 1, 2 ]\n");
+/// ```
+pub fn formatter_to_string(
+    input: &str,
+    language: &Language,
+    operation: Operation,
+) -> FormatterResult<String> {
+    let mut output = Vec::new();
+    formatter(&mut input.as_bytes(), &mut output, language, operation)?;
+
+    Ok(String::from_utf8(output)?)
+}
+
+/// Like `formatter`, but reuses the parse tree of a previous version of the
+/// input, given along with the edit that turned it into the current input.
+/// This is meant for editors, which format the same input repeatedly, after
+/// small edits. The parse tree of the input is returned, so that it can be
+/// passed to the next call.
+///
+/// For now, only parsing is incremental: the query is applied to the whole
+/// input. The previous tree is not used if the edit spans more than half of the
+/// input, or if the language expands leading tabs (as the input is modified
+/// before parsing).
+///
+/// # Errors
+///
+/// If formatting fails for any reason, a `FormatterError` will be returned.
+pub fn formatter_incremental(
+    input: &mut impl io::Read,
+    output: &mut impl io::Write,
+    language: &Language,
+    operation: Operation,
+    previous: Option<(Tree, &InputEdit)>,
+) -> FormatterResult<Tree> {
+    format_and_time(
+        input,
+        output,
+        language,
+        &[],
+        operation,
+        previous,
+        Recording::Nothing,
+    )
+    .map(|formatted| formatted.tree)
+}
+
+/// Like `formatter`, but also returns the time taken by each stage of
+/// formatting, to find out where to look when formatting is slow. The
+/// idempotence check is not included. With `Operation::Visualise`, all the
+/// durations are zero.
+///
+/// # Errors
+///
+/// If formatting fails for any reason, a `FormatterError` will be returned.
+pub fn formatter_with_timings(
+    input: &mut impl io::Read,
+    output: &mut impl io::Write,
+    language: &Language,
+    operation: Operation,
+) -> FormatterResult<Timings> {
+    format_and_time(
+        input,
+        output,
+        language,
+        &[],
+        operation,
+        None,
+        Recording::Timings,
+    )
+    .map(|formatted| formatted.timings)
+}
+
+/// Like `formatter`, but also returns the indentation depth of each line of
+/// the output, that is the number of indentation blocks open at its start, for
+/// tools that measure nesting. Lines that continue a multi-line token have the
+/// depth of the line before them. With `Operation::Visualise`, no depths are
+/// returned.
+///
+/// # Errors
+///
+/// If formatting fails for any reason, a `FormatterError` will be returned.
+pub fn formatter_with_indent_depths(
+    input: &mut impl io::Read,
+    output: &mut impl io::Write,
+    language: &Language,
+    operation: Operation,
+) -> FormatterResult<Vec<usize>> {
+    format_and_time(
+        input,
+        output,
+        language,
+        &[],
+        operation,
+        None,
+        Recording::IndentDepths,
+    )
+    .map(|formatted| formatted.depths.unwrap_or_default())
+}
+
+/// Like `formatter`, but also formats the content of the leaves marked with
+/// `@embedded`, such as SQL queries in string literals, in the language named
+/// by their `#embedded_language!` predicate. That language is looked up by
+/// name among `embedded`; if it is missing, a warning is raised and the leaf
+/// is left as it is.
+///
+/// # Errors
+///
+/// If formatting fails for any reason, a `FormatterError` will be returned.
+pub fn formatter_with_embedded_languages(
+    input: &mut impl io::Read,
+    output: &mut impl io::Write,
+    language: &Language,
+    embedded: &[&Language],
+    operation: Operation,
+) -> FormatterResult<()> {
+    format_and_time(
+        input,
+        output,
+        language,
+        embedded,
+        operation,
+        None,
+        Recording::Nothing,
+    )
+    .map(|_| ())
+}
+
+/// What `formatter_reporting_changes` reports about the formatting of its
+/// input.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FormatReport {
+    /// Whether the output differs from the input
+    pub changed: bool,
+    /// The warnings raised while formatting, unless they were denied, in which
+    /// case formatting fails instead
+    pub warnings: Vec<String>,
+}
+
+/// Like `formatter_with_embedded_languages`, but also reports whether the
+/// output differs from the input, which saves callers such as commit hooks
+/// from comparing them, and the warnings that were raised.
+///
+/// # Errors
+///
+/// If formatting fails for any reason, a `FormatterError` will be returned.
+pub fn formatter_reporting_changes(
+    input: &mut impl io::Read,
+    output: &mut impl io::Write,
+    language: &Language,
+    embedded: &[&Language],
+    operation: Operation,
+) -> FormatterResult<FormatReport> {
+    let content = read_input(input).map_err(|e| {
+        FormatterError::Io(IoError::Filesystem(
+            "Failed to read input contents".into(),
+            e,
+        ))
+    })?;
+
+    let mut formatted = Vec::new();
+    let warnings = format_and_time(
+        &mut content.as_bytes(),
+        &mut formatted,
+        language,
+        embedded,
+        operation,
+        None,
+        Recording::Nothing,
+    )?
+    .warnings;
+    output.write_all(&formatted)?;
+
+    Ok(FormatReport {
+        changed: formatted != content.as_bytes(),
+        warnings,
+    })
+}
+
+/// What `format_and_time` records, besides formatting its input.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Recording {
+    Nothing,
+    /// The time taken by each stage of formatting
+    Timings,
+    /// The indentation depth of each line of the output
+    IndentDepths,
+}
+
+/// What `format_and_time` returns, besides the output it writes.
+struct Formatted {
+    /// The parse tree of the input
+    tree: Tree,
+    timings: Timings,
+    /// With `Recording::IndentDepths`, the depth of each line of the output
+    depths: Option<Vec<usize>>,
+    /// The warnings raised while formatting
+    warnings: Vec<String>,
+}
+
+fn format_and_time(
+    input: &mut impl io::Read,
+    output: &mut impl io::Write,
+    language: &Language,
+    embedded: &[&Language],
+    operation: Operation,
+    previous: Option<(Tree, &InputEdit)>,
+    recording: Recording,
+) -> FormatterResult<Formatted> {
     let content = read_input(input).map_err(|e| {
         FormatterError::Io(IoError::Filesystem(
             "Failed to read input contents".into(),
@@ -218,54 +600,400 @@ pub fn formatter(
     })?;
 
     match operation {
-        Operation::Format {
-            skip_idempotence,
-            tolerate_parsing_errors,
-        } => {
+        Operation::Format(options) => {
+            let FormatOptions {
+                skip_idempotence,
+                tolerate_parsing_errors,
+                deny_warnings,
+                verify_tokens,
+                ..
+            } = options;
+
+            // The file trailer, if the input already ends with it, is added
+            // back once the rest is formatted
+            let content = match &language.file_trailer {
+                Some(trailer) => strip_file_trailer(content, trailer),
+                None => content,
+            };
+
+            // Expand leading tabs, so that column positions are consistent
+            let content = if language.expand_tabs {
+                tree_sitter::expand_leading_tabs(
+                    &content,
+                    &language.grammar,
+                    language.tab_width,
+                    language.comment_kinds.as_deref(),
+                    tolerate_parsing_errors,
+                )?
+            } else {
+                content
+            };
+
+            // Expanding tabs changes the input, so the previous tree no longer matches it
+            let previous = previous.filter(|_| !language.expand_tabs);
+            let tree = tree_sitter::reparse(
+                &content,
+                &language.grammar,
+                previous,
+                tolerate_parsing_errors,
+            )?;
+            tree_sitter::check_depth(&tree, options.max_depth)?;
+
             // All the work related to tree-sitter and the query is done here
             log::info!("Apply Tree-sitter query");
 
-            let mut atoms = tree_sitter::apply_query(
+            let mut atoms = tree_sitter::apply_query_to_tree(
                 &content,
+                &tree,
                 &language.query,
                 &language.grammar,
-                tolerate_parsing_errors,
-                false,
+                CaptureSettings {
+                    record_timings: recording == Recording::Timings,
+                    ..language.capture_settings(options)
+                },
             )?;
 
+            // Tabs that were not expanded above are reported
+            if let Some(line) = tree_sitter::detect_mixed_indentation(
+                &content,
+                &tree,
+                language.comment_kinds.as_deref(),
+            ) {
+                atoms.add_warning(format!(
+                    "Mixed tabs and spaces in indentation from line {line}; set expand_tabs to normalise it"
+                ));
+            }
+
+            // Leaves holding content in another language are formatted in it
+            format_embedded(
+                &mut atoms,
+                embedded,
+                Operation::Format(FormatOptions {
+                    skip_idempotence: true,
+                    verify_tokens: false,
+                    ..options
+                }),
+            );
+
             // Various post-processing of whitespace
-            atoms.post_process();
+            atoms.post_process_with(
+                language
+                    .post_process
+                    .as_deref()
+                    .unwrap_or(PostProcessPass::DEFAULT_ORDER),
+            );
+
+            if deny_warnings && !atoms.warnings().is_empty() {
+                return Err(FormatterError::Warnings(atoms.warnings().to_vec()));
+            }
 
             // Pretty-print atoms
             log::info!("Pretty-print output");
-            let rendered = pretty::render(
+            let stopwatch = Stopwatch::start(recording == Recording::Timings);
+            let Rendered {
+                output: rendered,
+                verbatim_lines,
+                depths,
+            } = pretty::render(
                 &atoms[..],
-                // Default to "  " is the language has no indentation specified
-                language.indent.as_ref().map_or("  ", |v| v.as_str()),
+                RenderOptions {
+                    record_depths: recording == Recording::IndentDepths,
+                    ..language.render_options()
+                },
             )?;
-            let trimmed = trim_whitespace(&rendered);
+            let timings = Timings {
+                render: stopwatch.elapsed(),
+                ..atoms.timings().clone()
+            };
+            log::debug!("Timings: {timings:?}");
+            // Default to "\n" if the language has no final newline specified
+            let final_newline = language.final_newline.as_deref().unwrap_or("\n");
+            let trimmed = trim_whitespace(&rendered, final_newline, &verbatim_lines);
+
+            if verify_tokens {
+                tree_sitter::verify_tokens(&content, &tree, &trimmed, &language.grammar)?;
+            }
+
+            let trimmed = match &language.file_trailer {
+                Some(trailer) => add_file_trailer(&trimmed, trailer, final_newline),
+                None => trimmed,
+            };
 
             if !skip_idempotence {
-                idempotence_check(&trimmed, language, tolerate_parsing_errors)?;
+                idempotence_check(&trimmed, options, |operation| {
+                    let mut input = trimmed.as_bytes();
+                    let mut output = io::BufWriter::new(Vec::new());
+                    format_and_time(
+                        &mut input,
+                        &mut output,
+                        language,
+                        embedded,
+                        operation,
+                        None,
+                        Recording::Nothing,
+                    )?;
+                    Ok(String::from_utf8(output.into_inner()?)?)
+                })?;
             }
 
             write!(output, "{trimmed}")?;
+
+            // The depths of the lines removed by trimming are left out, and
+            // those of the file trailer are zero
+            let depths = depths.map(|depths| {
+                let leading = rendered
+                    .lines()
+                    .take_while(|line| line.trim().is_empty())
+                    .count();
+                let mut depths: Vec<usize> = depths
+                    .into_iter()
+                    .skip(leading)
+                    .take(trimmed.lines().count())
+                    .collect();
+                depths.resize(trimmed.lines().count(), 0);
+                depths
+            });
+
+            Ok(Formatted {
+                tree,
+                timings,
+                depths,
+                warnings: atoms.warnings().to_vec(),
+            })
         }
 
         Operation::Visualise { output_format } => {
-            let (tree, _) = tree_sitter::parse(&content, &language.grammar, false)?;
-            let root: SyntaxNode = tree.root_node().into();
+            let tree = tree_sitter::reparse(&content, &language.grammar, previous, false)?;
+            visualise(output, &tree.root_node(), output_format)?;
 
-            match output_format {
-                Visualisation::GraphViz => graphviz::write(output, &root)?,
-                Visualisation::Json => serde_json::to_writer(output, &root)?,
-            };
+            Ok(Formatted {
+                tree,
+                timings: Timings::default(),
+                depths: None,
+                warnings: Vec::new(),
+            })
+        }
+    }
+}
+
+/// Formats only the smallest named node enclosing the byte `range` of the
+/// input, and returns the whole input, in which the rest is kept verbatim.
+/// This is meant for editors, to format a selection. With
+/// `Operation::Visualise`, the tree of that node is returned instead.
+///
+/// The lines of the node after its first one are indented like the line on
+/// which it starts. Leading tabs are not expanded, even if the language sets a
+/// tab width, as that would move the range.
+///
+/// # Errors
+///
+/// If the range is not within the input, or if formatting fails for any
+/// reason, a `FormatterError` will be returned.
+pub fn format_node_in_range(
+    input: &str,
+    range: Range<usize>,
+    language: &Language,
+    operation: Operation,
+) -> FormatterResult<String> {
+    if range.start > range.end || range.end > input.len() {
+        return Err(FormatterError::InvalidRange {
+            range,
+            length: input.len(),
+        });
+    }
+
+    let tolerate_parsing_errors = match operation {
+        Operation::Format(options) => options.tolerate_parsing_errors,
+        Operation::Visualise { .. } => false,
+    };
+    let tree = tree_sitter::reparse(input, &language.grammar, None, tolerate_parsing_errors)?;
+    let root = tree.root_node();
+    let node = root
+        .named_descendant_for_byte_range(range.start as u32, range.end as u32)
+        .unwrap_or(root);
+
+    let options = match operation {
+        Operation::Format(options) => options,
+        Operation::Visualise { output_format } => {
+            let mut output = Vec::new();
+            visualise(&mut output, &node, output_format)?;
+            return Ok(String::from_utf8(output)?);
         }
     };
+    tree_sitter::check_depth(&tree, options.max_depth)?;
+
+    let mut atoms = tree_sitter::apply_query_to_tree(
+        input,
+        &tree,
+        &language.query,
+        &language.grammar,
+        language.capture_settings(options),
+    )?;
+    atoms.post_process_with(
+        language
+            .post_process
+            .as_deref()
+            .unwrap_or(PostProcessPass::DEFAULT_ORDER),
+    );
+
+    if options.deny_warnings && !atoms.warnings().is_empty() {
+        return Err(FormatterError::Warnings(atoms.warnings().to_vec()));
+    }
+
+    // The atoms of the node are those from its first leaf to its last one
+    let mut ids = HashSet::new();
+    collect_descendant_ids(&node, &mut ids);
+    let is_inside = |atom: &Atom| matches!(atom, Atom::Leaf { id, .. } if ids.contains(id));
+    let atoms = &atoms[..];
+    let (Some(first), Some(last)) = (
+        atoms.iter().position(is_inside),
+        atoms.iter().rposition(is_inside),
+    ) else {
+        // Nothing of the node is output, e.g. as it is deleted
+        return Ok(input.to_owned());
+    };
+
+    // Indentation blocks opened before the node are closed within it, but
+    // the node is rendered from the outermost level
+    let mut depth = 0usize;
+    let node_atoms: Vec<Atom> = atoms[first..=last]
+        .iter()
+        .map(|atom| match atom {
+            Atom::IndentStart(_) => {
+                depth += 1;
+                atom.clone()
+            }
+            Atom::IndentEnd if depth == 0 => Atom::Empty,
+            Atom::IndentEnd => {
+                depth -= 1;
+                atom.clone()
+            }
+            _ => atom.clone(),
+        })
+        .collect();
+
+    let Rendered {
+        output: rendered,
+        verbatim_lines,
+        ..
+    } = pretty::render(&node_atoms, language.render_options())?;
+
+    let start = node.start_byte() as usize;
+    let end = node.end_byte() as usize;
+    let line_start = input[..start].rfind('\n').map_or(0, |i| i + 1);
+    let base_indent: String = input[line_start..start]
+        .chars()
+        .take_while(|c| c.is_whitespace())
+        .collect();
+
+    // Lines continuing a verbatim leaf already have their indentation
+    let formatted = rendered
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let line = if verbatim_lines.contains(&i) {
+                line
+            } else {
+                line.trim_end()
+            };
+            if i == 0 || line.is_empty() || verbatim_lines.contains(&(i - 1)) {
+                line.to_owned()
+            } else {
+                format!("{base_indent}{line}")
+            }
+        })
+        .join("\n");
+
+    let output = format!("{}{formatted}{}", &input[..start], &input[end..]);
+
+    if !options.skip_idempotence {
+        idempotence_check(&output, options, |operation| {
+            format_node_in_range(&output, start..start + formatted.len(), language, operation)
+        })?;
+    }
+
+    Ok(output)
+}
+
+/// Writes the syntax tree rooted at `node` in the given format.
+fn visualise(
+    output: &mut impl io::Write,
+    node: &Node,
+    output_format: Visualisation,
+) -> FormatterResult<()> {
+    let node: SyntaxNode = node.clone().into();
+    match output_format {
+        Visualisation::GraphViz => graphviz::write(output, &node)?,
+        Visualisation::Json => serde_json::to_writer(output, &node)?,
+    };
 
     Ok(())
 }
 
+/// Adds the ids of `node` and of all its descendants to `ids`
+fn collect_descendant_ids(node: &Node, ids: &mut HashSet<usize>) {
+    ids.insert(node.id());
+    for child in node.children(&mut node.walk()) {
+        collect_descendant_ids(&child, ids);
+    }
+}
+
+/// Formats the content of the leaves marked with `@embedded` in the language
+/// they name. The quotes delimiting a leaf are kept, escaped quotes and
+/// backslashes are unescaped before formatting and escaped again after, and
+/// the lines after the first are aligned with the character after the opening
+/// quote. Content that cannot be formatted is left as it is, with a warning.
+fn format_embedded(atoms: &mut AtomCollection, embedded: &[&Language], operation: Operation) {
+    for (id, name, column) in atoms.embedded().to_vec() {
+        let Some(language) = embedded.iter().find(|language| language.name == name) else {
+            atoms.add_warning(format!(
+                "No {name} language is available to format embedded content"
+            ));
+            continue;
+        };
+        let Some(content) = atoms.leaf_content(id) else {
+            continue;
+        };
+
+        let mut chars = content.chars();
+        let (Some(quote), Some(close)) = (chars.next(), chars.next_back()) else {
+            continue;
+        };
+        if quote != close || !matches!(quote, '"' | '\'' | '`') {
+            continue;
+        }
+
+        let mut unescaped = String::new();
+        let mut chars = chars.as_str().chars();
+        while let Some(c) = chars.next() {
+            match (c, chars.clone().next()) {
+                ('\\', Some(next)) if next == '\\' || next == quote => {
+                    unescaped.push(next);
+                    chars.next();
+                }
+                _ => unescaped.push(c),
+            }
+        }
+
+        match formatter_to_string(&unescaped, language, operation) {
+            Ok(formatted) => {
+                let indentation = format!("\n{}", " ".repeat(column + 1));
+                let escaped = formatted
+                    .trim_end()
+                    .replace('\\', "\\\\")
+                    .replace(quote, &format!("\\{quote}"))
+                    .lines()
+                    .collect::<Vec<&str>>()
+                    .join(&indentation);
+                atoms.set_leaf_content(id, format!("{quote}{escaped}{quote}"));
+            }
+            Err(error) => {
+                atoms.add_warning(format!("Failed to format embedded {name} content: {error}"))
+            }
+        }
+    }
+}
+
 /// Simple helper function to read the full content of an io Read stream
 fn read_input(input: &mut dyn io::Read) -> Result<String, io::Error> {
     let mut content = String::new();
@@ -273,44 +1001,79 @@ fn read_input(input: &mut dyn io::Read) -> Result<String, io::Error> {
     Ok(content)
 }
 
-/// Trim whitespace from the end of each line,
+/// Trim whitespace from the end of each line, except for the lines ending
+/// within verbatim leaves, whose indices are given by `verbatim_lines`,
 /// then trim any leading/trailing new lines,
-/// finally reinstate the new line at EOF.
-fn trim_whitespace(s: &str) -> String {
-    format!("{}\n", s.lines().map(str::trim_end).join("\n").trim())
+/// finally end the output with `final_newline`, unless it is empty.
+fn trim_whitespace(s: &str, final_newline: &str, verbatim_lines: &HashSet<usize>) -> String {
+    let trimmed = s
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            if verbatim_lines.contains(&i) {
+                line
+            } else {
+                line.trim_end()
+            }
+        })
+        .join("\n");
+    let trimmed = trimmed.trim();
+
+    if trimmed.is_empty() {
+        String::new()
+    } else {
+        format!("{trimmed}{final_newline}")
+    }
+}
+
+/// Remove the given trailer from the end of the input, if it ends with it.
+fn strip_file_trailer(content: String, trailer: &str) -> String {
+    match content.trim_end().strip_suffix(trailer.trim()) {
+        Some(body) if !trailer.trim().is_empty() => body.to_string(),
+        _ => content,
+    }
+}
+
+/// End the output with the given trailer, on lines of its own, before the
+/// final newline, unless the output is empty.
+fn add_file_trailer(s: &str, trailer: &str, final_newline: &str) -> String {
+    let trailer = trailer.trim();
+    let body = s.strip_suffix(final_newline).unwrap_or(s);
+
+    if body.is_empty() || trailer.is_empty() {
+        s.to_string()
+    } else {
+        format!("{body}\n{trailer}{final_newline}")
+    }
 }
 
-/// Perform the idempotence check. Given the already formatted content of the
-/// file, formats the content again and checks if the two are identical.
+/// Perform the idempotence check. Given the already formatted content,
+/// formats it again with `reformat`, with the same options but no further
+/// checks, and checks if the two are identical.
 /// Result in: `Ok(())`` if the idempotence check succeeded (the content is
 /// identical to the formatted content)
 ///
 /// # Errors
 ///
 /// `Err(FormatterError::Idempotence)` if the idempotence check failed
+/// `Err(FormatterError::IdempotenceParsing(...))` if the content could not be
+/// parsed again
 /// `Err(FormatterError::Formatting(...))` if the formatting failed
 fn idempotence_check(
     content: &str,
-    language: &Language,
-    tolerate_parsing_errors: bool,
+    options: FormatOptions,
+    reformat: impl FnOnce(Operation) -> FormatterResult<String>,
 ) -> FormatterResult<()> {
     log::info!("Checking for idempotence ...");
 
-    let mut input = content.as_bytes();
-    let mut output = io::BufWriter::new(Vec::new());
-
-    match formatter(
-        &mut input,
-        &mut output,
-        language,
-        Operation::Format {
-            skip_idempotence: true,
-            tolerate_parsing_errors,
-        },
-    ) {
-        Ok(()) => {
-            let reformatted = String::from_utf8(output.into_inner()?)?;
-
+    match reformat(Operation::Format(FormatOptions {
+        skip_idempotence: true,
+        deny_warnings: false,
+        verify_tokens: false,
+        max_depth: None,
+        ..options
+    })) {
+        Ok(reformatted) => {
             if content == reformatted {
                 Ok(())
             } else {
@@ -328,13 +1091,22 @@ fn idempotence_check(
 
 #[cfg(test)]
 mod tests {
-    use std::fs;
+    use std::{collections::HashMap, fs};
 
     use test_log::test;
 
+    use topiary_tree_sitter_facade::{InputEdit, Point};
+
     use crate::{
-        error::FormatterError, formatter, test_utils::pretty_assert_eq, Language, Operation,
-        TopiaryQuery,
+        apply_query,
+        error::FormatterError,
+        format_node_in_range, formatter, formatter_incremental, formatter_reporting_changes,
+        formatter_to_string, formatter_with_embedded_languages, formatter_with_indent_depths,
+        formatter_with_timings,
+        test_utils::{assert_formats, pretty_assert_eq, test_language},
+        tree_sitter::check_grammar_version,
+        AnsiRenderer, FormatOptions, KindSpacing, Language, Operation, PostProcessPass, Timings,
+        TopiaryQuery, MAX_GRAMMAR_VERSION, MIN_GRAMMAR_VERSION,
     };
 
     /// Attempt to parse invalid json, expecting a failure
@@ -343,26 +1115,21 @@ mod tests {
         let mut input = r#"{"foo":{"bar"}}"#.as_bytes();
         let mut output = Vec::new();
         let query_content = "(#language! json)";
-        let grammar = topiary_tree_sitter_facade::Language::from(tree_sitter_json::language());
-        let language = Language {
-            name: "json".to_owned(),
-            query: TopiaryQuery::new(&grammar, query_content).unwrap(),
-            grammar,
-            indent: None,
-        };
+        let language = test_language("json", tree_sitter_json::language(), query_content);
 
         match formatter(
             &mut input,
             &mut output,
             &language,
-            Operation::Format {
+            Operation::Format(FormatOptions {
                 skip_idempotence: true,
-                tolerate_parsing_errors: false,
-            },
+                ..Default::default()
+            }),
         ) {
             Err(FormatterError::Parsing {
                 start_line: 1,
                 end_line: 1,
+                span: Some(_),
                 ..
             }) => {}
             result => {
@@ -371,6 +1138,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn query_error_spans_the_matched_node() {
+        let language = test_language(
+            "json",
+            tree_sitter_json::language(),
+            "(array (number) @wrap_string)",
+        );
+        let operation = Operation::Format(FormatOptions::default());
+
+        // @wrap_string lacks its #max_width! predicate
+        match formatter_to_string("[1, 23]", &language, operation) {
+            Err(FormatterError::Query {
+                source: None,
+                span: Some(span),
+                ..
+            }) => assert_eq!(span, 1..2),
+            result => panic!("Expected a query error with a span, but got {result:?}"),
+        }
+    }
+
     #[test(tokio::test)]
     async fn tolerate_parsing_errors() {
         // Contains the invalid object {"bar"   "baz"}. It should be left untouched.
@@ -379,22 +1166,17 @@ mod tests {
 
         let mut output = Vec::new();
         let query_content = fs::read_to_string("../topiary-queries/queries/json.scm").unwrap();
-        let grammar = tree_sitter_json::language().into();
-        let language = Language {
-            name: "json".to_owned(),
-            query: TopiaryQuery::new(&grammar, &query_content).unwrap(),
-            grammar,
-            indent: None,
-        };
+        let language = test_language("json", tree_sitter_json::language(), &query_content);
 
         formatter(
             &mut input,
             &mut output,
             &language,
-            Operation::Format {
+            Operation::Format(FormatOptions {
                 skip_idempotence: true,
                 tolerate_parsing_errors: true,
-            },
+                ..Default::default()
+            }),
         )
         .unwrap();
 
@@ -403,4 +1185,2014 @@ mod tests {
 
         pretty_assert_eq(expected, &formatted);
     }
+
+    #[test]
+    fn allow_blank_line_after_keeps_one_leading_blank_line() {
+        let input = "{\n\n\n\"one\": 1, \"two\": 2}";
+        let expected = "{\n\n  \"one\": 1,\n  \"two\": 2\n}\n";
+
+        let query_content = format!(
+            "{}\n\"{{\" @allow_blank_line_after",
+            fs::read_to_string("../topiary-queries/queries/json.scm").unwrap()
+        );
+        let language = test_language("json", tree_sitter_json::language(), &query_content);
+
+        assert_formats(input, expected, &language);
+    }
+
+    #[test]
+    fn format_off_region_is_kept_verbatim() {
+        let input =
+            "{\"a\":1,\n  // topiary: off\n  \"b\":   [1,2,    3],\n  // topiary: on\n\"c\":   2}";
+        let expected =
+            "{\n  \"a\": 1,\n  // topiary: off\n  \"b\":   [1,2,    3],\n  // topiary: on\n  \"c\": 2\n}\n";
+
+        let query_content = format!(
+            "{}\n(comment) @prepend_input_softline @append_hardline",
+            fs::read_to_string("../topiary-queries/queries/json.scm").unwrap()
+        );
+        let language = test_language("json", tree_sitter_json::language(), &query_content);
+
+        assert_formats(input, expected, &language);
+    }
+
+    /// Check that grammars with an unsupported language version are rejected
+    #[test]
+    fn grammar_version_mismatch_is_reported() {
+        let grammar: topiary_tree_sitter_facade::Language = tree_sitter_json::language().into();
+        check_grammar_version(grammar.version()).unwrap();
+
+        match check_grammar_version(MAX_GRAMMAR_VERSION + 1) {
+            Err(
+                err @ FormatterError::GrammarVersion {
+                    version,
+                    min_version,
+                    max_version,
+                },
+            ) => {
+                assert_eq!(version, MAX_GRAMMAR_VERSION + 1);
+                assert_eq!(min_version, MIN_GRAMMAR_VERSION);
+                assert_eq!(max_version, MAX_GRAMMAR_VERSION);
+                assert!(err
+                    .to_string()
+                    .contains(&format!("language version {}", MAX_GRAMMAR_VERSION + 1)));
+            }
+            result => panic!("Expected a grammar version error, got {result:?}"),
+        }
+    }
+
+    /// Format without an async runtime, as a synchronous client would
+    #[test]
+    fn formatting_does_not_require_a_runtime() {
+        let input = "[1,2]";
+        let expected = "[ 1, 2 ]\n";
+
+        let query_content = fs::read_to_string("../topiary-queries/queries/json.scm").unwrap();
+        let language = test_language("json", tree_sitter_json::language(), &query_content);
+
+        assert_formats(input, expected, &language);
+    }
+
+    #[test]
+    fn space_only_between_adjacent_words() {
+        let input = "let x = f ( ) 1 2";
+        let expected = "let x=f()1 2\n";
+
+        let query_content = "_ @append_space_if_adjacent_word";
+        let language = test_language("ocaml", tree_sitter_ocaml::language_ocaml(), query_content);
+
+        assert_formats(input, expected, &language);
+    }
+
+    #[test]
+    fn not_eq_predicate_excludes_matches() {
+        let input = "r.a+r.b*c";
+        let expected = "r.a + r.b * c\n";
+
+        // Space around every binary operator, except member access
+        let query_content = r#"(
+          (_) . _ @prepend_space @append_space . (_)
+          (#not-eq? @prepend_space ".")
+        )"#;
+        let language = test_language("ocaml", tree_sitter_ocaml::language_ocaml(), query_content);
+
+        assert_formats(input, expected, &language);
+    }
+
+    #[test]
+    fn trailing_comments_are_aligned_within_blocks() {
+        let input =
+            "{\n\"a\": 1, // a\n\"bbb\": 22, // b\n\n\"c\": 3, // c\n\"d\": [4, // d\n5, // e\n6]}";
+        let expected = "{\n  \"a\": 1,    // a\n  \"bbb\": 22, // b\n\n  \"c\": 3,  // c\n  \"d\": [4, // d\n    5, // e\n    6]\n}\n";
+
+        let query_content = r#"
+          "{" @append_hardline @append_indent_start
+          "}" @prepend_hardline @prepend_indent_end
+          "[" @append_indent_start
+          "]" @prepend_indent_end
+          ":" @append_space
+          (pair) @allow_blank_line_before
+          (comment) @align_trailing_comment @append_hardline
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        assert_formats(input, expected, &language);
+    }
+
+    #[test]
+    fn named_only_ignores_captures_on_anonymous_nodes() {
+        let query_content = "\",\" @append_space\n\":\" @append_space\n(pair) @prepend_space";
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        for (named_only, expected) in [
+            (false, "{ \"a\": 1, \"b\": 2}\n"),
+            (true, "{ \"a\":1, \"b\":2}\n"),
+        ] {
+            let mut input = r#"{"a":1,"b":2}"#.as_bytes();
+            let mut output = Vec::new();
+
+            formatter(
+                &mut input,
+                &mut output,
+                &language,
+                Operation::Format(FormatOptions {
+                    named_only,
+                    ..Default::default()
+                }),
+            )
+            .unwrap();
+
+            pretty_assert_eq(expected, &String::from_utf8(output).unwrap());
+        }
+    }
+
+    #[test]
+    fn scope_literals_only_wrap_multi_line_scopes() {
+        let query_content = r#"
+          (
+            (object "{" @append_begin_scope "}" @prepend_end_scope)
+            (#scope_id! "object")
+          )
+          (
+            (object "{" @append_scope_open_literal)
+            (#scope_id! "object")
+            (#delimiter! "/*{*/")
+          )
+          (
+            (object "}" @append_scope_close_literal)
+            (#scope_id! "object")
+            (#delimiter! "/*}*/")
+          )
+          (
+            (pair) @prepend_empty_scoped_softline
+            (#scope_id! "object")
+          )
+          ; The markers are added again, so that formatting is idempotent
+          (comment) @delete
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        for (input, expected) in [
+            ("{\"a\":1,\"b\":2}", "{\"a\":1,\"b\":2}\n"),
+            ("{\"a\":1,\n\"b\":2}", "{/*{*/\n\"a\":1,\n\"b\":2/*}*/}\n"),
+        ] {
+            let mut output = Vec::new();
+
+            formatter(
+                &mut input.as_bytes(),
+                &mut output,
+                &language,
+                Operation::Format(FormatOptions {
+                    deny_warnings: true,
+                    ..Default::default()
+                }),
+            )
+            .unwrap();
+
+            pretty_assert_eq(expected, &String::from_utf8(output).unwrap());
+        }
+    }
+
+    #[test]
+    fn formatter_to_string_returns_the_formatted_string() {
+        let query_content = fs::read_to_string("../topiary-queries/queries/json.scm").unwrap();
+        let language = test_language("json", tree_sitter_json::language(), &query_content);
+
+        let formatted = formatter_to_string(
+            "{\"a\":1,\n\"b\":[2,3]}",
+            &language,
+            Operation::Format(FormatOptions::default()),
+        )
+        .unwrap();
+
+        pretty_assert_eq("{\n  \"a\": 1,\n  \"b\": [ 2, 3 ]\n}\n", &formatted);
+    }
+
+    #[test]
+    fn blank_lines_only_kept_in_innermost_scope() {
+        let query_content = r#"
+          ((object) @prepend_begin_scope @append_end_scope (#scope_id! "block"))
+          ((array) @prepend_begin_scope @append_end_scope (#scope_id! "args"))
+          (object "{" @append_hardline @append_indent_start)
+          (object "}" @prepend_hardline @prepend_indent_end)
+          (object "," @append_hardline)
+          (array "," @append_space)
+          ":" @append_space
+          (
+            [(pair) (number)] @allow_blank_line_before
+            (#innermost_scope_only! "block")
+          )
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        let formatted = formatter_to_string(
+            "{\"a\": [1,\n\n2],\n\n\"b\": 3}",
+            &language,
+            Operation::Format(FormatOptions {
+                deny_warnings: true,
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+
+        // The blank line between array elements is removed, but the one
+        // between object members is kept
+        pretty_assert_eq("{\n  \"a\": [1, 2],\n\n  \"b\": 3\n}\n", &formatted);
+    }
+
+    #[test]
+    fn normalise_indentation_rewrites_it_in_the_indent_unit() {
+        let grammar: topiary_tree_sitter_facade::Language = tree_sitter_json::language().into();
+        let query_content = r#"
+            (object "{" @append_hardline @append_indent_start)
+            (object "}" @prepend_hardline @prepend_indent_end)
+            (pair ":" @append_space)
+            (
+              (array
+                "[" @append_hardline @append_indent_start_width
+                "]" @prepend_hardline @prepend_indent_end
+              )
+              (#indent_width! "2")
+            )
+            (array "," @append_hardline)
+        "#;
+        let input = "{\n  \"a\": [1,\n\t2,\n    3]}";
+
+        // The array is indented by two spaces within a tab, which become a
+        // second tab
+        for (normalise_indentation, expected) in [
+            (false, "{\n\t\"a\": [\n\t  1,\n\t  2,\n\t  3\n\t]\n}\n"),
+            (true, "{\n\t\"a\": [\n\t\t1,\n\t\t2,\n\t\t3\n\t]\n}\n"),
+        ] {
+            let language = Language {
+                indent: Some("\t".into()),
+                tab_width: 2,
+                expand_tabs: true,
+                normalise_indentation,
+                ..test_language("json", grammar.clone(), query_content)
+            };
+
+            assert_formats(input, expected, &language);
+        }
+    }
+
+    #[test]
+    fn final_newline_is_configurable() {
+        let query_content = fs::read_to_string("../topiary-queries/queries/json.scm").unwrap();
+        let grammar: topiary_tree_sitter_facade::Language = tree_sitter_json::language().into();
+
+        for (final_newline, expected) in [
+            (None, "[ 1, 2 ]\n"),
+            (Some("\r\n"), "[ 1, 2 ]\r\n"),
+            (Some(""), "[ 1, 2 ]"),
+        ] {
+            let language = Language {
+                final_newline: final_newline.map(str::to_string),
+                ..test_language("json", grammar.clone(), &query_content)
+            };
+
+            assert_formats("[1,2]", expected, &language);
+            // Empty output is not terminated
+            assert_formats("", "", &language);
+        }
+    }
+
+    #[test]
+    fn file_trailer_ends_the_output_exactly_once() {
+        let query_content = fs::read_to_string("../topiary-queries/queries/json.scm").unwrap();
+        let language = Language {
+            file_trailer: Some("// Generated by gen-config".to_string()),
+            ..test_language("json", tree_sitter_json::language(), &query_content)
+        };
+
+        // Formatting the output again, trailer included, does not repeat it
+        assert_formats("[1,2]", "[ 1, 2 ]\n// Generated by gen-config\n", &language);
+        assert_formats(
+            "[ 1, 2 ]\n// Generated by gen-config\n",
+            "[ 1, 2 ]\n// Generated by gen-config\n",
+            &language,
+        );
+        assert_formats("", "", &language);
+    }
+
+    #[test]
+    fn empty_output_has_no_final_newline() {
+        let query_content = fs::read_to_string("../topiary-queries/queries/json.scm").unwrap();
+        let language = test_language("json", tree_sitter_json::language(), &query_content);
+
+        for (input, expected) in [("", ""), (" \n\t\n  ", ""), ("  1 \n", "1\n")] {
+            assert_formats(input, expected, &language);
+        }
+    }
+
+    #[test]
+    fn kind_spacing_applies_without_a_query() {
+        let grammar: topiary_tree_sitter_facade::Language = tree_sitter_json::language().into();
+        let comma = KindSpacing {
+            before: None,
+            after: Some("hardline".to_owned()),
+        };
+        let pair = KindSpacing {
+            before: Some("space".to_owned()),
+            after: None,
+        };
+        let language = Language {
+            name: "json".to_owned(),
+            query: TopiaryQuery::with_spacing(&grammar, "", [(",", &comma), ("pair", &pair)])
+                .unwrap(),
+            grammar: grammar.clone(),
+            indent: None,
+            tab_width: 8,
+            expand_tabs: false,
+            final_newline: None,
+            normalise_indentation: false,
+            file_trailer: None,
+            post_process: None,
+            comment_kinds: None,
+        };
+        let operation = Operation::Format(FormatOptions::default());
+
+        let output = formatter_to_string("{\"a\":1,\"b\":2}", &language, operation).unwrap();
+        pretty_assert_eq("{ \"a\":1,\n\"b\":2}\n", &output);
+
+        let unknown = KindSpacing {
+            before: Some("tab".to_owned()),
+            after: None,
+        };
+        assert!(matches!(
+            TopiaryQuery::with_spacing(&grammar, "", [(",", &unknown)]),
+            Err(FormatterError::Query {
+                source: None,
+                span: None,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn verbatim_nodes_keep_their_whitespace() {
+        let query_content = fs::read_to_string("../topiary-queries/queries/json.scm").unwrap();
+        let language = test_language(
+            "json",
+            tree_sitter_json::language(),
+            &format!("{query_content}\n(array) @verbatim"),
+        );
+
+        assert_formats(
+            "{\"a\":[1,   2,  \n      3 ],\"b\":{\"c\":1}}",
+            "{\n  \"a\": [1,   2,  \n      3 ],\n  \"b\": { \"c\": 1 }\n}\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn hardline_if_parent_over_breaks_only_long_lists() {
+        let query_content = r#"
+            (array "[" @append_hardline_if_parent_over (#child_count! 3))
+            (array "," @append_hardline_if_parent_over (#child_count! 3))
+            (array "," @append_space)
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        assert_formats("[1,2]", "[1, 2]\n", &language);
+        assert_formats("[1,2,3,4,5]", "[\n1,\n2,\n3,\n4,\n5]\n", &language);
+    }
+
+    #[test]
+    fn indent_width_overrides_the_indentation_of_a_block() {
+        let query_content = r#"
+            (object "{" @append_hardline @append_indent_start)
+            (object "}" @prepend_hardline @prepend_indent_end)
+            (array
+              "[" @append_hardline @append_indent_start_width
+              (#indent_width! "4")
+            )
+            (array "]" @prepend_hardline @prepend_indent_end)
+            (pair ":" @append_space)
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        // The object is indented by the default two spaces, and the array by
+        // four more
+        assert_formats("{\"a\":[1]}", "{\n  \"a\": [\n      1\n  ]\n}\n", &language);
+    }
+
+    #[test]
+    fn indent_guide_marks_each_level_of_nested_blocks() {
+        let query_content = r#"
+            (array
+              "[" @append_hardline @append_indent_start_guide
+              (#indent_guide! "│ ")
+            )
+            (array "]" @prepend_hardline @prepend_indent_end)
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+        // The guides are not valid JSON, so the output cannot be parsed again
+        let operation = Operation::Format(FormatOptions {
+            skip_idempotence: true,
+            ..Default::default()
+        });
+
+        pretty_assert_eq(
+            "[\n│ [\n│ │ 1\n│ ]\n]\n",
+            &formatter_to_string("[[1]]", &language, operation).unwrap(),
+        );
+    }
+
+    #[test]
+    fn sort_children_sorts_object_keys() {
+        let query_content = fs::read_to_string("../topiary-queries/queries/json.scm").unwrap();
+        let language = test_language(
+            "json",
+            tree_sitter_json::language(),
+            &format!("{query_content}\n(comment) @append_hardline\n(object) @sort_children"),
+        );
+
+        assert_formats(
+            "{\"c\":3,\"b\":{\"y\":2,\"x\":1},\"a\":2}",
+            "{ \"a\": 2, \"b\": { \"x\": 1, \"y\": 2 }, \"c\": 3 }\n",
+            &language,
+        );
+
+        // A comment on its own line moves with the entry that follows it
+        assert_formats(
+            "{\n  // About c\n  \"c\": 3,\n  \"a\": 1\n}",
+            "{\n  \"a\": 1,\n  // About c\n  \"c\": 3\n}\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn sort_children_moves_attached_comments_with_their_keys() {
+        let query_content = r#"
+            (string) @leaf
+            (object "{" @append_hardline @append_indent_start)
+            (object "}" @prepend_hardline @prepend_indent_end)
+            (object "," @append_space)
+            (object (pair) @prepend_hardline)
+            (pair ":" @append_space)
+            (comment) @prepend_input_softline @append_hardline
+            (object) @sort_children
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        // Leading comments, on their own lines, and trailing comments, after
+        // the separator, move with their keys, while the separator stays
+        assert_formats(
+            "{\n  // About c\n  \"c\": 3, // Trailing c\n  // About a\n  \"a\": 1, // Trailing a\n  \"b\": 2\n}",
+            "{\n  // About a\n  \"a\": 1, // Trailing a\n  \"b\": 2,\n  // About c\n  \"c\": 3 // Trailing c\n}\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn surround_space_leaves_a_single_space_on_each_side() {
+        let query_content = r#"
+            (pair ":" @surround_space)
+            (pair ":" @append_space)
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        assert_formats("{\"a\"    :     1}", "{\"a\" : 1}\n", &language);
+    }
+
+    #[test]
+    fn softline_around_operator_can_leave_the_operator_at_the_end_of_the_line() {
+        let grammar: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let query_content = r#"
+            "let" @append_space
+            "=" @surround_space
+            (infix_expression) @prepend_indent_start @append_indent_end
+            (
+              (infix_expression (add_operator) @softline_around_operator)
+              (#operator_break! "after")
+            )
+        "#;
+        let language = test_language("ocaml", grammar, query_content);
+
+        assert_formats("let x = a\n  + b", "let x = a +\n  b\n", &language);
+        assert_formats("let y = a+b", "let y = a + b\n", &language);
+    }
+
+    #[test]
+    fn method_chain_breaks_only_when_too_wide() {
+        let grammar: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let query_content = r##"
+            "let" @append_space
+            "=" @surround_space
+            (compilation_unit (_) @prepend_hardline)
+            (
+              (method_invocation) @method_chain
+              (#delimiter! "#")
+              (#max_width! "20")
+            )
+        "##;
+        let language = test_language("ocaml", grammar, query_content);
+
+        assert_formats(
+            "let x = window#frame#document#body#first_child\nlet y = a#b#c",
+            "let x = window#frame\n  #document\n  #body\n  #first_child\nlet y = a#b#c\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn inline_keeps_a_short_array_on_one_line() {
+        let query_content = r#"
+            (array "," @append_spaced_softline)
+            (array (array) @inline)
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        // The outer array is multi-line, the inner one is kept inline
+        assert_formats("[[1,\n2],\n3]", "[[1, 2],\n3]\n", &language);
+    }
+
+    #[test]
+    fn expand_arguments_puts_each_argument_on_its_own_line() {
+        let query_content = r#"
+            (array "," @append_space)
+            (array) @expand_arguments
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        // A multi-line array is expanded, one element per line
+        assert_formats("[1, 2,\n3]", "[\n  1,\n  2,\n  3\n]\n", &language);
+        // A single-line array stays inline
+        assert_formats("[1,2,3]", "[1, 2, 3]\n", &language);
+    }
+
+    #[test]
+    fn collapse_if_fits_cascades_from_inner_to_outer_nodes() {
+        let query_content = r#"
+            "[" @append_empty_softline @append_indent_start
+            "]" @prepend_empty_softline @prepend_indent_end
+            "," @append_spaced_softline
+            ((array) @collapse_if_fits (#max_width! "16"))
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        // Once the inner array is collapsed, the outer one fits too
+        assert_formats("[[1,\n2],\n3]", "[[1, 2], 3]\n", &language);
+        // The outer array does not fit, but the inner one still collapses
+        assert_formats(
+            "[[1,\n2],\n\"too long\"]",
+            "[\n  [1, 2],\n  \"too long\"\n]\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn max_line_width_overrides_the_query_width() {
+        let query_content = r#"
+            "[" @append_empty_softline @append_indent_start
+            "]" @prepend_empty_softline @prepend_indent_end
+            "," @append_spaced_softline
+            ((array) @collapse_if_fits (#max_width! "16"))
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+        let operation = |max_line_width| {
+            Operation::Format(FormatOptions {
+                max_line_width: Some(max_line_width),
+                ..Default::default()
+            })
+        };
+        let input = "[1000000, 2000000, 3000000,\n4000000, 5000000, 6000000]";
+
+        pretty_assert_eq(
+            "[\n  1000000,\n  2000000,\n  3000000,\n  4000000,\n  5000000,\n  6000000\n]\n",
+            &formatter_to_string(input, &language, operation(40)).unwrap(),
+        );
+        pretty_assert_eq(
+            "[1000000, 2000000, 3000000, 4000000, 5000000, 6000000]\n",
+            &formatter_to_string(input, &language, operation(100)).unwrap(),
+        );
+    }
+
+    #[test]
+    fn formatter_reporting_changes_compares_output_to_input() {
+        let language = test_language(
+            "json",
+            tree_sitter_json::language(),
+            r#"(array "," @append_space)"#,
+        );
+        let operation = Operation::Format(FormatOptions::default());
+
+        let mut output = Vec::new();
+        let changed = formatter_reporting_changes(
+            &mut "[1, 2]\n".as_bytes(),
+            &mut output,
+            &language,
+            &[],
+            operation,
+        )
+        .unwrap()
+        .changed;
+        assert!(!changed);
+
+        let mut output = Vec::new();
+        let changed = formatter_reporting_changes(
+            &mut "[1,2]".as_bytes(),
+            &mut output,
+            &language,
+            &[],
+            operation,
+        )
+        .unwrap()
+        .changed;
+        assert!(changed);
+        pretty_assert_eq("[1, 2]\n", &String::from_utf8(output).unwrap());
+    }
+
+    #[test]
+    fn formatter_reads_a_non_seekable_input_once() {
+        /// A pipe-like reader, which gives its content a few bytes at a time
+        /// and cannot be read again once exhausted
+        struct Pipe<'a> {
+            content: &'a [u8],
+            exhausted: bool,
+        }
+
+        impl std::io::Read for Pipe<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                assert!(!self.exhausted, "the input was read past its end");
+                let n = self.content.len().min(buf.len()).min(3);
+                buf[..n].copy_from_slice(&self.content[..n]);
+                self.content = &self.content[n..];
+                self.exhausted = n == 0;
+                Ok(n)
+            }
+        }
+
+        let language = test_language(
+            "json",
+            tree_sitter_json::language(),
+            r#"(array "," @append_space)"#,
+        );
+        let operation = Operation::Format(FormatOptions::default());
+
+        let mut input = Pipe {
+            content: b"[1,2,3,4]",
+            exhausted: false,
+        };
+        let mut output = Vec::new();
+        formatter(&mut input, &mut output, &language, operation).unwrap();
+        pretty_assert_eq("[1, 2, 3, 4]\n", &String::from_utf8(output).unwrap());
+    }
+
+    #[test]
+    fn respect_input_breaks_follows_the_marked_node() {
+        let query_content = r#"
+            (array "," @append_spaced_softline)
+            (document (array) @respect_input_breaks)
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        // The inner array is on one line, but breaks like the outer one
+        assert_formats("[[1, 2],\n3]", "[[1,\n2],\n3]\n", &language);
+        // An expression written on one line stays inline
+        assert_formats("[[1,2],3]", "[[1, 2], 3]\n", &language);
+    }
+
+    #[test]
+    fn space_before_comment_separates_a_trailing_comment() {
+        let grammar: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let query_content = r#"
+            "let" @append_space
+            "=" @surround_space
+            (comment) @space_before_comment
+        "#;
+        let language = test_language("ocaml", grammar, query_content);
+
+        assert_formats("let x=1(* c *)", "let x = 1 (* c *)\n", &language);
+    }
+
+    #[test]
+    fn protect_keeps_the_original_bytes_of_a_node() {
+        let query_content = r#"
+            (array "," @append_space)
+            (comment) @prepend_hardline @append_hardline @protect
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        // The trailing spaces of the art are kept, while the code is formatted
+        let art = "/*  +---+  \n    | x |\n    +---+  */";
+        assert_formats(
+            &format!("[1,2,\n{art}\n3,4]"),
+            &format!("[1, 2,\n{art}\n3, 4]\n"),
+            &language,
+        );
+    }
+
+    #[test]
+    fn align_lines_up_operators_and_values() {
+        let grammar: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let query_content = r#"
+            (compilation_unit (_) @prepend_hardline)
+            (infix_expression
+              operator: (_) @align @prepend_space @append_space
+              right: (_) @align
+            )
+        "#;
+        let language = test_language("ocaml", grammar, query_content);
+
+        // The operators line up, then the values, after the wider operator
+        assert_formats(
+            "a:=1;;\nwidth=22;;\nb:=-3;;",
+            "a     := 1;;\nwidth =  22;;\nb     := -3;;\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn align_right_pads_numbers_on_the_left() {
+        let query_content = r#"
+            "[" @append_hardline @append_indent_start
+            "]" @prepend_hardline @prepend_indent_end
+            "," @append_hardline
+            (number) @align_right
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        assert_formats("[1,22,333]", "[\n    1,\n   22,\n  333\n]\n", &language);
+    }
+
+    #[test]
+    fn expand_if_comment_only_only_expands_comment_only_blocks() {
+        let query_content = r#"
+            (object
+              "{" @append_spaced_softline @append_indent_start
+              "}" @prepend_spaced_softline @prepend_indent_end
+            )
+            (pair ":" @append_space)
+            (object) @expand_if_comment_only
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        assert_formats(
+            "{/* nothing yet */}",
+            "{\n  /* nothing yet */\n}\n",
+            &language,
+        );
+        assert_formats(r#"{"a":1}"#, "{ \"a\": 1 }\n", &language);
+    }
+
+    #[test]
+    fn comment_kinds_set_which_nodes_are_comments() {
+        let query_content = r#"
+            (array
+              "[" @append_spaced_softline @append_indent_start
+              "]" @prepend_spaced_softline @prepend_indent_end
+            )
+            (array) @expand_if_comment_only
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+        assert_formats("[/* a */]", "[\n  /* a */\n]\n", &language);
+
+        // Once comments are strings, the comment is no longer one
+        let language = Language {
+            comment_kinds: Some(vec!["string".into()]),
+            ..test_language("json", tree_sitter_json::language(), query_content)
+        };
+        assert_formats("[/* a */]", "[ /* a */ ]\n", &language);
+        assert_formats(r#"["a"]"#, "[\n  \"a\"\n]\n", &language);
+    }
+
+    #[test]
+    fn normalise_path_separator_respects_escapes() {
+        let query_content = r#"
+            (string) @leaf
+            (
+              (string) @normalise_path_separator
+              (#path_separator! "/")
+            )
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+        let operation = Operation::Format(FormatOptions::default());
+
+        pretty_assert_eq(
+            r#""C:/dir/file\n\"name\"""#,
+            formatter_to_string(r#""C:\\dir\\file\n\"name\"""#, &language, operation)
+                .unwrap()
+                .trim_end(),
+        );
+    }
+
+    #[test]
+    fn verify_tokens_catches_a_dropped_token() {
+        let language = test_language(
+            "json",
+            tree_sitter_json::language(),
+            r#"(array "," @delete @append_space)"#,
+        );
+        let operation = |verify_tokens| {
+            Operation::Format(FormatOptions {
+                skip_idempotence: true,
+                verify_tokens,
+                ..Default::default()
+            })
+        };
+
+        // Without the check, the dropped commas go unnoticed
+        pretty_assert_eq(
+            "[1 2]\n",
+            &formatter_to_string("[1,2]", &language, operation(false)).unwrap(),
+        );
+
+        match formatter_to_string("[1,2]", &language, operation(true)) {
+            Err(FormatterError::TokenMismatch { expected, span, .. }) => {
+                assert_eq!(expected.as_deref(), Some(","));
+                assert_eq!(span, Some(2..3));
+            }
+            result => panic!("Expected a token mismatch, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn max_depth_rejects_deeply_nested_input() {
+        let language = test_language(
+            "json",
+            tree_sitter_json::language(),
+            r#"(array "," @append_space)"#,
+        );
+        let operation = |max_depth| {
+            Operation::Format(FormatOptions {
+                max_depth,
+                ..Default::default()
+            })
+        };
+        let input = "[[[[[[1]]]]]]";
+
+        pretty_assert_eq(
+            "[[[[[[1]]]]]]\n",
+            &formatter_to_string(input, &language, operation(Some(7))).unwrap(),
+        );
+
+        // The document is at depth 0 and the outermost array at depth 1, so the
+        // first node beyond depth 4 is the opening bracket of the fourth array
+        match formatter_to_string(input, &language, operation(Some(4))) {
+            Err(FormatterError::TooDeep { max_depth, span }) => {
+                assert_eq!(max_depth, 4);
+                assert_eq!(span, Some(3..4));
+            }
+            result => panic!("Expected the input to be too deep, got {result:?}"),
+        }
+
+        // Far deeper inputs are rejected too, rather than overflowing the
+        // stack, whether they parse or not
+        let deep = format!("{}{}", "[".repeat(100_000), "]".repeat(100_000));
+        assert!(matches!(
+            formatter_to_string(&deep, &language, operation(Some(50))),
+            Err(FormatterError::TooDeep { .. })
+        ));
+        assert!(matches!(
+            formatter_to_string(&"[".repeat(100_000), &language, operation(Some(50))),
+            Err(FormatterError::Parsing { .. })
+        ));
+    }
+
+    #[test]
+    fn strip_redundant_parens_keeps_the_needed_ones() {
+        let grammar: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let query_content = r#"
+            "let" @append_space
+            ["=" (add_operator) (mult_operator)] @surround_space
+            (compilation_unit (_) @prepend_hardline)
+            (
+              (parenthesized_expression) @strip_redundant_parens
+              (#kinds! "value_path" "number")
+            )
+        "#;
+        let language = test_language("ocaml", grammar, query_content);
+
+        assert_formats(
+            "let a = ((x))\nlet b = ((x+1))*(2)",
+            "let a = x\nlet b = (x + 1) * 2\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn indent_from_previous_sibling_follows_its_column() {
+        let query_content = r#"
+            (pair ":" @append_space)
+            (object "," @append_space)
+            (array "," @append_hardline)
+            (pair (array) @indent_from_previous_sibling)
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        assert_formats(
+            r#"{"a":1,"key":[1,2,3]}"#,
+            "{\"a\": 1, \"key\": [1,\n           2,\n           3]}\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn tight_brackets_only_affects_type_arguments() {
+        let grammar: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let query_content = r#"
+            ["let" "type" ","] @append_space
+            ["=" (rel_operator)] @surround_space
+            ["(" ")"] @prepend_space @append_space
+            (compilation_unit (_) @prepend_hardline)
+            (constructed_type) @tight_brackets
+        "#;
+        let language = test_language("ocaml", grammar, query_content);
+
+        assert_formats(
+            "type t = ( int,string ) result\nlet b = (x<y)",
+            "type t = (int, string) result\nlet b = ( x < y )\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn hardline_splits_jammed_items_and_yields_to_blank_lines() {
+        let grammar: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let query_content = r#"
+            "let" @append_space
+            "=" @surround_space
+            (compilation_unit (_) @append_hardline @allow_blank_line_before)
+        "#;
+        let language = test_language("ocaml", grammar, query_content);
+
+        assert_formats(
+            "let a = 1 let b = 2\n\nlet c = 3",
+            "let a = 1\nlet b = 2\n\nlet c = 3\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn comment_style_conversion_merges_runs_of_line_comments() {
+        let grammar: topiary_tree_sitter_facade::Language = tree_sitter_json::language().into();
+        let query = |capture: &str| {
+            format!(
+                r#"
+                (array "[" @append_hardline @append_indent_start)
+                (array "]" @prepend_hardline @prepend_indent_end)
+                (array "," @append_hardline)
+                (comment) @append_hardline
+                (
+                  (comment) @{capture}
+                  (#comment_delimiters! "//" "/*" "*/")
+                )
+                "#
+            )
+        };
+        let format = |capture: &str, input: &str| {
+            let language = test_language("json", grammar.clone(), &query(capture));
+            let operation = Operation::Format(FormatOptions::default());
+            formatter_to_string(input, &language, operation).unwrap()
+        };
+
+        pretty_assert_eq(
+            "[\n  /* first\n     second */\n  1,\n  /* third */\n  2\n]\n",
+            &format(
+                "to_block_comment",
+                "[\n  // first\n  // second\n  1,\n  // third\n\n  2]",
+            ),
+        );
+        // Continuation lines follow the comment when it is indented anew
+        pretty_assert_eq(
+            "[\n  /* first\n     second */\n  1\n]\n",
+            &format(
+                "to_block_comment",
+                "[\n      // first\n      // second\n  1]",
+            ),
+        );
+        // Comments holding the closing delimiter are left as they are
+        pretty_assert_eq(
+            "[\n  // a */ b\n  // c\n  1\n]\n",
+            &format("to_block_comment", "[\n  // a */ b\n  // c\n  1]"),
+        );
+        pretty_assert_eq(
+            "[\n  // first\n  // second\n  1,\n  // third\n  2\n]\n",
+            &format(
+                "to_line_comment",
+                "[\n  /*\n   * first\n   * second\n   */\n  1,\n  /* third */ 2]",
+            ),
+        );
+        // Only continuation lines lose their leading `*`
+        pretty_assert_eq(
+            "[\n  // *first*\n  1\n]\n",
+            &format("to_line_comment", "[\n  /* *first* */ 1]"),
+        );
+        // Later line comments keep the column of the comment in the input,
+        // counted in characters, until they are formatted again
+        let language = test_language("json", grammar.clone(), &query("to_line_comment"));
+        let operation = Operation::Format(FormatOptions {
+            skip_idempotence: true,
+            ..FormatOptions::default()
+        });
+        pretty_assert_eq(
+            "[\n  \"é\",\n  // first\n       // second\n  1\n]\n",
+            &formatter_to_string(
+                "[\n  \"é\", /* first\n          second */ 1]",
+                &language,
+                operation,
+            )
+            .unwrap(),
+        );
+        // Doc comments are left as they are
+        pretty_assert_eq(
+            "[\n  /// first\n  1\n]\n",
+            &format("to_block_comment", "[\n  /// first\n  1]"),
+        );
+        pretty_assert_eq(
+            "[\n  /** first */\n  1,\n  //\n  2\n]\n",
+            &format("to_line_comment", "[\n  /** first */ 1,\n  /**/ 2]"),
+        );
+    }
+
+    #[test]
+    fn embedded_json_is_formatted_inside_a_string() {
+        let ocaml: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let json: topiary_tree_sitter_facade::Language = tree_sitter_json::language().into();
+        let host_query = r#"
+            "let" @append_space
+            "=" @surround_space
+            (
+              (string) @leaf @embedded
+              (#embedded_language! json)
+            )
+        "#;
+        let embedded_query = r#"
+            (string) @leaf
+            (object "{" @append_hardline @append_indent_start)
+            (object "}" @prepend_hardline @prepend_indent_end)
+            (object "," @append_hardline)
+            (pair ":" @append_space)
+            (array "," @append_space)
+        "#;
+        let host = test_language("ocaml", ocaml.clone(), host_query);
+        let embedded = test_language("json", json.clone(), embedded_query);
+        assert_eq!(host.query.embedded_languages(), ["json"]);
+        let operation = Operation::Format(FormatOptions {
+            deny_warnings: true,
+            ..Default::default()
+        });
+
+        let input = r#"let config="{\"name\":\"a \\\"b\\\"\",\"ids\":[1,2]}""#;
+        let mut output = Vec::new();
+        formatter_with_embedded_languages(
+            &mut input.as_bytes(),
+            &mut output,
+            &host,
+            &[&embedded],
+            operation,
+        )
+        .unwrap();
+
+        pretty_assert_eq(
+            concat!(
+                "let config = \"{\n",
+                "                \\\"name\\\": \\\"a \\\\\\\"b\\\\\\\"\\\",\n",
+                "                \\\"ids\\\": [1, 2]\n",
+                "              }\"\n",
+            ),
+            &String::from_utf8(output).unwrap(),
+        );
+
+        // The lines are aligned by characters, not bytes, with the quote
+        let input = r#"let s="\"é\"" let config="{\"a\":1}""#;
+        let mut output = Vec::new();
+        formatter_with_embedded_languages(
+            &mut input.as_bytes(),
+            &mut output,
+            &host,
+            &[&embedded],
+            operation,
+        )
+        .unwrap();
+
+        pretty_assert_eq(
+            concat!(
+                "let s = \"\\\"é\\\"\"let config = \"{\n",
+                "                               \\\"a\\\": 1\n",
+                "                             }\"\n",
+            ),
+            &String::from_utf8(output).unwrap(),
+        );
+    }
+
+    #[test]
+    fn space_around_operator_puts_one_space_on_each_side() {
+        let grammar: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let query_content = r#"
+            "let" @append_space
+            "=" @surround_space
+            (
+              (infix_expression) @space_around_operator
+              (#kinds! add_operator)
+            )
+        "#;
+        let language = test_language("ocaml", grammar, query_content);
+
+        assert_formats("let x = a+b", "let x = a + b\n", &language);
+        assert_formats("let x = a  +  b", "let x = a + b\n", &language);
+    }
+
+    #[test]
+    fn inline_tests_check_a_query_against_input_and_output() {
+        let query_content = r#"
+            (array "," @append_space)
+            (object "{" @append_space)
+            (object "}" @prepend_space)
+            (object "," @append_space)
+            (pair ":" @append_space)
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        assert_formats("[1,2,3]", "[1, 2, 3]\n", &language);
+        assert_formats(
+            "{\"a\":[1,2],\"b\":{}}",
+            "{ \"a\": [1, 2], \"b\": { } }\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn collapse_single_statement_leaves_longer_blocks_expanded() {
+        let query_content = r#"
+            (object "{" @append_hardline @append_indent_start)
+            (object "}" @prepend_hardline @prepend_indent_end)
+            (object "," @append_hardline)
+            (pair ":" @append_space)
+            (comment) @prepend_space
+            ((object) @collapse_single_statement (#max_width! "30"))
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        assert_formats("{\"a\":1}", "{ \"a\": 1 }\n", &language);
+        // The line break ending a nested line comment is kept
+        assert_formats(
+            "{\"a\":{\"b\":1 // c\n}}",
+            "{\n  \"a\": {\n    \"b\": 1 // c\n  }\n}\n",
+            &language,
+        );
+        assert_formats(
+            "{\"a\":1,\"b\":2}",
+            "{\n  \"a\": 1,\n  \"b\": 2\n}\n",
+            &language,
+        );
+        assert_formats(
+            "{\"a\":\"much too long to fit here\"}",
+            "{\n  \"a\": \"much too long to fit here\"\n}\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn ansi_renderer_colours_keywords_only() {
+        let grammar: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let language = test_language("ocaml", grammar, "");
+        let renderer = AnsiRenderer::new(HashMap::from([
+            ("let".to_string(), "1;34".to_string()),
+            ("in".to_string(), "1;34".to_string()),
+            ("string".to_string(), "32".to_string()),
+        ]));
+
+        pretty_assert_eq(
+            "\x1b[1;34mlet\x1b[0m x = \x1b[32m\"a b\"\x1b[0m \x1b[1;34min\x1b[0m\n  f x\n",
+            &renderer
+                .render("let x = \"a b\" in\n  f x\n", &language)
+                .unwrap(),
+        );
+    }
+
+    #[test]
+    fn multiline_delimiter_is_stable_across_passes() {
+        let grammar: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let query_content = r#"
+            "let" @append_space
+            "=" @surround_space
+            (list_expression "[" @append_empty_softline @append_indent_start)
+            (list_expression "]" @prepend_empty_softline @prepend_indent_end)
+            (list_expression ";" @append_spaced_softline)
+            (list_expression
+              (#delimiter! ";")
+              (_) @append_multiline_delimiter
+              .
+              ";"? @do_nothing
+              .
+              "]"
+              .
+            )
+        "#;
+        let language = test_language("ocaml", grammar, query_content);
+        let operation = Operation::Format(FormatOptions {
+            skip_idempotence: true,
+            ..Default::default()
+        });
+
+        // The trailing delimiter is decided by the layout of the input, so the
+        // second pass, which sees it, does not change the output
+        for (input, expected) in [
+            ("let l = [\n  1;\n  2\n]", "let l = [\n  1;\n  2;\n]\n"),
+            ("let l = [1;  2]", "let l = [1; 2]\n"),
+        ] {
+            let once = formatter_to_string(input, &language, operation).unwrap();
+            let twice = formatter_to_string(&once, &language, operation).unwrap();
+            pretty_assert_eq(expected, &once);
+            pretty_assert_eq(&once, &twice);
+        }
+    }
+
+    #[test]
+    fn hang_arguments_aligns_arguments_with_the_first() {
+        let query_content = r#"
+            (string) @leaf
+            (pair ":" @append_space)
+            (array "," @append_space)
+            (array) @hang_arguments
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        assert_formats(
+            "{\"key\": [1,\n2,\n3]}",
+            "{\"key\": [1,\n         2,\n         3]}\n",
+            &language,
+        );
+        assert_formats("{\"key\": [1,2]}", "{\"key\": [1, 2]}\n", &language);
+    }
+
+    #[test]
+    fn post_process_passes_run_in_the_language_order() {
+        let grammar: topiary_tree_sitter_facade::Language = tree_sitter_json::language().into();
+        let query_content = r#"
+            (array "," @delete)
+            (array "," @append_hardline)
+            (array . (number) @glue_to_next)
+        "#;
+        let language = |post_process| Language {
+            post_process: Some(post_process),
+            ..test_language("json", grammar.clone(), query_content)
+        };
+        let operation = Operation::Format(FormatOptions {
+            skip_idempotence: true,
+            ..Default::default()
+        });
+
+        // Run first, the glue sees the deleted comma as the next leaf, and
+        // the line break after it is kept
+        let glue_first = language(vec![
+            PostProcessPass::Scopes,
+            PostProcessPass::Glue,
+            PostProcessPass::Deletes,
+        ]);
+        assert_eq!(
+            formatter_to_string("[1, 2, 3]", &glue_first, operation).unwrap(),
+            "[1\n2\n3]\n"
+        );
+
+        let deletes_first = language(vec![
+            PostProcessPass::Scopes,
+            PostProcessPass::Deletes,
+            PostProcessPass::Glue,
+        ]);
+        assert_eq!(
+            formatter_to_string("[1, 2, 3]", &deletes_first, operation).unwrap(),
+            "[12\n3]\n"
+        );
+
+        // The passes left out still run, after those listed
+        let glue_only = language(vec![PostProcessPass::Glue]);
+        assert_eq!(
+            formatter_to_string("[1, 2, 3]", &glue_only, operation).unwrap(),
+            "[1\n2\n3]\n"
+        );
+    }
+
+    #[test]
+    fn remove_trailing_blank_lines_before_the_closing_brace() {
+        let grammar: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let query_content = r#"
+            "let" @append_space
+            "=" @surround_space
+            (record_expression "{" @append_hardline @append_indent_start)
+            (record_expression "}" @prepend_hardline @prepend_indent_end)
+            (record_expression ";" @append_hardline @allow_blank_line_after)
+            (record_expression) @remove_trailing_blank_lines
+        "#;
+        let language = test_language("ocaml", grammar, query_content);
+
+        // The blank line after the last field is removed, not those between
+        // the fields
+        assert_formats(
+            "let r = { a = 1;\n\n  b = 2;\n\n}",
+            "let r = {\n  a = 1;\n\n  b = 2;\n}\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn comma_spacing_moves_the_space_after_the_comma() {
+        let query_content = r#"
+            (number) @append_space
+            (array "]" @prepend_antispace)
+            (array) @comma_spacing
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        assert_formats("[1 ,2]", "[1, 2]\n", &language);
+        assert_formats("[1  ,  2 , 3]", "[1, 2, 3]\n", &language);
+    }
+
+    #[test]
+    fn canonicalise_rewrites_synonyms() {
+        let grammar: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let query_content = r#"
+            "let" @append_space
+            "=" @surround_space
+            (compilation_unit (_) @prepend_hardline)
+            (
+              (value_name) @canonicalise
+              (#canonical! "true" "yes" "on")
+            )
+        "#;
+        let language = test_language("ocaml", grammar, query_content);
+
+        assert_formats(
+            "let a = yes\nlet b = on\nlet c = off",
+            "let a = true\nlet b = true\nlet c = off\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn ensure_semicolon_adds_a_missing_terminator() {
+        let grammar: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let query_content = r#"
+            "let" @append_space
+            "=" @surround_space
+            (compilation_unit (_) @prepend_hardline)
+            ((value_definition) @ensure_semicolon (#delimiter! ";;"))
+        "#;
+        let language = test_language("ocaml", grammar, query_content);
+
+        // The terminator is added where it is missing, and not doubled
+        assert_formats(
+            "let x = 1\nlet y = 2;;",
+            "let x = 1;;\nlet y = 2;;\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn remove_semicolon_deletes_a_present_terminator() {
+        let grammar: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let query_content = r#"
+            "let" @append_space
+            "=" @surround_space
+            (compilation_unit (value_definition) @prepend_hardline)
+            ((value_definition) @remove_semicolon (#delimiter! ";;"))
+        "#;
+        let language = test_language("ocaml", grammar, query_content);
+
+        assert_formats(
+            "let x = 1;;\nlet y = 2",
+            "let x = 1\nlet y = 2\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn scope_line_literals_show_where_multi_line_scopes_start() {
+        let query_content = r#"
+          (
+            (object "{" @append_begin_scope "}" @append_end_scope)
+            (#scope_id! "object")
+          )
+          (
+            (object "{" @append_scope_line_literal)
+            (#scope_id! "object")
+            (#delimiter! "/* from line {line} */")
+          )
+          (
+            (pair) @prepend_empty_scoped_softline
+            (#scope_id! "object")
+          )
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+        // The literals are comments, which formatting again would keep, so
+        // idempotence does not hold here
+        let operation = Operation::Format(FormatOptions {
+            skip_idempotence: true,
+            ..Default::default()
+        });
+
+        for (input, expected) in [
+            ("{\"a\":1,\"b\":2}", "{\"a\":1,\"b\":2}\n"),
+            (
+                "\n\n{\"a\":1,\n\"b\":2}",
+                "{/* from line 3 */\n\"a\":1,\n\"b\":2}\n",
+            ),
+        ] {
+            pretty_assert_eq(
+                expected,
+                &formatter_to_string(input, &language, operation).unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn hardline_every_n_puts_elements_in_rows() {
+        let query_content = r#"
+            (array "," @append_space)
+            (
+              (array) @hardline_every_n
+              (#row_size! "3")
+            )
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        // The last, partial, row is not followed by a line break
+        for (input, expected) in [
+            ("[1,2,3,4,5,6,7]", "[1, 2, 3,\n4, 5, 6,\n7]\n"),
+            ("[1,2,3,4,5,6]", "[1, 2, 3,\n4, 5, 6]\n"),
+            ("[1,2]", "[1, 2]\n"),
+        ] {
+            assert_formats(input, expected, &language);
+        }
+    }
+
+    #[test]
+    fn malformed_queries_return_errors() {
+        let grammar: topiary_tree_sitter_facade::Language = tree_sitter_json::language().into();
+        let operation = Operation::Format(FormatOptions {
+            deny_warnings: true,
+            ..Default::default()
+        });
+
+        // Each of these must be reported as an error, either when the query
+        // is parsed or when it is applied, and never cause a panic
+        for query_content in [
+            "(object) @append_indent_end",
+            "(object) @not_a_capture",
+            "(array) @hardline_every_n",
+            "((array) @hardline_every_n (#row_size! \"0\"))",
+            "((array) @append_hardline_if_parent_over (#child_count! \"many\"))",
+            "((object) @append_indent_start_width (#indent_width!))",
+            "(object) @append_begin_scope",
+        ] {
+            let result = TopiaryQuery::new(&grammar, query_content).and_then(|query| {
+                let language = Language {
+                    name: "json".to_owned(),
+                    query,
+                    grammar: grammar.clone(),
+                    indent: None,
+                    tab_width: 8,
+                    expand_tabs: false,
+                    final_newline: None,
+                    normalise_indentation: false,
+                    file_trailer: None,
+                    post_process: None,
+                    comment_kinds: None,
+                };
+                formatter_to_string("{\"a\": [1, 2]}", &language, operation)
+            });
+            assert!(result.is_err(), "{query_content} was accepted");
+        }
+    }
+
+    #[test]
+    fn zero_width_nodes_do_not_take_line_breaks() {
+        let language = test_language(
+            "json",
+            tree_sitter_json::language(),
+            "(array (number) @append_input_softline .)",
+        );
+        // The output lacks the missing bracket, so it cannot be parsed again
+        let operation = Operation::Format(FormatOptions {
+            skip_idempotence: true,
+            tolerate_parsing_errors: true,
+            ..Default::default()
+        });
+
+        // Error recovery inserts an empty "]" right after the "2", which must
+        // not hide the line break between the "2" and the "}"
+        pretty_assert_eq(
+            "{\"a\":[1,2\n}\n",
+            &formatter_to_string("{\"a\": [1, 2\n}", &language, operation).unwrap(),
+        );
+    }
+
+    #[test]
+    fn normalise_number_trims_trailing_zeros() {
+        let query_content = r#"
+            (array "," @append_space)
+            (
+              (number) @normalise_number
+              (#number_format! "trim_zeros")
+            )
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        assert_formats("[1.50,2.0,1e10,100]", "[1.5, 2, 1e10, 100]\n", &language);
+    }
+
+    #[test]
+    fn blankline_after_kinds_separates_types_from_values() {
+        let grammar: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let query_content = r#"
+            ["type" "let"] @append_space
+            "=" @surround_space
+            (compilation_unit (_) @prepend_hardline)
+            (
+              (compilation_unit (value_definition) @blankline_after_kinds)
+              (#kinds! "type_definition")
+            )
+        "#;
+        let language = test_language("ocaml", grammar, query_content);
+
+        // The comment before the second value stays with it
+        assert_formats(
+            "type t = int\nlet x = 1\ntype u = t\n(* y *)\nlet y = 2\n\n\nlet z = 3",
+            "type t = int\n\nlet x = 1\ntype u = t\n\n(* y *)\nlet y = 2\nlet z = 3\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn blankline_on_kind_change_separates_imports_from_definitions() {
+        let grammar: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let query_content = r#"
+            ["open" "let"] @append_space
+            "=" @surround_space
+            (compilation_unit (_) @prepend_hardline @blankline_on_kind_change)
+        "#;
+        let language = test_language("ocaml", grammar, query_content);
+
+        assert_formats(
+            "open Foo open Bar let x = 1 let y = 2",
+            "open Foo\nopen Bar\n\nlet x = 1\nlet y = 2\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn blankline_after_doc_comment_separates_it_from_the_code() {
+        let grammar: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let query_content = r#"
+            "let" @append_space
+            "=" @surround_space
+            (compilation_unit (_) @prepend_hardline)
+            (
+              (comment) @blankline_after_doc_comment
+              (#match? @blankline_after_doc_comment "^[(][*][*]")
+            )
+        "#;
+        let language = test_language("ocaml", grammar, query_content);
+
+        // Neither plain comments nor a doc comment ending the file are followed
+        // by a blank line
+        assert_formats(
+            "(** The answer *) let x = 42 (* Plain *) let y = 1 (** Trailing *)",
+            "(** The answer *)\n\nlet x = 42\n(* Plain *)\nlet y = 1\n(** Trailing *)\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn glue_to_next_keeps_elements_with_their_comma() {
+        let query_content = r#"
+            (array (_) @append_spaced_softline)
+            (array "," @append_spaced_softline)
+            (array (_) @glue_to_next . ",")
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        // Only the last element, which is not followed by a comma, breaks
+        assert_formats("[1,\n2,3]", "[1,\n2,\n3\n]\n", &language);
+    }
+
+    #[test]
+    fn multi_line_strings_keep_their_interior_and_surroundings() {
+        let grammar: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let query_content = r#"
+            (quoted_string) @multi_line_string
+            "let" @append_space
+            (let_binding "=" @prepend_space @append_spaced_softline @append_indent_start)
+            (let_binding) @append_indent_end
+            (application_expression (_) @append_space)
+        "#;
+        let language = test_language("ocaml", grammar, query_content);
+
+        // The string does not make the binding multi-line, so no line break is
+        // inserted after `=`, and the indentation of its second line is kept
+        assert_formats(
+            "let x = f {|one\n   two|} y",
+            "let x = f {|one\n   two|} y\n",
+            &language,
+        );
+    }
+
+    #[test]
+    fn dedup_adjacent_collapses_doubled_tokens() {
+        let grammar: topiary_tree_sitter_facade::Language =
+            tree_sitter_ocaml::language_ocaml().into();
+        let query_content = r#"
+            "let" @append_space
+            "=" @surround_space
+            (parenthesized_expression
+              .
+              "(" @dedup_adjacent
+              .
+              (parenthesized_expression . "(" @dedup_adjacent ")" @dedup_adjacent .)
+              .
+              ")" @dedup_adjacent
+            )
+        "#;
+        let language = test_language("ocaml", grammar, query_content);
+
+        assert_formats("let x = ( (1) )", "let x = (1)\n", &language);
+        // Parentheses which are not directly nested are left alone, as the
+        // deduplication is purely textual
+        assert_formats("let x = f (g (1))", "let x = f(g(1))\n", &language);
+    }
+
+    #[test]
+    fn format_node_in_range_only_formats_the_enclosing_node() {
+        let language = test_language("json", tree_sitter_json::language(), r#""," @append_space"#);
+        let operation = Operation::Format(FormatOptions::default());
+
+        // The range covers the elements of the first array, which is formatted
+        pretty_assert_eq(
+            "[[1, 2],[3,4]]",
+            &format_node_in_range("[[1,2],[3,4]]", 2..5, &language, operation).unwrap(),
+        );
+    }
+
+    #[test]
+    fn format_node_in_range_rejects_ranges_outside_the_input() {
+        let language = test_language("json", tree_sitter_json::language(), r#""," @append_space"#);
+        let operation = Operation::Format(FormatOptions::default());
+
+        for (start, end) in [(2, 20), (5, 2)] {
+            match format_node_in_range("[1,2]", start..end, &language, operation) {
+                Err(FormatterError::InvalidRange {
+                    range: invalid,
+                    length,
+                }) => {
+                    assert_eq!(invalid, start..end);
+                    assert_eq!(length, 5);
+                }
+                result => panic!("Expected an invalid range, got {result:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn format_node_in_range_without_a_node_formats_the_enclosing_one() {
+        let language = test_language("json", tree_sitter_json::language(), r#""," @append_space"#);
+        let operation = Operation::Format(FormatOptions::default());
+
+        // The range only covers whitespace, between the elements of the outer array
+        pretty_assert_eq(
+            "[[1, 2], [3, 4]]",
+            &format_node_in_range("[[1,2],  [3,4]]", 7..8, &language, operation).unwrap(),
+        );
+        // An empty range at the start of the input is within the whole document
+        pretty_assert_eq(
+            "[1, 2]",
+            &format_node_in_range("[1,2]", 0..0, &language, operation).unwrap(),
+        );
+    }
+
+    #[test]
+    fn formatter_with_timings_measures_each_stage() {
+        let input = fs::read_to_string("../topiary-cli/tests/samples/input/ocaml.ml").unwrap();
+        let query_content = fs::read_to_string("../topiary-queries/queries/ocaml.scm").unwrap();
+        let language = test_language("ocaml", tree_sitter_ocaml::language_ocaml(), &query_content);
+
+        let timings = formatter_with_timings(
+            &mut input.as_bytes(),
+            &mut Vec::new(),
+            &language,
+            Operation::Format(FormatOptions {
+                skip_idempotence: true,
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+
+        assert!(!timings.collect_leafs.is_zero());
+        assert!(!timings.query.is_zero());
+        // Resolving the scopes of a small input may take less time than the
+        // clock can measure, so it is not required to be positive
+        assert!(timings.post_process >= timings.post_process_scopes);
+        assert!(!timings.render.is_zero());
+
+        // Timings are only measured when asked for
+        let mut atoms =
+            apply_query(&input, &language.query, &language.grammar, false, false).unwrap();
+        atoms.post_process();
+        assert_eq!(atoms.timings(), &Timings::default());
+    }
+
+    #[test]
+    fn formatter_with_indent_depths_reports_each_line() {
+        let query_content = fs::read_to_string("../topiary-queries/queries/json.scm").unwrap();
+        let language = test_language("json", tree_sitter_json::language(), &query_content);
+
+        let mut output = Vec::new();
+        let depths = formatter_with_indent_depths(
+            &mut "{\"a\": {\n\"b\": {\n\"c\": 1}},\n\"d\": 2}".as_bytes(),
+            &mut output,
+            &language,
+            Operation::Format(FormatOptions::default()),
+        )
+        .unwrap();
+
+        pretty_assert_eq(
+            "{\n  \"a\": {\n    \"b\": {\n      \"c\": 1\n    }\n  },\n  \"d\": 2\n}\n",
+            &String::from_utf8(output).unwrap(),
+        );
+        assert_eq!(depths, vec![0, 1, 2, 3, 2, 1, 1, 0]);
+    }
+
+    #[test]
+    fn leading_tabs_are_expanded_outside_tokens() {
+        let format = |input: &str| {
+            let mut output = Vec::new();
+            let query_content = fs::read_to_string("../topiary-queries/queries/ocaml.scm").unwrap();
+            let language = Language {
+                tab_width: 4,
+                expand_tabs: true,
+                ..test_language("ocaml", tree_sitter_ocaml::language_ocaml(), &query_content)
+            };
+
+            formatter(
+                &mut input.as_bytes(),
+                &mut output,
+                &language,
+                Operation::Format(FormatOptions::default()),
+            )
+            .unwrap();
+
+            String::from_utf8(output).unwrap()
+        };
+
+        let tabs = format("let x =\n\t(* a\n\t   b *)\n\t1\n\nlet s = {|\n\tkeep\n|}\n");
+        let spaces = format("let x =\n    (* a\n       b *)\n    1\n\nlet s = {|\n\tkeep\n|}\n");
+
+        pretty_assert_eq(&spaces, &tabs);
+        assert!(tabs.contains("{|\n\tkeep\n|}"));
+    }
+
+    #[test]
+    fn mixed_indentation_is_reported_or_normalised() {
+        let input = "{\n\t\"a\": 1,\n  \"b\": 2\n}";
+        let grammar: topiary_tree_sitter_facade::Language = tree_sitter_json::language().into();
+        let query_content = fs::read_to_string("../topiary-queries/queries/json.scm").unwrap();
+
+        let normalised =
+            crate::tree_sitter::expand_leading_tabs(input, &grammar, 2, None, false).unwrap();
+        assert_eq!(normalised, "{\n  \"a\": 1,\n  \"b\": 2\n}");
+
+        for expand_tabs in [false, true] {
+            let mut output = Vec::new();
+            let language = Language {
+                tab_width: 2,
+                expand_tabs,
+                ..test_language("json", grammar.clone(), &query_content)
+            };
+
+            let result = formatter(
+                &mut input.as_bytes(),
+                &mut output,
+                &language,
+                Operation::Format(FormatOptions {
+                    deny_warnings: true,
+                    ..Default::default()
+                }),
+            );
+
+            match (expand_tabs, result) {
+                (false, Err(FormatterError::Warnings(warnings))) => {
+                    assert!(
+                        warnings[0].contains("Mixed tabs and spaces in indentation from line 3")
+                    );
+                }
+                (true, Ok(())) => {}
+                (_, result) => panic!("Unexpected result: {result:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn indent_literal_follows_configured_indent() {
+        for (indent, expected) in [("  ", "[1,  2]\n"), ("\t", "[1,\t2]\n")] {
+            let input = "[1,2]";
+            let query_content = "\",\" @append_indent_literal";
+            let language = Language {
+                indent: Some(indent.to_owned()),
+                ..test_language("json", tree_sitter_json::language(), query_content)
+            };
+
+            assert_formats(input, expected, &language);
+        }
+    }
+
+    #[test]
+    fn deleting_a_node_keeps_its_leading_comment_attached() {
+        let input = "let x = 1\n\n(** doc *)\nlet y = 2\n\nlet z = 3\n";
+        let expected = "let x = 1\n\n(** doc *)\nlet z = 3\n";
+
+        let query_content = format!(
+            "{}\n(value_definition (let_binding pattern: (value_name) @leaf (#eq? @leaf \"y\"))) @delete",
+            fs::read_to_string("../topiary-queries/queries/ocaml.scm").unwrap()
+        );
+        let language = test_language("ocaml", tree_sitter_ocaml::language_ocaml(), &query_content);
+
+        assert_formats(input, expected, &language);
+    }
+
+    #[test]
+    fn deleting_a_node_keeps_its_indentation() {
+        // The indentation started after the deleted bracket ends before the
+        // closing one, which is kept
+        let query_content = r#"
+            (array "[" @append_indent_start @delete)
+            (array "," @append_hardline)
+            (array "]" @prepend_indent_end @prepend_hardline)
+        "#;
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+        let operation = Operation::Format(FormatOptions {
+            skip_idempotence: true,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            formatter_to_string("[1,2]", &language, operation).unwrap(),
+            "1,\n  2\n]\n"
+        );
+    }
+
+    #[test]
+    fn hardline_pairs_only_kept_where_allowed() {
+        let input = "[{\"a\":1,\"b\":2},3]";
+        let expected = "[\n{\n\"a\":1,\n\n\"b\":2},\n3]\n";
+
+        let query_content = "\",\" @append_hardline
+(pair) @prepend_hardline
+(array (_) @prepend_hardline)
+(object) @allow_hardline_pairs";
+        let language = test_language("json", tree_sitter_json::language(), query_content);
+
+        assert_formats(input, expected, &language);
+    }
+
+    #[test]
+    fn deny_warnings_fails_on_scope_warning() {
+        let grammar: topiary_tree_sitter_facade::Language = tree_sitter_json::language().into();
+        let clean_query = fs::read_to_string("../topiary-queries/queries/json.scm").unwrap();
+        let faulty_query =
+            format!("{clean_query}\n(object \"}}\" @append_end_scope (#scope_id! \"x\"))");
+
+        for (query_content, should_fail) in [(clean_query.as_str(), false), (&faulty_query, true)] {
+            let mut input = "{\"a\": 1}".as_bytes();
+            let mut output = Vec::new();
+            let language = test_language("json", grammar.clone(), query_content);
+
+            let result = formatter(
+                &mut input,
+                &mut output,
+                &language,
+                Operation::Format(FormatOptions {
+                    deny_warnings: true,
+                    ..Default::default()
+                }),
+            );
+
+            match result {
+                Err(FormatterError::Warnings(warnings)) if should_fail => {
+                    assert!(warnings[0].contains("Closing unopened scope"));
+                }
+                Ok(()) if !should_fail => {}
+                result => panic!("Unexpected result: {result:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn incremental_formatting_matches_full_formatting() {
+        let query_content = fs::read_to_string("../topiary-queries/queries/json.scm").unwrap();
+        let language = test_language("json", tree_sitter_json::language(), &query_content);
+        let operation = Operation::Format(FormatOptions::default());
+
+        let original = "{\"one\": [1, 2, 3],\n\"two\": {\"three\": 3}}";
+        let tree = formatter_incremental(
+            &mut original.as_bytes(),
+            &mut Vec::new(),
+            &language,
+            operation,
+            None,
+        )
+        .unwrap();
+
+        // Replace the 2 in the first array by 42
+        let edited = "{\"one\": [1, 42, 3],\n\"two\": {\"three\": 3}}";
+        let edit = InputEdit::new(
+            12,
+            13,
+            14,
+            &Point::new(0, 12),
+            &Point::new(0, 13),
+            &Point::new(0, 14),
+        );
+
+        let mut incremental = Vec::new();
+        formatter_incremental(
+            &mut edited.as_bytes(),
+            &mut incremental,
+            &language,
+            operation,
+            Some((tree, &edit)),
+        )
+        .unwrap();
+
+        let mut full = Vec::new();
+        formatter(&mut edited.as_bytes(), &mut full, &language, operation).unwrap();
+
+        pretty_assert_eq(
+            &String::from_utf8(full.clone()).unwrap(),
+            &String::from_utf8(incremental).unwrap(),
+        );
+
+        // An edit that does not fit the input is ignored, rather than panicking
+        let tree = formatter_incremental(
+            &mut original.as_bytes(),
+            &mut Vec::new(),
+            &language,
+            operation,
+            None,
+        )
+        .unwrap();
+        let edit = InputEdit::new(
+            14,
+            12,
+            13,
+            &Point::new(0, 14),
+            &Point::new(0, 12),
+            &Point::new(0, 13),
+        );
+
+        let mut incremental = Vec::new();
+        formatter_incremental(
+            &mut edited.as_bytes(),
+            &mut incremental,
+            &language,
+            operation,
+            Some((tree, &edit)),
+        )
+        .unwrap();
+
+        pretty_assert_eq(
+            &String::from_utf8(full).unwrap(),
+            &String::from_utf8(incremental).unwrap(),
+        );
+    }
 }
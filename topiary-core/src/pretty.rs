@@ -2,50 +2,202 @@
 //! module is reponsible for rendering the slice of Atoms back into a displayable
 //! format.
 
-use std::fmt::Write;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+};
 
-use crate::{Atom, FormatterError, FormatterResult};
+use crate::{Atom, FormatterError, FormatterResult, IndentKind, StringWrap};
 
-/// Renders a slice of Atoms into an owned string.
-/// The indent &str is used when an `Atom::IdentStart` is encountered.
-/// Any string is accepted, but you will probably want to specify something
-/// along the lines of "  " "    " or "\t".
+/// How a slice of Atoms is rendered.
+#[derive(Clone, Copy, Debug)]
+pub struct RenderOptions<'a> {
+    /// Used when an `Atom::IndentStart(IndentKind::Default)` is encountered.
+    /// Any string is accepted, but you will probably want to specify something
+    /// along the lines of "  " "    " or "\t".
+    pub indent: &'a str,
+    /// Columns, used to align positions and wrap strings, are computed with
+    /// tab stops every `tab_width` columns.
+    pub tab_width: usize,
+    /// Whether the indentation of each line is rewritten in the unit of
+    /// `indent`, be it tabs or spaces.
+    pub normalise_indentation: bool,
+    /// Whether the number of indentation blocks open at the start of each line
+    /// is recorded.
+    pub record_depths: bool,
+}
+
+impl Default for RenderOptions<'_> {
+    fn default() -> Self {
+        Self {
+            indent: "  ",
+            tab_width: 8,
+            normalise_indentation: false,
+            record_depths: false,
+        }
+    }
+}
+
+/// The result of rendering a slice of Atoms.
+#[derive(Debug)]
+pub struct Rendered {
+    /// The rendered string.
+    pub output: String,
+    /// The (0-based) indices of the lines that end within verbatim leaves, so
+    /// their trailing whitespace can be preserved.
+    pub verbatim_lines: HashSet<usize>,
+    /// If `record_depths` is set, the number of indentation blocks open at the
+    /// start of each line; lines that continue a multi-line leaf have the depth
+    /// of the line before them.
+    pub depths: Option<Vec<usize>>,
+}
+
+/// Renders a slice of Atoms into an owned string, as set out by `options`.
 ///
 /// # Errors
 ///
 /// If an unexpected Atom is encountered, a `FormatterError::Internal` is returned.
-pub fn render(atoms: &[Atom], indent: &str) -> FormatterResult<String> {
+pub fn render(atoms: &[Atom], options: RenderOptions) -> FormatterResult<Rendered> {
+    let RenderOptions {
+        indent,
+        tab_width,
+        normalise_indentation,
+        record_depths,
+    } = options;
     let mut buffer = String::new();
-    let mut indent_level: usize = 0;
+    let mut verbatim_lines: HashSet<usize> = HashSet::new();
+    let mut depths = record_depths.then(Depths::default);
+    // The indentation of each open indentation block
+    let mut indents: Vec<String> = Vec::new();
+
+    let mut alignments: Vec<Alignment> = Vec::new();
+    // The offset of the leaf to right-align next, if any
+    let mut right_alignment: Option<usize> = None;
+    let mut block: usize = 0;
+    let mut line_indent_level: usize = 0;
+    // The leaves from whose column an indentation block starts, and the
+    // columns at which they were output
+    let anchors: HashSet<usize> = atoms
+        .iter()
+        .filter_map(|atom| match atom {
+            Atom::IndentStart(IndentKind::From(anchor)) => Some(*anchor),
+            _ => None,
+        })
+        .collect();
+    let mut anchor_columns: HashMap<usize, usize> = HashMap::new();
+    // The leaves at whose column an indentation block that is already open
+    // starts, and the index of that block, set once they are output
+    let mut pending_anchors: HashMap<usize, usize> = HashMap::new();
+    let indentation = |indents: &[String]| {
+        let indentation = indents.concat();
+        if normalise_indentation {
+            normalise(&indentation, indent, tab_width)
+        } else {
+            indentation
+        }
+    };
 
     for atom in atoms {
         match atom {
-            Atom::Blankline => write!(buffer, "\n\n{}", indent.repeat(indent_level))?,
+            Atom::Align => {
+                let line_start = buffer.rfind('\n').map_or(0, |i| i + 1);
+                if !buffer[line_start..].trim().is_empty() {
+                    alignments.push(Alignment {
+                        offset: buffer.len(),
+                        block,
+                        width: None,
+                    });
+                }
+            }
+
+            Atom::AlignRight => right_alignment = Some(buffer.len()),
+
+            Atom::Blankline => {
+                block += 1;
+                line_indent_level = indents.len();
+                write!(buffer, "\n\n{}", indentation(&indents))?;
+                if let Some(depths) = &mut depths {
+                    depths.record(&buffer, 2, indents.len());
+                }
+            }
 
             Atom::Empty => (),
 
-            Atom::Hardline => write!(buffer, "\n{}", indent.repeat(indent_level))?,
+            Atom::Hardline => {
+                if indents.len() != line_indent_level {
+                    block += 1;
+                    line_indent_level = indents.len();
+                }
+                write!(buffer, "\n{}", indentation(&indents))?;
+                if let Some(depths) = &mut depths {
+                    depths.record(&buffer, 1, indents.len());
+                }
+            }
 
             Atom::IndentEnd => {
-                if indent_level == 0 {
-                    return Err(FormatterError::Query(
-                        "Trying to close an unopened indentation block".into(),
-                        None,
+                if indents.pop().is_none() {
+                    return Err(FormatterError::query(
+                        "Trying to close an unopened indentation block",
                     ));
                 }
+            }
+
+            Atom::IndentStart(IndentKind::Default) => indents.push(indent.to_owned()),
 
-                indent_level -= 1;
+            Atom::IndentStart(IndentKind::Width(width)) => indents.push(" ".repeat(*width)),
+
+            Atom::IndentStart(IndentKind::Guide(guide)) => indents.push(guide.clone()),
+
+            // An anchor that is not output, e.g. as it is deleted, leaves the
+            // indentation as it is
+            Atom::IndentStart(IndentKind::From(anchor)) => {
+                let current = current_column(&indents.concat(), tab_width);
+                let column = anchor_columns.get(anchor).copied().unwrap_or(current);
+                indents.push(format!(
+                    "{}{indent}",
+                    " ".repeat(column.saturating_sub(current))
+                ));
+            }
+
+            // The anchor comes after, so the indentation is set once it is
+            // output; until then, and if it is not, it is left as it is
+            Atom::IndentStart(IndentKind::At(anchor)) => {
+                pending_anchors.insert(*anchor, indents.len());
+                indents.push(String::new());
             }
 
-            Atom::IndentStart => indent_level += 1,
+            Atom::IndentLiteral => write!(buffer, "{indent}")?,
 
             Atom::Leaf {
+                id,
                 content,
                 original_position,
                 single_line_no_indent,
                 multi_line_indent_all,
+                wrap_string,
+                verbatim,
                 ..
             } => {
+                let right_alignment = right_alignment.take();
+
+                if anchors.contains(id) {
+                    anchor_columns.insert(*id, current_column(&buffer, tab_width));
+                }
+                if let Some(index) = pending_anchors.remove(id) {
+                    if index < indents.len() {
+                        let current = current_column(&indents[..index].concat(), tab_width);
+                        let column = current_column(&buffer, tab_width);
+                        indents[index] = " ".repeat(column.saturating_sub(current));
+                    }
+                }
+
+                if *verbatim {
+                    let line = buffer.matches('\n').count();
+                    verbatim_lines.extend(line..line + content.matches('\n').count());
+                    write!(buffer, "{content}")?;
+                    continue;
+                }
+
                 if *single_line_no_indent {
                     // The line break after the content has been previously added
                     // as a `Hardline` in the atom stream.
@@ -55,7 +207,7 @@ pub fn render(atoms: &[Atom], indent: &str) -> FormatterResult<String> {
                 let content = content.trim_end_matches('\n');
 
                 let content = if *multi_line_indent_all {
-                    let cursor = current_column(&buffer) as i32;
+                    let cursor = current_column(&buffer, tab_width) as i32;
 
                     // original_position is 1-based
                     let original_column = original_position.column as i32 - 1;
@@ -72,7 +224,30 @@ pub fn render(atoms: &[Atom], indent: &str) -> FormatterResult<String> {
                     content.into()
                 };
 
-                write!(buffer, "{}", content)?;
+                match wrap_string {
+                    Some(wrap) => write_wrapped(
+                        &mut buffer,
+                        &content,
+                        wrap,
+                        &indentation(&indents),
+                        tab_width,
+                    ),
+                    None => write!(buffer, "{}", content)?,
+                }
+
+                // Only leaves that end on the line they start on are right-aligned
+                if let Some(offset) = right_alignment {
+                    if !buffer[offset..].contains('\n') {
+                        alignments.push(Alignment {
+                            offset,
+                            block,
+                            width: Some(
+                                current_column(&buffer, tab_width)
+                                    - current_column(&buffer[..offset], tab_width),
+                            ),
+                        });
+                    }
+                }
             }
 
             Atom::Literal(s) => write!(buffer, "{s}")?,
@@ -89,11 +264,222 @@ pub fn render(atoms: &[Atom], indent: &str) -> FormatterResult<String> {
         };
     }
 
-    Ok(buffer)
+    pad_alignments(&mut buffer, &alignments, tab_width);
+
+    let depths = depths.map(|mut depths| {
+        depths.record(&buffer, 0, 0);
+        depths.depths
+    });
+
+    Ok(Rendered {
+        output: buffer,
+        verbatim_lines,
+        depths,
+    })
 }
 
-fn current_column(s: &str) -> usize {
-    s.chars().rev().take_while(|c| *c != '\n').count()
+/// The indentation depth of each line rendered so far.
+struct Depths {
+    depths: Vec<usize>,
+    /// The length of the buffer when depths were last recorded.
+    recorded: usize,
+}
+
+impl Default for Depths {
+    fn default() -> Self {
+        Self {
+            depths: vec![0],
+            recorded: 0,
+        }
+    }
+}
+
+impl Depths {
+    /// Records the depth of the lines started since the last call, the last
+    /// `started` of which start at `depth`, the others continuing the line
+    /// before them.
+    fn record(&mut self, buffer: &str, started: usize, depth: usize) {
+        let new_lines = buffer[self.recorded..].matches('\n').count();
+        let continued = self.depths.last().copied().unwrap_or_default();
+        let lines = self.depths.len();
+        self.depths.resize(lines + new_lines - started, continued);
+        self.depths.resize(lines + new_lines, depth);
+        self.recorded = buffer.len();
+    }
+}
+
+/// A position in the buffer to pad with spaces.
+struct Alignment {
+    /// The offset of the position in the buffer.
+    offset: usize,
+    /// The block of lines the position belongs to.
+    block: usize,
+    /// For right alignments, the width of the leaf that follows the position,
+    /// whose end is aligned rather than its start.
+    width: Option<usize>,
+}
+
+/// Pads each of the `alignments` with spaces, up to the largest column among
+/// the alignments of the same kind and rank in its block, where the rank of an
+/// alignment is its position among those of its kind on its line. This way,
+/// several positions on each line can be aligned, e.g. an assignment operator
+/// and the value that follows it. Ranks are padded in turn, so that the
+/// padding of a position moves the later ones on its line.
+fn pad_alignments(buffer: &mut String, alignments: &[Alignment], tab_width: usize) {
+    let mut offsets: Vec<usize> = alignments.iter().map(|a| a.offset).collect();
+
+    // Alignments are recorded in the order of the buffer, but for right
+    // alignments, which are recorded after their leaf
+    let mut order: Vec<usize> = (0..alignments.len()).collect();
+    order.sort_by_key(|&i| offsets[i]);
+
+    let mut ranks: Vec<usize> = vec![0; alignments.len()];
+    let mut per_line: HashMap<(usize, bool), usize> = HashMap::new();
+    let mut line = 0;
+    let mut scanned = 0;
+    for &i in &order {
+        line += buffer[scanned..offsets[i]].matches('\n').count();
+        scanned = offsets[i];
+        let rank = per_line
+            .entry((line, alignments[i].width.is_some()))
+            .or_default();
+        ranks[i] = *rank;
+        *rank += 1;
+    }
+    order.sort_by_key(|&i| (offsets[i], ranks[i]));
+
+    for rank in 0..=ranks.iter().copied().max().unwrap_or(0) {
+        let columns: HashMap<usize, usize> = order
+            .iter()
+            .filter(|&&i| ranks[i] == rank)
+            .map(|&i| {
+                let column = current_column(&buffer[..offsets[i]], tab_width);
+                (i, column + alignments[i].width.unwrap_or(0))
+            })
+            .collect();
+
+        let mut targets: HashMap<(usize, bool), usize> = HashMap::new();
+        for (&i, &column) in &columns {
+            let target = targets
+                .entry((alignments[i].block, alignments[i].width.is_some()))
+                .or_default();
+            *target = (*target).max(column);
+        }
+
+        // Rebuild the buffer with the padding of this rank, moving the
+        // positions that follow each padding
+        let mut padded = String::with_capacity(buffer.len());
+        let mut copied = 0;
+        let mut shift = 0;
+        for &i in &order {
+            let offset = offsets[i];
+            offsets[i] += shift;
+            if let Some(column) = columns.get(&i) {
+                let target = targets[&(alignments[i].block, alignments[i].width.is_some())];
+                padded.push_str(&buffer[copied..offset]);
+                padded.push_str(&" ".repeat(target - column));
+                copied = offset;
+                shift += target - column;
+            }
+        }
+        padded.push_str(&buffer[copied..]);
+        *buffer = padded;
+    }
+}
+
+/// The column at the end of `s`, where a tab advances to the next multiple of
+/// `tab_width`.
+fn current_column(s: &str, tab_width: usize) -> usize {
+    let line_start = s.rfind('\n').map_or(0, |i| i + 1);
+
+    s[line_start..].chars().fold(0, |column, c| match c {
+        '\t' if tab_width > 0 => (column / tab_width + 1) * tab_width,
+        _ => column + 1,
+    })
+}
+
+/// Rewrites whitespace indentation as tabs, with spaces for any remainder, if
+/// the indentation unit `indent` is made of tabs, and as spaces otherwise.
+/// Indentation that is not all whitespace, such as guides, is left as is.
+fn normalise(indentation: &str, indent: &str, tab_width: usize) -> String {
+    if !indentation.chars().all(char::is_whitespace) {
+        return indentation.to_string();
+    }
+
+    let column = current_column(indentation, tab_width);
+    if tab_width > 0 && !indent.is_empty() && indent.chars().all(|c| c == '\t') {
+        format!(
+            "{}{}",
+            "\t".repeat(column / tab_width),
+            " ".repeat(column % tab_width)
+        )
+    } else {
+        " ".repeat(column)
+    }
+}
+
+/// Writes a string leaf, splitting it after spaces so that each line ends
+/// before `wrap.max_width` wherever possible. A split is never made within an
+/// escape sequence. A word too long to fit is kept whole.
+fn write_wrapped(
+    buffer: &mut String,
+    content: &str,
+    wrap: &StringWrap,
+    indentation: &str,
+    tab_width: usize,
+) {
+    let continuation = wrap.continuation.replace('\n', &format!("\n{indentation}"));
+    // The part of the continuation that ends the current line
+    let line_end_width = wrap
+        .continuation
+        .split('\n')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .count();
+
+    let mut rest = content;
+    loop {
+        let available = wrap
+            .max_width
+            .saturating_sub(current_column(buffer, tab_width));
+        if rest.chars().count() <= available {
+            break;
+        }
+
+        let split_points = split_points(rest);
+        let fitting = split_points
+            .iter()
+            .take_while(|&&i| rest[..i].chars().count() + line_end_width <= available)
+            .last();
+        let Some(&split) = fitting.or(split_points.first()) else {
+            break;
+        };
+
+        buffer.push_str(&rest[..split]);
+        buffer.push_str(&continuation);
+        rest = &rest[split..];
+    }
+    buffer.push_str(rest);
+}
+
+/// Returns the byte offsets just after each unescaped space in `s`, leaving
+/// out the end of the string.
+fn split_points(s: &str) -> Vec<usize> {
+    let mut points = Vec::new();
+    let mut escaped = false;
+
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == ' ' && i + 1 < s.len() {
+            points.push(i + 1);
+        }
+    }
+
+    points
 }
 
 fn add_spaces_after_newlines(s: &str, n: i32) -> String {
@@ -135,3 +521,174 @@ fn try_removing_spaces_after_newlines(s: &str, n: i32) -> String {
 
     result
 }
+
+#[cfg(test)]
+mod test {
+    use super::{render, RenderOptions};
+    use crate::{
+        atom_collection::AtomCollection, tree_sitter::Position, Atom, IndentKind, StringWrap,
+    };
+    use test_log::test;
+
+    fn literal(s: &str) -> Atom {
+        Atom::Literal(s.into())
+    }
+
+    #[test]
+    fn render_two_nested_indent_levels() {
+        let atoms = [
+            literal("switch"),
+            Atom::IndentStart(IndentKind::Default),
+            Atom::Hardline,
+            literal("case"),
+            Atom::IndentStart(IndentKind::Default),
+            Atom::Hardline,
+            literal("body"),
+            Atom::IndentEnd,
+            Atom::IndentEnd,
+            Atom::Hardline,
+            literal("end"),
+        ];
+
+        assert_eq!(
+            render(&atoms, RenderOptions::default()).unwrap().output,
+            "switch\n  case\n    body\nend"
+        );
+    }
+
+    #[test]
+    fn render_three_nested_indent_levels() {
+        let atoms = [
+            literal("a"),
+            Atom::IndentStart(IndentKind::Default),
+            Atom::Hardline,
+            literal("b"),
+            Atom::IndentStart(IndentKind::Default),
+            Atom::Hardline,
+            literal("c"),
+            Atom::IndentStart(IndentKind::Default),
+            Atom::Hardline,
+            literal("d"),
+            Atom::IndentEnd,
+            Atom::Hardline,
+            literal("e"),
+            Atom::IndentEnd,
+            Atom::IndentEnd,
+            Atom::Hardline,
+            literal("f"),
+        ];
+
+        assert_eq!(
+            render(
+                &atoms,
+                RenderOptions {
+                    indent: "\t",
+                    ..Default::default()
+                }
+            )
+            .unwrap()
+            .output,
+            "a\n\tb\n\t\tc\n\t\t\td\n\t\te\nf"
+        );
+    }
+
+    #[test]
+    fn render_nested_indent_ends_after_hardline() {
+        // Indentation atoms following a line break are moved before it during
+        // post-processing, so that all of them apply to the new line
+        let mut atom_collection = AtomCollection::new(vec![
+            literal("switch"),
+            Atom::Hardline,
+            Atom::IndentStart(IndentKind::Default),
+            Atom::IndentStart(IndentKind::Default),
+            literal("case"),
+            Atom::Hardline,
+            Atom::IndentStart(IndentKind::Default),
+            literal("body"),
+            Atom::Hardline,
+            Atom::IndentEnd,
+            Atom::Empty,
+            Atom::IndentEnd,
+            Atom::IndentEnd,
+            literal("end"),
+        ]);
+
+        atom_collection.post_process();
+
+        assert_eq!(
+            render(&atom_collection[..], RenderOptions::default())
+                .unwrap()
+                .output,
+            "switch\n    case\n      body\nend"
+        );
+    }
+
+    #[test]
+    fn render_unbalanced_indent_end() {
+        let atoms = [literal("a"), Atom::IndentEnd, Atom::Hardline, literal("b")];
+
+        assert!(render(&atoms, RenderOptions::default()).is_err());
+    }
+
+    #[test]
+    fn alignment_columns_depend_on_tab_width() {
+        let atoms = [
+            literal("\ta"),
+            Atom::Space,
+            Atom::Align,
+            literal("// 1"),
+            Atom::Hardline,
+            literal("abcdef"),
+            Atom::Space,
+            Atom::Align,
+            literal("// 2"),
+        ];
+
+        // "\ta" ends at column 9 with tabs of width 8, and at column 3 with
+        // tabs of width 2
+        assert_eq!(
+            render(&atoms, RenderOptions::default()).unwrap().output,
+            "\ta // 1\nabcdef    // 2"
+        );
+        assert_eq!(
+            render(
+                &atoms,
+                RenderOptions {
+                    tab_width: 2,
+                    ..Default::default()
+                }
+            )
+            .unwrap()
+            .output,
+            "\ta    // 1\nabcdef // 2"
+        );
+    }
+
+    #[test]
+    fn render_wrapped_string() {
+        let atoms = [
+            literal("x ="),
+            Atom::IndentStart(IndentKind::Default),
+            Atom::Hardline,
+            Atom::Leaf {
+                content: r#""lorem ipsum dolor\ sit amet consectetur""#.into(),
+                id: 0,
+                original_position: Position { row: 1, column: 1 },
+                single_line_no_indent: false,
+                multi_line_indent_all: false,
+                wrap_string: Some(StringWrap {
+                    max_width: 20,
+                    continuation: "\" +\n\"".into(),
+                }),
+                verbatim: false,
+            },
+            Atom::IndentEnd,
+        ];
+
+        // The escaped space in `dolor\ sit` is not a split point
+        assert_eq!(
+            render(&atoms, RenderOptions::default()).unwrap().output,
+            "x =\n  \"lorem ipsum \" +\n  \"dolor\\ sit \" +\n  \"amet consectetur\""
+        );
+    }
+}
@@ -1,5 +1,7 @@
 use prettydiff::text::{diff_lines, ContextConfig};
 
+use crate::{formatter, FormatOptions, Language, Operation, TopiaryQuery};
+
 pub fn pretty_assert_eq(v1: &str, v2: &str) {
     if v1 != v2 {
         let diff = diff_lines(v1, v2);
@@ -15,3 +17,42 @@ pub fn pretty_assert_eq(v1: &str, v2: &str) {
         )
     }
 }
+
+/// A language with the given grammar and query, and the default for every
+/// other setting.
+pub fn test_language(
+    name: &str,
+    grammar: impl Into<topiary_tree_sitter_facade::Language>,
+    query: &str,
+) -> Language {
+    let grammar = grammar.into();
+    Language {
+        name: name.to_owned(),
+        query: TopiaryQuery::new(&grammar, query).unwrap(),
+        grammar,
+        indent: None,
+        tab_width: 8,
+        expand_tabs: false,
+        final_newline: None,
+        normalise_indentation: false,
+        file_trailer: None,
+        post_process: None,
+        comment_kinds: None,
+    }
+}
+
+/// Formats `input` through the whole pipeline, including the idempotence
+/// check, and asserts that the output is `expected`. This allows to test a
+/// query against inline input and output, rather than sample files.
+pub fn assert_formats(input: &str, expected: &str, language: &Language) {
+    let mut output = Vec::new();
+    formatter(
+        &mut input.as_bytes(),
+        &mut output,
+        language,
+        Operation::Format(FormatOptions::default()),
+    )
+    .unwrap_or_else(|error| panic!("Failed to format {input:?}: {error}"));
+
+    pretty_assert_eq(expected, &String::from_utf8(output).unwrap());
+}
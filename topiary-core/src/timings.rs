@@ -0,0 +1,53 @@
+//! Measurement of the time taken by each stage of the formatting pipeline, to
+//! find out where to look when formatting is slow.
+
+use std::time::Duration;
+
+/// The time taken by each stage of formatting an input. Returned by
+/// [`formatter_with_timings`](crate::formatter_with_timings).
+///
+/// Durations are only measured on targets that have a clock: on WebAssembly,
+/// they are all zero.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Timings {
+    /// Flattening the syntax tree into leaves, including the detection of
+    /// line breaks in the input.
+    pub collect_leafs: Duration,
+    /// Matching the query against the syntax tree, and turning its captures
+    /// into atoms.
+    pub query: Duration,
+    /// Resolving scoped softlines and conditionals. This is part of
+    /// `post_process`.
+    pub post_process_scopes: Duration,
+    /// All the post-processing of whitespace atoms.
+    pub post_process: Duration,
+    /// Rendering the atoms to the output.
+    pub render: Duration,
+}
+
+/// Measures the time elapsed since it was started, on targets that have a
+/// clock, if timings are recorded.
+pub(crate) struct Stopwatch {
+    #[cfg(not(target_arch = "wasm32"))]
+    start: Option<std::time::Instant>,
+}
+
+impl Stopwatch {
+    /// Starts measuring the time if `record` is set. Otherwise, the clock is
+    /// not read, and the elapsed time is zero.
+    #[cfg_attr(target_arch = "wasm32", allow(unused_variables))]
+    pub(crate) fn start(record: bool) -> Self {
+        Self {
+            #[cfg(not(target_arch = "wasm32"))]
+            start: record.then(std::time::Instant::now),
+        }
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        #[cfg(not(target_arch = "wasm32"))]
+        return self.start.map_or(Duration::ZERO, |start| start.elapsed());
+
+        #[cfg(target_arch = "wasm32")]
+        Duration::ZERO
+    }
+}
@@ -1,13 +1,16 @@
-use std::{collections::HashSet, fmt::Display};
+use std::{collections::HashSet, fmt::Display, ops::Range, str::FromStr};
 
 use serde::Serialize;
 use topiary_tree_sitter_facade::{
-    Node, Parser, Point, Query, QueryCapture, QueryCursor, QueryPredicate, Tree,
+    InputEdit, Node, Parser, Point, Query, QueryCapture, QueryCursor, QueryPredicate, Tree,
 };
 
 use crate::{
-    atom_collection::{AtomCollection, QueryPredicates},
+    atom_collection::{
+        AtomCollection, MarkedNodes, NumberFormat, OperatorBreak, PathSeparator, QueryPredicates,
+    },
     error::FormatterError,
+    timings::Stopwatch,
     FormatterResult,
 };
 
@@ -42,20 +45,72 @@ pub struct TopiaryQuery {
     pub query_content: String,
 }
 
+/// Spacing to insert before and after every node of a given kind, declared in
+/// the configuration instead of the query. Each directive names the capture it
+/// translates to, without its `@append_`/`@prepend_` prefix: `space`,
+/// `antispace`, `hardline`, `empty_softline`, `spaced_softline` or
+/// `input_softline`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KindSpacing {
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+/// The spacing directives that `KindSpacing` accepts.
+const SPACING_DIRECTIVES: [&str; 6] = [
+    "space",
+    "antispace",
+    "hardline",
+    "empty_softline",
+    "spaced_softline",
+    "input_softline",
+];
+
+/// The oldest tree-sitter language version (ABI) of grammars Topiary can use,
+/// that supported by the tree-sitter it is built with.
+pub const MIN_GRAMMAR_VERSION: u32 = topiary_tree_sitter_facade::MIN_COMPATIBLE_LANGUAGE_VERSION;
+
+/// The newest tree-sitter language version (ABI) of grammars Topiary can use,
+/// that supported by the tree-sitter it is built with.
+pub const MAX_GRAMMAR_VERSION: u32 = topiary_tree_sitter_facade::LANGUAGE_VERSION;
+
+/// Checks that a grammar's language version is within the range supported by
+/// Topiary.
+///
+/// # Errors
+///
+/// This function will return an error if the version is not supported.
+pub(crate) fn check_grammar_version(version: u32) -> FormatterResult<()> {
+    if (MIN_GRAMMAR_VERSION..=MAX_GRAMMAR_VERSION).contains(&version) {
+        Ok(())
+    } else {
+        Err(FormatterError::GrammarVersion {
+            version,
+            min_version: MIN_GRAMMAR_VERSION,
+            max_version: MAX_GRAMMAR_VERSION,
+        })
+    }
+}
+
 impl TopiaryQuery {
     /// Creates a new `TopiaryQuery` from a tree-sitter language/grammar and the
     /// contents of the query file.
     ///
     /// # Errors
     ///
-    /// This function will return an error if tree-sitter failed to parse the
-    /// query file.
+    /// This function will return an error if the grammar's language version is
+    /// not supported, or if tree-sitter failed to parse the query file.
     pub fn new(
         grammar: &topiary_tree_sitter_facade::Language,
         query_content: &str,
     ) -> FormatterResult<TopiaryQuery> {
-        let query = Query::new(grammar, query_content)
-            .map_err(|e| FormatterError::Query("Error parsing query file".into(), Some(e)))?;
+        check_grammar_version(grammar.version())?;
+
+        let query = Query::new(grammar, query_content).map_err(|e| FormatterError::Query {
+            message: "Error parsing query file".into(),
+            source: Some(e),
+            span: None,
+        })?;
 
         Ok(TopiaryQuery {
             query,
@@ -63,6 +118,52 @@ impl TopiaryQuery {
         })
     }
 
+    /// Creates a new `TopiaryQuery` like `new`, extended with one pattern for
+    /// each spacing directive of `spacing`, which maps node kinds to the
+    /// spacing to insert around them. Named node kinds are matched as
+    /// `(kind)`, and anonymous ones as `"kind"`. The patterns are added after
+    /// those of the query, so the query can be empty.
+    ///
+    /// # Errors
+    ///
+    /// As well as the errors of `new`, this function will return an error if a
+    /// spacing directive is unknown.
+    pub fn with_spacing<'a>(
+        grammar: &topiary_tree_sitter_facade::Language,
+        query_content: &str,
+        spacing: impl IntoIterator<Item = (&'a str, &'a KindSpacing)>,
+    ) -> FormatterResult<TopiaryQuery> {
+        let mut query_content = query_content.to_owned();
+
+        for (kind, kind_spacing) in spacing {
+            // Kind ID 0 is the grammar's "end" symbol, which stands for unknown kinds
+            let pattern = if grammar.id_for_node_kind(kind, true) != 0 {
+                format!("({kind})")
+            } else {
+                format!("\"{}\"", kind.replace('\\', "\\\\").replace('"', "\\\""))
+            };
+
+            for (position, directive) in [
+                ("prepend", &kind_spacing.before),
+                ("append", &kind_spacing.after),
+            ] {
+                let Some(directive) = directive else {
+                    continue;
+                };
+
+                if !SPACING_DIRECTIVES.contains(&directive.as_str()) {
+                    return Err(FormatterError::query(format!(
+                        "Unknown spacing directive \"{directive}\" for kind \"{kind}\""
+                    )));
+                }
+
+                query_content.push_str(&format!("\n({pattern} @{position}_{directive})"));
+            }
+        }
+
+        Self::new(grammar, &query_content)
+    }
+
     /// Calculates the provided position of the Pattern in the query source file
     /// from the byte offset of the pattern in the query.
     #[cfg(not(target_arch = "wasm32"))]
@@ -88,6 +189,22 @@ impl TopiaryQuery {
     pub fn pattern_position(&self, _pattern_index: usize) -> Position {
         unimplemented!()
     }
+
+    /// The names of the languages that the `#embedded_language!` predicates
+    /// of the query refer to, sorted and without duplicates, so that callers
+    /// know which languages to provide for `@embedded`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn embedded_languages(&self) -> Vec<String> {
+        let mut names: Vec<String> = (0..self.query.pattern_count())
+            .flat_map(|index| self.query.general_predicates(index as u32))
+            .filter(|predicate| predicate.operator() == "embedded_language!")
+            .flat_map(|predicate| predicate.args())
+            .collect();
+        names.sort();
+        names.dedup();
+
+        names
+    }
 }
 
 impl From<Point> for Position {
@@ -205,8 +322,88 @@ pub fn apply_query(
     should_check_input_exhaustivity: bool,
 ) -> FormatterResult<AtomCollection> {
     let (tree, grammar) = parse(input_content, grammar, tolerate_parsing_errors)?;
+    apply_query_to_tree(
+        input_content,
+        &tree,
+        query,
+        grammar,
+        CaptureSettings {
+            check_input_exhaustivity: should_check_input_exhaustivity,
+            ..CaptureSettings::default()
+        },
+    )
+}
+
+/// Like `apply_query`, but the returned collection also records which captures
+/// contributed which atoms; see `AtomCollection::provenance`.
+///
+/// # Errors
+///
+/// As `apply_query`.
+pub fn apply_query_with_provenance(
+    input_content: &str,
+    query: &TopiaryQuery,
+    grammar: &topiary_tree_sitter_facade::Language,
+    tolerate_parsing_errors: bool,
+    should_check_input_exhaustivity: bool,
+) -> FormatterResult<AtomCollection> {
+    let (tree, grammar) = parse(input_content, grammar, tolerate_parsing_errors)?;
+    apply_query_to_tree(
+        input_content,
+        &tree,
+        query,
+        grammar,
+        CaptureSettings {
+            check_input_exhaustivity: should_check_input_exhaustivity,
+            record_provenance: true,
+            ..CaptureSettings::default()
+        },
+    )
+}
+
+/// Settings of a formatting run that affect how the captures of a query are
+/// applied.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct CaptureSettings<'a> {
+    /// Whether captures on anonymous nodes are skipped.
+    pub named_only: bool,
+    /// The width that replaces the `#max_width!` of every pattern.
+    pub max_line_width: Option<usize>,
+    /// Whether every pattern of the query must match somewhere in the input.
+    pub check_input_exhaustivity: bool,
+    /// Whether the atoms each capture contributed are recorded.
+    pub record_provenance: bool,
+    /// Whether the time taken by each stage of formatting is measured.
+    pub record_timings: bool,
+    /// The kinds of the nodes that are comments, if the language sets them.
+    pub comment_kinds: Option<&'a [String]>,
+}
+
+/// Whether a node is a comment: of one of `comment_kinds`, if they are given,
+/// or else of a kind whose name contains "comment".
+pub(crate) fn is_comment(node: &Node, comment_kinds: Option<&[String]>) -> bool {
+    match comment_kinds {
+        Some(kinds) => kinds.iter().any(|kind| *kind == node.kind()),
+        None => node.kind().contains("comment"),
+    }
+}
+
+/// Applies a query to an input content that has already been parsed into the
+/// given tree, and returns the resulting atom collection.
+///
+/// # Errors
+///
+/// As `apply_query`, except for parsing errors.
+pub(crate) fn apply_query_to_tree(
+    input_content: &str,
+    tree: &Tree,
+    query: &TopiaryQuery,
+    grammar: &topiary_tree_sitter_facade::Language,
+    settings: CaptureSettings,
+) -> FormatterResult<AtomCollection> {
     let root = tree.root_node();
     let source = input_content.as_bytes();
+    let stopwatch = Stopwatch::start(settings.record_timings);
 
     // Match queries
     let mut cursor = QueryCursor::new();
@@ -222,19 +419,21 @@ pub fn apply_query(
         });
     }
 
-    if should_check_input_exhaustivity {
+    if settings.check_input_exhaustivity {
         let ref_match_count = matches.len();
         check_input_exhaustivity(ref_match_count, query, grammar, &root, source)?;
     }
 
     // Find the ids of all tree-sitter nodes that were identified as a leaf
     // We want to avoid recursing into them in the collect_leafs function.
-    let specified_leaf_nodes: HashSet<usize> = collect_leaf_ids(&matches, capture_names.clone());
-
-    // The Flattening: collects all terminal nodes of the tree-sitter tree in a Vec
-    let mut atoms = AtomCollection::collect_leafs(&root, source, specified_leaf_nodes)?;
-
-    log::debug!("List of atoms before formatting: {atoms:?}");
+    let capture_ids = |names: &[&str]| collect_capture_ids(&matches, &capture_names, names);
+    let mut nodes = MarkedNodes {
+        leaves: capture_ids(&["leaf", "verbatim", "multi_line_string"]),
+        strings: capture_ids(&["multi_line_string"]),
+        inline: capture_ids(&["inline"]),
+        input_breaks: capture_ids(&["respect_input_breaks"]),
+        protected: capture_ids(&["protect"]),
+    };
 
     // Memoization of the pattern positions
     let mut pattern_positions: Vec<Option<Position>> = Vec::new();
@@ -246,6 +445,93 @@ pub fn apply_query(
         pattern_positions.resize(query.query.pattern_count(), None);
     }
 
+    // Multi-line nodes that are kept on one line if they fit within their
+    // maximum width. Nodes marked with @collapse_single_statement also have
+    // their hardlines removed, provided that they hold a single statement.
+    let mut collapsible: Vec<(Node, usize, bool)> = Vec::new();
+    for m in &matches {
+        for c in &m.captures {
+            let node = c.node();
+            let single_statement = match &*c.name(capture_names.as_slice()) {
+                "collapse_if_fits" => false,
+                "collapse_single_statement"
+                    if holds_single_statement(&node, settings.comment_kinds) =>
+                {
+                    true
+                }
+                _ => continue,
+            };
+            if let Some(max_width) = match_predicates(query, m, settings)?.max_width {
+                collapsible.push((node, max_width, single_statement));
+            }
+        }
+    }
+    collapsible.sort_by_key(|(node, _, _)| std::cmp::Reverse(depth(node)));
+
+    // The Flattening: collects all terminal nodes of the tree-sitter tree in a Vec
+    let leafs_stopwatch = Stopwatch::start(settings.record_timings);
+    let mut atoms = AtomCollection::collect_leafs(&root, source, &nodes, settings)?;
+    let mut collect_leafs = leafs_stopwatch.elapsed();
+
+    log::debug!("List of atoms before formatting: {atoms:?}");
+
+    apply_captures(
+        &mut atoms,
+        query,
+        &matches,
+        settings,
+        &mut pattern_positions,
+    )?;
+
+    // The nodes kept on one line are decided on the atoms as they stand. As
+    // this changes which nodes are multi-line, the atoms are then collected
+    // again, so that the captures that depend on it agree.
+    let collapsed = atoms.collapse_fitting(&collapsible);
+    if !collapsed.is_empty() {
+        nodes
+            .inline
+            .extend(collapsed.iter().map(|(node, _)| node.id()));
+
+        let leafs_stopwatch = Stopwatch::start(settings.record_timings);
+        atoms = AtomCollection::collect_leafs(&root, source, &nodes, settings)?;
+        collect_leafs += leafs_stopwatch.elapsed();
+
+        apply_captures(
+            &mut atoms,
+            query,
+            &matches,
+            settings,
+            &mut pattern_positions,
+        )?;
+
+        // Single statements collapsed onto one line lose their hardlines
+        for (node, _) in collapsed.iter().filter(|(_, flattened)| *flattened) {
+            atoms.flatten_hardlines(node);
+        }
+    }
+
+    atoms.expand_softlines();
+
+    let timings = atoms.timings_mut();
+    timings.collect_leafs = collect_leafs;
+    timings.query = stopwatch.elapsed().saturating_sub(collect_leafs);
+
+    Ok(atoms)
+}
+
+/// Resolves the captures of the matches of a query on a freshly collected
+/// atom collection, then attaches the resulting atoms to its leaves.
+// The pattern positions are only resized on wasm32
+#[cfg_attr(not(target_arch = "wasm32"), allow(clippy::ptr_arg))]
+fn apply_captures(
+    atoms: &mut AtomCollection,
+    query: &TopiaryQuery,
+    matches: &[LocalQueryMatch],
+    settings: CaptureSettings,
+    pattern_positions: &mut Vec<Option<Position>>,
+) -> FormatterResult<()> {
+    let capture_names = query.query.capture_names();
+
     // If there are more than one capture per match, it generally means that we
     // want to use the last capture. For example
     // (
@@ -273,12 +559,7 @@ pub fn apply_query(
             log::info!("Processing match: {m} at location {pos}");
         }
 
-        let mut predicates = QueryPredicates::default();
-
-        for p in query.query.general_predicates(m.pattern_index) {
-            predicates = handle_predicate(&p, &predicates)?;
-        }
-        check_predicates(&predicates)?;
+        let predicates = match_predicates(query, m, settings)?;
 
         // If any capture is a do_nothing, then do nothing.
         if m.captures
@@ -288,16 +569,120 @@ pub fn apply_query(
             continue;
         }
 
-        for c in m.captures {
+        for c in &m.captures {
             let name = c.name(capture_names.as_slice());
-            atoms.resolve_capture(&name, &c.node(), &predicates)?;
+            if settings.named_only && !c.node().is_named() {
+                log::debug!("Skipping @{name} on anonymous node {}", c.node().kind());
+                continue;
+            }
+            let node = c.node();
+            atoms
+                .resolve_capture(&name, &node, &predicates)
+                .map_err(|e| e.with_span(byte_range(&node)))?;
         }
     }
 
+    // Reorder the children marked with @sort_children, while atoms are still leaves
+    atoms.sort_children();
+
     // Now apply all atoms in prepend and append to the leaf nodes.
     atoms.apply_prepends_and_appends();
 
-    Ok(atoms)
+    Ok(())
+}
+
+/// Gathers the general predicates of the pattern of a query match. The width
+/// of the settings, if any, takes precedence over that of the pattern.
+fn match_predicates(
+    query: &TopiaryQuery,
+    m: &LocalQueryMatch,
+    settings: CaptureSettings,
+) -> FormatterResult<QueryPredicates> {
+    let mut predicates = QueryPredicates::default();
+
+    for p in query.query.general_predicates(m.pattern_index) {
+        predicates = handle_predicate(&p, &predicates)?;
+    }
+    check_predicates(&predicates)?;
+
+    if settings.max_line_width.is_some() {
+        predicates.max_width = settings.max_line_width;
+    }
+
+    Ok(predicates)
+}
+
+/// The byte offsets of a node in the input.
+fn byte_range(node: &Node) -> Range<usize> {
+    node.start_byte() as usize..node.end_byte() as usize
+}
+
+/// The tokens of a tree, that is the text of its leaves with any whitespace
+/// removed, along with their byte offsets. Leaves left empty, such as missing
+/// nodes, are skipped.
+fn tokens(content: &str, tree: &Tree) -> Vec<(String, Range<usize>)> {
+    let mut tokens = Vec::new();
+    let mut stack = vec![tree.root_node()];
+
+    while let Some(node) = stack.pop() {
+        let children: Vec<Node> = node.children(&mut node.walk()).collect();
+        if children.is_empty() {
+            let token: String = content[byte_range(&node)].split_whitespace().collect();
+            if !token.is_empty() {
+                tokens.push((token, byte_range(&node)));
+            }
+        } else {
+            stack.extend(children.into_iter().rev());
+        }
+    }
+
+    tokens
+}
+
+/// Checks that the output has the same tokens as the input, of which `tree` is
+/// the syntax tree, whitespace aside. The output is parsed tolerating errors,
+/// so that the tokens of invalid output are compared too.
+///
+/// # Errors
+///
+/// If the tokens differ, a `FormatterError::TokenMismatch` is returned for the
+/// first that does.
+pub(crate) fn verify_tokens(
+    input: &str,
+    tree: &Tree,
+    output: &str,
+    grammar: &topiary_tree_sitter_facade::Language,
+) -> FormatterResult<()> {
+    let output_tree = reparse(output, grammar, None, true)?;
+    let mut expected = tokens(input, tree).into_iter();
+    let mut found = tokens(output, &output_tree).into_iter();
+
+    loop {
+        match (expected.next(), found.next()) {
+            (None, None) => return Ok(()),
+            (Some((expected, _)), Some((found, _))) if expected == found => {}
+            (expected, found) => {
+                let (expected, span) = expected.unzip();
+                return Err(FormatterError::TokenMismatch {
+                    expected,
+                    found: found.map(|(found, _)| found),
+                    span,
+                });
+            }
+        }
+    }
+}
+
+/// Whether a node, such as a block, has exactly one named child, which is not
+/// a comment.
+fn holds_single_statement(node: &Node, comment_kinds: Option<&[String]>) -> bool {
+    let children: Vec<Node> = node.named_children(&mut node.walk()).collect();
+    matches!(children.as_slice(), [child] if !is_comment(child, comment_kinds))
+}
+
+/// The number of ancestors of a node.
+fn depth(node: &Node) -> usize {
+    std::iter::successors(node.parent(), Node::parent).count()
 }
 
 // A single "language" can correspond to multiple grammars.
@@ -311,54 +696,248 @@ pub fn parse<'a>(
     grammar: &'a topiary_tree_sitter_facade::Language,
     tolerate_parsing_errors: bool,
 ) -> FormatterResult<(Tree, &'a topiary_tree_sitter_facade::Language)> {
+    let tree = parse_with_old_tree(content, grammar, None, tolerate_parsing_errors)?;
+    Ok((tree, grammar))
+}
+
+/// Parses an edited input, reusing the tree of its previous version if it is
+/// given along with the edit that was made. If the edit spans more than half of
+/// the input, reusing the tree is unlikely to pay off, so the input is parsed
+/// from scratch instead, as it is if the edit does not fit the input.
+///
+/// # Errors
+///
+/// This function will return an error if the input cannot be parsed.
+pub fn reparse(
+    content: &str,
+    grammar: &topiary_tree_sitter_facade::Language,
+    previous: Option<(Tree, &InputEdit)>,
+    tolerate_parsing_errors: bool,
+) -> FormatterResult<Tree> {
+    let old_tree = previous.and_then(|(mut tree, edit)| {
+        let fits = edit.start_byte() <= edit.old_end_byte()
+            && edit.start_byte() <= edit.new_end_byte()
+            && edit.new_end_byte() as usize <= content.len();
+        let edit_length = edit
+            .old_end_byte()
+            .max(edit.new_end_byte())
+            .saturating_sub(edit.start_byte()) as usize;
+        if !fits {
+            log::info!("Edit does not fit the input, parsing from scratch");
+            None
+        } else if edit_length.saturating_mul(2) > content.len() {
+            log::info!("Edit spans too much of the input, parsing from scratch");
+            None
+        } else {
+            tree.edit(edit);
+            Some(tree)
+        }
+    });
+
+    parse_with_old_tree(content, grammar, old_tree.as_ref(), tolerate_parsing_errors)
+}
+
+fn parse_with_old_tree(
+    content: &str,
+    grammar: &topiary_tree_sitter_facade::Language,
+    old_tree: Option<&Tree>,
+    tolerate_parsing_errors: bool,
+) -> FormatterResult<Tree> {
     let mut parser = Parser::new()?;
     parser.set_language(grammar).map_err(|_| {
         FormatterError::Internal("Could not apply Tree-sitter grammar".into(), None)
     })?;
 
     let tree = parser
-        .parse(content, None)?
+        .parse(content, old_tree)?
         .ok_or_else(|| FormatterError::Internal("Could not parse input".into(), None))?;
 
     // Fail parsing if we don't get a complete syntax tree.
     if !tolerate_parsing_errors {
-        check_for_error_nodes(&tree.root_node())?;
+        check_for_error_nodes(&tree)?;
     }
 
-    Ok((tree, grammar))
+    Ok(tree)
 }
 
-fn check_for_error_nodes(node: &Node) -> FormatterResult<()> {
-    if node.kind() == "ERROR" {
-        let start = node.start_position();
-        let end = node.end_position();
+/// Expands the tabs in the leading whitespace of each line of the input to
+/// spaces, with tab stops every `tab_width` columns. Leading whitespace that is
+/// part of a token other than a comment, such as the continuation lines of a
+/// multi-line string literal, is left untouched. Comments are the nodes of
+/// `comment_kinds`, as for `is_comment`. A `tab_width` of zero leaves the input
+/// as is.
+///
+/// # Errors
+///
+/// This function will return an error if the input cannot be parsed.
+pub fn expand_leading_tabs(
+    content: &str,
+    grammar: &topiary_tree_sitter_facade::Language,
+    tab_width: usize,
+    comment_kinds: Option<&[String]>,
+    tolerate_parsing_errors: bool,
+) -> FormatterResult<String> {
+    if tab_width == 0 || !content.contains('\t') {
+        return Ok(content.to_string());
+    }
 
-        // Report 1-based lines and columns.
-        return Err(FormatterError::Parsing {
-            start_line: start.row() + 1,
-            start_column: start.column() + 1,
-            end_line: end.row() + 1,
-            end_column: end.column() + 1,
-        });
+    let (tree, _) = parse(content, grammar, tolerate_parsing_errors)?;
+    let root = tree.root_node();
+
+    let mut expanded = String::with_capacity(content.len());
+    let mut line_start = 0;
+
+    for line in content.split_inclusive('\n') {
+        let indent = &line[..line.len() - line.trim_start_matches([' ', '\t']).len()];
+
+        if indent.contains('\t') && !indent_within_token(&root, line_start, indent, comment_kinds) {
+            let mut column = 0;
+            for c in indent.chars() {
+                column = match c {
+                    '\t' => (column / tab_width + 1) * tab_width,
+                    _ => column + 1,
+                };
+            }
+            expanded.push_str(&" ".repeat(column));
+            expanded.push_str(&line[indent.len()..]);
+        } else {
+            expanded.push_str(line);
+        }
+
+        line_start += line.len();
     }
 
-    for child in node.children(&mut node.walk()) {
-        check_for_error_nodes(&child)?;
+    Ok(expanded)
+}
+
+/// Returns the 1-based number of the first line from which the input mixes
+/// tabs and spaces in its indentation, either within the line or compared to
+/// previous lines. As with `expand_leading_tabs`, leading whitespace that is
+/// part of a token other than a comment is ignored.
+pub(crate) fn detect_mixed_indentation(
+    content: &str,
+    tree: &Tree,
+    comment_kinds: Option<&[String]>,
+) -> Option<usize> {
+    let root = tree.root_node();
+    let mut seen_tabs = false;
+    let mut seen_spaces = false;
+    let mut line_start = 0;
+
+    for (index, line) in content.split_inclusive('\n').enumerate() {
+        let indent = &line[..line.len() - line.trim_start_matches([' ', '\t']).len()];
+
+        // Lines made only of whitespace do not count as indented
+        if !indent.is_empty()
+            && indent.len() < line.trim_end_matches(['\r', '\n']).len()
+            && !indent_within_token(&root, line_start, indent, comment_kinds)
+        {
+            seen_tabs |= indent.contains('\t');
+            seen_spaces |= indent.contains(' ');
+
+            if seen_tabs && seen_spaces {
+                return Some(index + 1);
+            }
+        }
+
+        line_start += line.len();
     }
 
-    Ok(())
+    None
 }
 
-/// Collects the IDs of all leaf nodes in a set of query matches.
+// Comments are not considered tokens here, so that tabs in them are expanded
+// and `@multi_line_indent_all` can realign their lines
+fn indent_within_token(
+    root: &Node,
+    line_start: usize,
+    indent: &str,
+    comment_kinds: Option<&[String]>,
+) -> bool {
+    root.descendant_for_byte_range(line_start as u32, (line_start + indent.len()) as u32)
+        .is_some_and(|node| node.child_count() == 0 && !is_comment(&node, comment_kinds))
+}
+
+/// Checks that no node of the tree is deeper than `max_depth`, the root being
+/// at depth 0. The tree is walked with a cursor rather than recursively, so
+/// that inputs nested too deeply are rejected before anything recurses over
+/// their syntax tree.
+pub(crate) fn check_depth(tree: &Tree, max_depth: Option<usize>) -> FormatterResult<()> {
+    let Some(max_depth) = max_depth else {
+        return Ok(());
+    };
+
+    let mut cursor = tree.walk();
+    let mut depth = 0;
+    loop {
+        if depth > max_depth {
+            return Err(FormatterError::TooDeep {
+                max_depth,
+                span: Some(byte_range(&cursor.node())),
+            });
+        }
+
+        if cursor.goto_first_child() {
+            depth += 1;
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                return Ok(());
+            }
+            depth -= 1;
+        }
+    }
+}
+
+/// Fails on the first error node of the tree, in depth-first order. Like
+/// `check_depth`, the tree is walked with a cursor, so that deeply nested
+/// inputs cannot overflow the stack.
+fn check_for_error_nodes(tree: &Tree) -> FormatterResult<()> {
+    let mut cursor = tree.walk();
+    loop {
+        let node = cursor.node();
+        if node.kind() == "ERROR" {
+            let start = node.start_position();
+            let end = node.end_position();
+
+            // Report 1-based lines and columns.
+            return Err(FormatterError::Parsing {
+                start_line: start.row() + 1,
+                start_column: start.column() + 1,
+                end_line: end.row() + 1,
+                end_column: end.column() + 1,
+                span: Some(byte_range(&node)),
+            });
+        }
+
+        if cursor.goto_first_child() {
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Collects the IDs of the nodes captured by some of the captures of a set of
+/// query matches.
 ///
-/// This function takes a slice of `LocalQueryMatch` and a slice of capture names,
-/// and returns a `HashSet` of node IDs that are matched by the "leaf" capture name.
-fn collect_leaf_ids(matches: &[LocalQueryMatch], capture_names: Vec<&str>) -> HashSet<usize> {
+/// This function takes a slice of `LocalQueryMatch`, the capture names of the
+/// query, and the names of the relevant captures, such as "leaf", and returns
+/// a `HashSet` of the IDs of the nodes they match.
+fn collect_capture_ids(
+    matches: &[LocalQueryMatch],
+    capture_names: &[&str],
+    names: &[&str],
+) -> HashSet<usize> {
     let mut ids = HashSet::new();
 
     for m in matches {
         for c in &m.captures {
-            if c.name(capture_names.as_slice()) == "leaf" {
+            if names.contains(&c.name(capture_names).as_ref()) {
                 ids.insert(c.node().id());
             }
         }
@@ -389,23 +968,131 @@ fn handle_predicate(
 ) -> FormatterResult<QueryPredicates> {
     let operator = &*predicate.operator();
     if "delimiter!" == operator {
-        let arg =
-            predicate.args().into_iter().next().ok_or_else(|| {
-                FormatterError::Query(format!("{operator} needs an argument"), None)
-            })?;
+        let arg = single_arg(predicate, operator)?;
         Ok(QueryPredicates {
             delimiter: Some(arg),
             ..predicates.clone()
         })
     } else if "scope_id!" == operator {
-        let arg =
-            predicate.args().into_iter().next().ok_or_else(|| {
-                FormatterError::Query(format!("{operator} needs an argument"), None)
-            })?;
+        let arg = single_arg(predicate, operator)?;
         Ok(QueryPredicates {
             scope_id: Some(arg),
             ..predicates.clone()
         })
+    } else if "kinds!" == operator {
+        let args = predicate.args();
+        if args.is_empty() {
+            return Err(FormatterError::query(format!(
+                "{operator} needs at least one argument"
+            )));
+        }
+        Ok(QueryPredicates {
+            kinds: Some(args),
+            ..predicates.clone()
+        })
+    } else if "canonical!" == operator {
+        let args = predicate.args();
+        if args.len() < 2 {
+            return Err(FormatterError::query(format!(
+                "{operator} needs a canonical form and at least one synonym"
+            )));
+        }
+        Ok(QueryPredicates {
+            canonical: Some(args),
+            ..predicates.clone()
+        })
+    } else if "comment_delimiters!" == operator {
+        let args = predicate.args();
+        if args.len() != 3 {
+            return Err(FormatterError::query(format!(
+                "{operator} needs a line comment prefix, and block comment delimiters"
+            )));
+        }
+        Ok(QueryPredicates {
+            comment_delimiters: Some(args),
+            ..predicates.clone()
+        })
+    } else if "embedded_language!" == operator {
+        let arg = single_arg(predicate, operator)?;
+        Ok(QueryPredicates {
+            embedded_language: Some(arg),
+            ..predicates.clone()
+        })
+    } else if "max_width!" == operator {
+        let max_width = numeric_arg(predicate, operator)?;
+        Ok(QueryPredicates {
+            max_width: Some(max_width),
+            ..predicates.clone()
+        })
+    } else if "child_count!" == operator {
+        let child_count = numeric_arg(predicate, operator)?;
+        Ok(QueryPredicates {
+            child_count: Some(child_count),
+            ..predicates.clone()
+        })
+    } else if "indent_width!" == operator {
+        let indent_width = numeric_arg(predicate, operator)?;
+        Ok(QueryPredicates {
+            indent_width: Some(indent_width),
+            ..predicates.clone()
+        })
+    } else if "indent_guide!" == operator {
+        let arg = single_arg(predicate, operator)?;
+        Ok(QueryPredicates {
+            indent_guide: Some(arg),
+            ..predicates.clone()
+        })
+    } else if "row_size!" == operator {
+        let row_size = numeric_arg(predicate, operator)?;
+        Ok(QueryPredicates {
+            row_size: Some(row_size),
+            ..predicates.clone()
+        })
+    } else if "number_format!" == operator {
+        let arg = single_arg(predicate, operator)?;
+        let number_format = match arg.as_str() {
+            "trim_zeros" => NumberFormat::TrimZeros,
+            "group_digits" => NumberFormat::GroupDigits,
+            _ => {
+                return Err(FormatterError::query(format!(
+                    "{operator} needs \"trim_zeros\" or \"group_digits\" as argument"
+                )))
+            }
+        };
+        Ok(QueryPredicates {
+            number_format: Some(number_format),
+            ..predicates.clone()
+        })
+    } else if "operator_break!" == operator {
+        let arg = single_arg(predicate, operator)?;
+        let operator_break = match arg.as_str() {
+            "before" => OperatorBreak::Before,
+            "after" => OperatorBreak::After,
+            _ => {
+                return Err(FormatterError::query(format!(
+                    "{operator} needs \"before\" or \"after\" as argument"
+                )))
+            }
+        };
+        Ok(QueryPredicates {
+            operator_break: Some(operator_break),
+            ..predicates.clone()
+        })
+    } else if "path_separator!" == operator {
+        let arg = single_arg(predicate, operator)?;
+        let path_separator = match arg.as_str() {
+            "/" => PathSeparator::Slash,
+            "\\" => PathSeparator::Backslash,
+            _ => {
+                return Err(FormatterError::query(format!(
+                    "{operator} needs \"/\" or \"\\\\\" as argument"
+                )))
+            }
+        };
+        Ok(QueryPredicates {
+            path_separator: Some(path_separator),
+            ..predicates.clone()
+        })
     } else if "single_line_only!" == operator {
         Ok(QueryPredicates {
             single_line_only: true,
@@ -417,28 +1104,44 @@ fn handle_predicate(
             ..predicates.clone()
         })
     } else if "single_line_scope_only!" == operator {
-        let arg =
-            predicate.args().into_iter().next().ok_or_else(|| {
-                FormatterError::Query(format!("{operator} needs an argument"), None)
-            })?;
+        let arg = single_arg(predicate, operator)?;
         Ok(QueryPredicates {
             single_line_scope_only: Some(arg),
             ..predicates.clone()
         })
     } else if "multi_line_scope_only!" == operator {
-        let arg =
-            predicate.args().into_iter().next().ok_or_else(|| {
-                FormatterError::Query(format!("{operator} needs an argument"), None)
-            })?;
+        let arg = single_arg(predicate, operator)?;
         Ok(QueryPredicates {
             multi_line_scope_only: Some(arg),
             ..predicates.clone()
         })
+    } else if "innermost_scope_only!" == operator {
+        let arg = single_arg(predicate, operator)?;
+        Ok(QueryPredicates {
+            innermost_scope_only: Some(arg),
+            ..predicates.clone()
+        })
     } else {
         Ok(predicates.clone())
     }
 }
 
+/// The argument of a predicate that takes one.
+fn single_arg(predicate: &QueryPredicate, operator: &str) -> FormatterResult<String> {
+    predicate
+        .args()
+        .into_iter()
+        .next()
+        .ok_or_else(|| FormatterError::query(format!("{operator} needs an argument")))
+}
+
+/// The argument of a predicate that takes one number.
+fn numeric_arg<T: FromStr>(predicate: &QueryPredicate, operator: &str) -> FormatterResult<T> {
+    single_arg(predicate, operator)?
+        .parse()
+        .map_err(|_| FormatterError::query(format!("{operator} needs a numeric argument")))
+}
+
 /// Checks the validity of the query predicates.
 ///
 /// This function ensures that the query predicates do not contain more than one
@@ -468,11 +1171,11 @@ fn check_predicates(predicates: &QueryPredicates) -> FormatterResult<()> {
     if predicates.multi_line_scope_only.is_some() {
         incompatible_predicates += 1;
     }
+    if predicates.innermost_scope_only.is_some() {
+        incompatible_predicates += 1;
+    }
     if incompatible_predicates > 1 {
-        Err(FormatterError::Query(
-            "A query can contain at most one #single/multi_line[_scope]_only! predicate".into(),
-            None,
-        ))
+        Err(FormatterError::query("A query can contain at most one #single/multi_line[_scope]_only! or #innermost_scope_only! predicate"))
     } else {
         Ok(())
     }
@@ -504,8 +1207,11 @@ fn check_input_exhaustivity(
         // We don't need to use TopiaryQuery in this test since we have no need
         // for duplicate versions of the query_content string, instead we create the query
         // manually.
-        let mut query = Query::new(grammar, query_content)
-            .map_err(|e| FormatterError::Query("Error parsing query file".into(), Some(e)))?;
+        let mut query = Query::new(grammar, query_content).map_err(|e| FormatterError::Query {
+            message: "Error parsing query file".into(),
+            source: Some(e),
+            span: None,
+        })?;
         query.disable_pattern(i);
         let mut cursor = QueryCursor::new();
         let match_count = query.matches(root, source, &mut cursor).count();
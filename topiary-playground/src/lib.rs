@@ -8,7 +8,9 @@ pub use wasm_mod::*;
 mod wasm_mod {
     use std::sync::Mutex;
     use topiary_config::Configuration;
-    use topiary_core::{formatter, FormatterResult, Language, Operation, TopiaryQuery};
+    use topiary_core::{
+        formatter, FormatOptions, FormatterResult, Language, Operation, TopiaryQuery,
+    };
     use topiary_tree_sitter_facade::TreeSitter;
     use wasm_bindgen::prelude::*;
 
@@ -46,6 +48,13 @@ mod wasm_mod {
             query,
             grammar,
             indent: language.config.indent,
+            tab_width: language.config.tab_width.unwrap_or(8),
+            expand_tabs: language.config.expand_tabs.unwrap_or_default(),
+            final_newline: language.config.final_newline.clone(),
+            normalise_indentation: language.config.normalise_indentation.unwrap_or_default(),
+            file_trailer: language.config.file_trailer.clone(),
+            post_process: language.config.post_process,
+            comment_kinds: language.config.comment_kinds,
         };
 
         *guard = Some(QueryState { language });
@@ -71,6 +80,10 @@ mod wasm_mod {
     ) -> FormatterResult<String> {
         let mut output = Vec::new();
 
+        let mut options = FormatOptions::default();
+        options.skip_idempotence = !check_idempotence;
+        options.tolerate_parsing_errors = tolerate_parsing_errors;
+
         let mut guard = QUERY_STATE.lock().unwrap();
 
         match &mut *guard {
@@ -79,10 +92,7 @@ mod wasm_mod {
                     &mut input.as_bytes(),
                     &mut output,
                     &query_state.language,
-                    Operation::Format {
-                        skip_idempotence: !check_idempotence,
-                        tolerate_parsing_errors,
-                    },
+                    Operation::Format(options),
                 )?;
 
                 Ok(String::from_utf8(output)?)
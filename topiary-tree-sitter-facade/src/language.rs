@@ -101,6 +101,15 @@ mod native {
 #[cfg(not(target_arch = "wasm32"))]
 pub use native::*;
 
+/// The newest language version (ABI) of the grammars the parser can load.
+#[cfg(not(target_arch = "wasm32"))]
+pub const LANGUAGE_VERSION: u32 = tree_sitter::LANGUAGE_VERSION as u32;
+
+/// The oldest language version (ABI) of the grammars the parser can load.
+#[cfg(not(target_arch = "wasm32"))]
+pub const MIN_COMPATIBLE_LANGUAGE_VERSION: u32 =
+    tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION as u32;
+
 #[cfg(target_arch = "wasm32")]
 mod wasm {
     use std::borrow::Cow;
@@ -185,3 +194,13 @@ mod wasm {
 
 #[cfg(target_arch = "wasm32")]
 pub use wasm::*;
+
+/// The newest language version (ABI) of the grammars web-tree-sitter can load, which does not
+/// expose it.
+#[cfg(target_arch = "wasm32")]
+pub const LANGUAGE_VERSION: u32 = 14;
+
+/// The oldest language version (ABI) of the grammars web-tree-sitter can load, which does not
+/// expose it.
+#[cfg(target_arch = "wasm32")]
+pub const MIN_COMPATIBLE_LANGUAGE_VERSION: u32 = 13;